@@ -0,0 +1,81 @@
+//! Phase-1 scheduling benchmarks.
+//!
+//! Inputs are fixed so numbers stay comparable across runs, run with
+//! `cargo bench --bench scheduling`.
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use engine::{
+    gpu::Gpu,
+    scheduling::{SchedulingParams, schedule_pipelines},
+};
+
+const MODEL_LAYER: usize = 32;
+
+fn params() -> SchedulingParams {
+    SchedulingParams {
+        model_id: "bench".into(),
+        model_layer: MODEL_LAYER,
+        alpha: 1.0,
+        r_rtt: 1.0,
+        t_comp: 10.0,
+        max_stages_per_replica: None,
+        affinities: vec![],
+        layer_compute_weights: vec![],
+        standby_count: 0,
+        explain: false,
+        min_compute_cap: 0,
+        hop_rtt: Default::default(),
+        client_regions: vec![],
+        load_budget: None,
+        one_replica_per_region: false,
+        max_pipeline_rtt: None,
+        max_dp_states: None,
+        layer_overlap: 0,
+        required_capabilities: Default::default(),
+    }
+}
+
+fn uniform(n: usize) -> Vec<Gpu> {
+    (0..n)
+        .map(|i| Gpu {
+            layer_cap: 12,
+            compute_cap: 1 + i % 3,
+            ..Default::default()
+        })
+        .collect()
+}
+
+// a few big cards and a long tail of small ones
+fn skewed() -> Vec<Gpu> {
+    [40, 24, 16, 16, 8, 8, 8, 6, 4, 4, 4, 2]
+        .into_iter()
+        .enumerate()
+        .map(|(i, layer_cap)| Gpu {
+            layer_cap,
+            compute_cap: 1 + i % 4,
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn bench_schedule_pipelines(c: &mut Criterion) {
+    let params = params();
+    let mut group = c.benchmark_group("schedule_pipelines");
+    group.sample_size(10);
+
+    for n in [8, 16, 32] {
+        let gpus = uniform(n);
+        group.bench_with_input(BenchmarkId::new("uniform", n), &gpus, |b, gpus| {
+            b.iter(|| schedule_pipelines(gpus, &params, None))
+        });
+    }
+
+    let gpus = skewed();
+    group.bench_with_input(BenchmarkId::new("skewed", gpus.len()), &gpus, |b, gpus| {
+        b.iter(|| schedule_pipelines(gpus, &params, None))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_schedule_pipelines);
+criterion_main!(benches);
@@ -0,0 +1,25 @@
+//! `cargo fuzz run process_get` from the engine directory.
+#![no_main]
+
+use std::sync::OnceLock;
+
+use engine::server::{ServerState, parse_get_path, process_get};
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::{Builder, Runtime};
+
+fn runtime() -> &'static Runtime {
+    static RT: OnceLock<Runtime> = OnceLock::new();
+    RT.get_or_init(|| Builder::new_current_thread().build().unwrap())
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(segments) = parse_get_path(data) {
+        for s in segments {
+            assert!(!s.is_empty() && s != "." && s != "..", "{s:?} let through");
+            assert!(!s.contains(['\0', '\\']));
+        }
+    }
+
+    let state = ServerState::default();
+    let _ = runtime().block_on(process_get(data, &state));
+});
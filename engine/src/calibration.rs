@@ -0,0 +1,148 @@
+//! Puts `compute_cap` of every backend on one scale.
+//!
+//! Each node times the same fixed-size matmul on its own device and divides
+//! the time the reference GPU takes by it. The reference GPU scores 1.0, a
+//! node twice as fast scores 2.0, whether it runs CUDA, Metal or the CPU.
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use serde::Serialize;
+
+use crate::{
+    executor::{Activation, InferenceBackend, KvCache},
+    utils::total_cmp_f64,
+};
+
+// side of the square f32 matrices multiplied by the benchmark
+pub const MATMUL_DIM: usize = 2048;
+// timed repetitions, after one untimed warmup
+pub const MATMUL_ITERS: u32 = 8;
+// time the reference GPU takes for one benchmark matmul
+pub const REFERENCE_MATMUL: Duration = Duration::from_micros(1_200);
+
+// per layer latency of the reference GPU, for turning measured layer
+// latencies into factors on the same scale as the matmul benchmark
+pub const REFERENCE_LAYER_LATENCY: Duration = Duration::from_millis(2);
+
+// `compute_cap` of the reference GPU, caps are the factor in hundredths so
+// the integer scheduler still sees small differences
+pub const REFERENCE_COMPUTE_CAP: usize = 100;
+
+/// CUDA or Metal if the build and the machine have them, else the CPU.
+pub fn detect_device() -> Device {
+    if candle_core::utils::cuda_is_available()
+        && let Ok(d) = Device::new_cuda(0)
+    {
+        return d;
+    }
+    if candle_core::utils::metal_is_available()
+        && let Ok(d) = Device::new_metal(0)
+    {
+        return d;
+    }
+    Device::Cpu
+}
+
+/// Mean wall time of one benchmark matmul on `device`.
+pub fn time_matmul(device: &Device) -> candle_core::Result<Duration> {
+    let a = Tensor::randn(0f32, 1.0, (MATMUL_DIM, MATMUL_DIM), device)?;
+    let b = Tensor::randn(0f32, 1.0, (MATMUL_DIM, MATMUL_DIM), device)?;
+
+    // reading a scalar back waits for the device to finish the queued work
+    let run = || -> candle_core::Result<f32> {
+        a.matmul(&b)?
+            .sum_all()?
+            .to_dtype(DType::F32)?
+            .to_scalar::<f32>()
+    };
+
+    run()?;
+    let start = Instant::now();
+    for _ in 0..MATMUL_ITERS {
+        run()?;
+    }
+    Ok(start.elapsed() / MATMUL_ITERS)
+}
+
+/// How fast a node is relative to the reference GPU, from its benchmark time.
+pub fn compute_factor(matmul: Duration) -> f64 {
+    let secs = matmul.as_secs_f64();
+    if secs <= 0.0 {
+        return 1.0;
+    }
+    REFERENCE_MATMUL.as_secs_f64() / secs
+}
+
+/// The scheduler's integer `compute_cap` for a calibration factor. Never 0,
+/// even a very slow node can hold layers.
+pub fn compute_cap(factor: f64) -> usize {
+    if !factor.is_finite() || factor <= 0.0 {
+        return 1;
+    }
+    ((factor * REFERENCE_COMPUTE_CAP as f64).round() as usize).max(1)
+}
+
+/// Benchmarks the local device, `None` if the benchmark can't run.
+pub fn calibrate() -> Option<f64> {
+    let device = detect_device();
+    let elapsed = time_matmul(&device).ok()?;
+    Some(compute_factor(elapsed))
+}
+
+#[derive(Debug, Clone)]
+pub struct LayerBenchConfig {
+    // untimed passes first, the first kernel launches and cold caches
+    // would otherwise show up as a slow layer
+    pub warmup_passes: usize,
+    pub measured_passes: usize,
+}
+
+impl Default for LayerBenchConfig {
+    fn default() -> Self {
+        Self {
+            warmup_passes: 3,
+            measured_passes: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LayerBenchmark {
+    pub median_ms: f32,
+    pub p95_ms: f32,
+}
+
+/// Times `layer` on `backend` over `input`, after `warmup_passes` passes
+/// whose timings are thrown away. Every pass starts from an empty cache,
+/// so this times prefill. `None` if no pass was measured.
+pub fn benchmark_layer_latency(
+    backend: &dyn InferenceBackend,
+    layer: usize,
+    input: &Activation,
+    config: &LayerBenchConfig,
+) -> Result<Option<LayerBenchmark>> {
+    for _ in 0..config.warmup_passes {
+        let mut cache = KvCache::new(usize::MAX);
+        backend.forward(layer..layer + 1, input.clone(), &mut cache)?;
+    }
+
+    let mut times = Vec::with_capacity(config.measured_passes);
+    for _ in 0..config.measured_passes {
+        let act = input.clone();
+        let mut cache = KvCache::new(usize::MAX);
+        let start = Instant::now();
+        backend.forward(layer..layer + 1, act, &mut cache)?;
+        times.push(start.elapsed().as_secs_f64() * 1000.0);
+    }
+    if times.is_empty() {
+        return Ok(None);
+    }
+    times.sort_by(|a, b| total_cmp_f64(*a, *b));
+
+    let at = |q: f64| times[((times.len() - 1) as f64 * q).round() as usize] as f32;
+    Ok(Some(LayerBenchmark {
+        median_ms: at(0.5),
+        p95_ms: at(0.95),
+    }))
+}
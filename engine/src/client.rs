@@ -0,0 +1,656 @@
+//! Outgoing connections to other nodes.
+//!
+//! Every node serves a self-signed cert, so there is no CA to check against.
+//! Instead the client pins the SHA-256 of each peer's SubjectPublicKeyInfo,
+//! which nodes publish in their `NodePerf` record. Anything not in the pin
+//! set is refused.
+use anyhow::{Result, anyhow, bail};
+use quinn::{ClientConfig, Connection, Endpoint, IdleTimeout, RecvStream, TransportConfig};
+use rustls::{
+    ClientConfig as TlsClientConfig, DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{
+        CryptoProvider, WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    server::ParsedCertificate,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::lookup_host,
+    task::JoinSet,
+};
+use tracing::info;
+
+use crate::{
+    dht::{GossipMsg, Handshake, NodePerf, frame},
+    executor::{InferenceRequest, TokenChunk},
+    server::{ClusterMap, merge_perf},
+};
+
+pub type SpkiHash = [u8; 32];
+
+/// Pins accepted by `PinnedVerifier`, shared so the gossip loop can add
+/// pins as new nodes show up.
+pub type PinSet = Arc<RwLock<HashSet<SpkiHash>>>;
+
+pub fn spki_hash(cert: &CertificateDer<'_>) -> Result<SpkiHash, rustls::Error> {
+    let parsed = ParsedCertificate::try_from(cert)?;
+    let spki = parsed.subject_public_key_info();
+    Ok(Sha256::digest(spki.as_ref()).into())
+}
+
+pub fn pin_to_hex(pin: &SpkiHash) -> String {
+    pin.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn pin_from_hex(s: &str) -> Result<SpkiHash> {
+    let s = s.trim();
+    if s.len() != 64 {
+        return Err(anyhow!("pin must be 64 hex characters"));
+    }
+    let mut pin = [0u8; 32];
+    for (i, byte) in pin.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(pin)
+}
+
+/// Accepts a server cert only if the hash of its SPKI is in the pin set.
+/// Names and validity dates aren't checked, the pin is the identity.
+/// Handshake signatures are still verified, so the peer must hold the key.
+#[derive(Debug)]
+pub struct PinnedVerifier {
+    pins: PinSet,
+    algs: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedVerifier {
+    pub fn new(pins: PinSet) -> Arc<Self> {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+
+        Arc::new(Self {
+            pins,
+            algs: provider.signature_verification_algorithms,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let pin = spki_hash(end_entity)?;
+        let pinned = self.pins.read().unwrap().contains(&pin);
+
+        if pinned {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "certificate {} is not pinned",
+                pin_to_hex(&pin)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algs.supported_schemes()
+    }
+}
+
+/// Connection liveness settings, shared by the server and the client.
+#[derive(Debug, Clone)]
+pub struct QuicTimeouts {
+    // pings sent on an otherwise idle connection, frequent enough that NAT
+    // mappings (often dropped after 30s of silence) stay open. None sends
+    // none
+    pub keep_alive_interval: Option<Duration>,
+    // a connection nothing was received on for this long is closed
+    pub max_idle_timeout: Duration,
+}
+
+impl Default for QuicTimeouts {
+    fn default() -> Self {
+        Self {
+            keep_alive_interval: Some(Duration::from_secs(15)),
+            max_idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl QuicTimeouts {
+    pub fn transport_config(&self) -> Result<Arc<TransportConfig>> {
+        let mut transport = TransportConfig::default();
+        transport
+            .keep_alive_interval(self.keep_alive_interval)
+            .max_idle_timeout(Some(IdleTimeout::try_from(self.max_idle_timeout)?));
+        Ok(Arc::new(transport))
+    }
+}
+
+pub fn make_client_config(pins: PinSet) -> Result<ClientConfig> {
+    make_client_config_with(pins, &QuicTimeouts::default())
+}
+
+pub fn make_client_config_with(pins: PinSet, timeouts: &QuicTimeouts) -> Result<ClientConfig> {
+    let tls = TlsClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(PinnedVerifier::new(pins))
+        .with_no_client_auth();
+
+    let mut config = ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(tls)?,
+    ));
+    config.transport_config(timeouts.transport_config()?);
+    Ok(config)
+}
+
+// how long one address gets before the next one is tried alongside it,
+// the Connection Attempt Delay of RFC 8305
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to a peer, Happy Eyeballs style. `addr` is one address or a
+/// comma separated list, host names resolve to all their addresses. IPv6
+/// and IPv4 addresses are tried alternately, IPv6 first, each getting a
+/// head start of `ATTEMPT_DELAY` over the next, and the first connection
+/// to come up wins. The other attempts are dropped.
+pub async fn connect(addr: &str, pins: &PinSet) -> Result<Connection> {
+    let (mut v6, mut v4) = (vec![], vec![]);
+    for part in addr.split(',').map(str::trim) {
+        for target in lookup_host(part).await? {
+            if target.is_ipv6() {
+                v6.push(target);
+            } else {
+                v4.push(target);
+            }
+        }
+    }
+    let mut pending = vec![];
+    for i in 0..v6.len().max(v4.len()) {
+        pending.extend(v6.get(i).copied());
+        pending.extend(v4.get(i).copied());
+    }
+
+    let config = make_client_config(pins.clone())?;
+    let mut pending = pending.into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last = None;
+    loop {
+        if let Some(target) = pending.next() {
+            attempts.spawn(dial(target, config.clone()));
+        }
+        if attempts.is_empty() {
+            break;
+        }
+        tokio::select! {
+            res = attempts.join_next() => match res {
+                Some(Ok(Ok(conn))) => return Ok(conn),
+                Some(Ok(Err(e))) => last = Some(e),
+                Some(Err(e)) => last = Some(e.into()),
+                None => {}
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY) => {}
+        }
+    }
+    Err(last.unwrap_or_else(|| anyhow!("{addr} resolves to no address")))
+}
+
+// one connection attempt, from an endpoint of the target's family
+async fn dial(target: SocketAddr, config: ClientConfig) -> Result<Connection> {
+    let local: SocketAddr = if target.is_ipv6() {
+        "[::]:0".parse()?
+    } else {
+        "0.0.0.0:0".parse()?
+    };
+    let mut endpoint = Endpoint::client(local)?;
+    endpoint.set_default_client_config(config);
+    Ok(endpoint.connect(target, "localhost")?.await?)
+}
+
+/// Pushes our record to a peer, after greeting it with `local` on the same
+/// connection.
+pub async fn send_perf(addr: &str, perf: NodePerf, pins: &PinSet, local: &Handshake) -> Result<()> {
+    send_perf_with(addr, perf, pins, local, false).await
+}
+
+/// `send_perf`, zstd compressing the message when `compress` is set. See
+/// `dht::frame`.
+pub async fn send_perf_with(
+    addr: &str,
+    perf: NodePerf,
+    pins: &PinSet,
+    local: &Handshake,
+    compress: bool,
+) -> Result<()> {
+    let conn = connect(addr, pins).await?;
+    hello(&conn, addr, local).await?;
+
+    let (mut send, _) = conn.open_bi().await?;
+
+    let msg = GossipMsg::Perf(perf);
+    let mut bytes = serde_json::to_vec(&msg)?;
+    if compress {
+        bytes = frame(&bytes, true)?;
+    }
+
+    send.write_all(&bytes).await?;
+    send.finish()?;
+
+    Ok(())
+}
+
+/// Cancels a request sent to a node, on a stream of its own so it can be
+/// sent while the request's stream is still busy streaming tokens. The
+/// node only takes the cancel over the connection the request came in on.
+#[derive(Clone)]
+pub struct CancelHandle {
+    conn: Connection,
+    pub request_id: String,
+}
+
+impl CancelHandle {
+    /// Asks the node to stop generating. The node frees the request once
+    /// its pipeline reaches the next layer boundary.
+    pub async fn cancel(&self) -> Result<()> {
+        let (mut send, _) = self.conn.open_bi().await?;
+
+        let msg = GossipMsg::Cancel(self.request_id.clone());
+        send.write_all(&serde_json::to_vec(&msg)?).await?;
+        send.finish()?;
+        Ok(())
+    }
+}
+
+/// A completion streaming in from a node, see `generate`.
+pub struct Generation {
+    pub cancel: CancelHandle,
+    recv: RecvStream,
+    // bytes received past the last full line
+    buf: Vec<u8>,
+}
+
+impl Generation {
+    /// The next chunk, `None` once the node closed the stream. The chunk
+    /// with `done` set is the last one.
+    pub async fn next(&mut self) -> Result<Option<TokenChunk>> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                let chunk = serde_json::from_slice(&line).map_err(|_| {
+                    anyhow!(
+                        "generation failed: {}",
+                        String::from_utf8_lossy(&line).trim_end()
+                    )
+                })?;
+                return Ok(Some(chunk));
+            }
+            let mut read = vec![0u8; 4096];
+            match self.recv.read(&mut read).await? {
+                Some(n) => self.buf.extend_from_slice(&read[..n]),
+                // an error body comes without a newline
+                None if !self.buf.is_empty() => {
+                    let body = std::mem::take(&mut self.buf);
+                    bail!("generation failed: {}", String::from_utf8_lossy(&body));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Sends `request` to the node at `addr`, which streams the completion
+/// back chunk by chunk.
+pub async fn generate(addr: &str, pins: &PinSet, request: &InferenceRequest) -> Result<Generation> {
+    let conn = connect(addr, pins).await?;
+    let (mut send, recv) = conn.open_bi().await?;
+
+    let msg = GossipMsg::Infer(request.clone());
+    send.write_all(&serde_json::to_vec(&msg)?).await?;
+    send.finish()?;
+
+    Ok(Generation {
+        cancel: CancelHandle {
+            conn,
+            request_id: request.request_id.clone(),
+        },
+        recv,
+        buf: vec![],
+    })
+}
+
+/// Checks that the peer at `addr` runs our protocol version and model,
+/// before this node joins its swarm.
+pub async fn handshake(addr: &str, pins: &PinSet, local: &Handshake) -> Result<()> {
+    let conn = connect(addr, pins).await?;
+    hello(&conn, addr, local).await
+}
+
+/// A peer answered our `Hello` with `Refused`. Asking again gets the same
+/// answer, it runs another protocol or serves other weights.
+#[derive(Debug)]
+pub struct Refused {
+    pub addr: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} refused us: {}", self.addr, self.reason)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Sends our `Hello` on `conn` and waits for the peer's answer. Peers only
+/// take gossip over a connection they welcomed, so this goes first on
+/// every connection that carries some. A refusal fails with `Refused`.
+async fn hello(conn: &Connection, addr: &str, local: &Handshake) -> Result<()> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+
+    let msg = GossipMsg::Hello(local.clone());
+    let bytes = serde_json::to_vec(&msg)?;
+
+    send.write_all(&bytes).await?;
+    send.finish()?;
+
+    let resp = recv.read_to_end(64 * 1024).await?;
+    match serde_json::from_slice(&resp)? {
+        GossipMsg::Welcome => Ok(()),
+        GossipMsg::Refused(reason) => Err(Refused {
+            addr: addr.to_string(),
+            reason,
+        }
+        .into()),
+        _ => Err(anyhow!("unexpected handshake reply from {addr}")),
+    }
+}
+
+/// Merges the cluster map of the peer at `addr` into `cluster`, after
+/// greeting it with `local`.
+pub async fn request_sync(
+    addr: &str,
+    pins: &PinSet,
+    local: &Handshake,
+    cluster: ClusterMap,
+) -> Result<()> {
+    let conn = connect(addr, pins).await?;
+    hello(&conn, addr, local).await?;
+
+    let (mut send, mut recv) = conn.open_bi().await?;
+
+    let msg = GossipMsg::SyncRequest;
+    let bytes = serde_json::to_vec(&msg)?;
+
+    send.write_all(&bytes).await?;
+    send.finish()?;
+
+    let resp = recv.read_to_end(1024 * 1024).await?;
+    let msg: GossipMsg = serde_json::from_slice(&resp)?;
+
+    if let GossipMsg::SyncResponse(perfs) = msg {
+        for p in perfs {
+            merge_perf(cluster.clone(), p).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// How hard `join` tries to reach the seed peer. Nodes are often started
+/// together, so the seed may not be listening yet, or DNS not answering.
+#[derive(Debug, Clone)]
+pub struct JoinConfig {
+    // tries in total, the first one included
+    pub attempts: usize,
+    // wait between two tries
+    pub retry_delay: Duration,
+}
+
+impl Default for JoinConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            retry_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum JoinError {
+    // every try failed, `last` is the error of the final one
+    BootstrapFailed {
+        attempts: usize,
+        last: anyhow::Error,
+    },
+    // the seed turned our handshake down, not worth retrying
+    Refused(Refused),
+    // `join_any` was given no seeds to try
+    NoSeeds,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::BootstrapFailed { attempts, last } => {
+                write!(f, "joining failed after {attempts} attempts: {last}")
+            }
+            JoinError::Refused(refused) => write!(f, "joining failed: {refused}"),
+            JoinError::NoSeeds => write!(f, "no seed peers to join through"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// Handshakes with the seed peer at `addr` and pulls its view of the
+/// cluster into `cluster`, retrying the pair as `config` allows. A seed
+/// that refuses the handshake isn't asked again.
+pub async fn join(
+    addr: &str,
+    pins: &PinSet,
+    local: &Handshake,
+    cluster: ClusterMap,
+    config: &JoinConfig,
+) -> Result<(), JoinError> {
+    let attempts = config.attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let Err(e) = request_sync(addr, pins, local, cluster.clone()).await else {
+            return Ok(());
+        };
+        match e.downcast::<Refused>() {
+            Ok(refused) => return Err(JoinError::Refused(refused)),
+            Err(last) if attempt == attempts => {
+                return Err(JoinError::BootstrapFailed { attempts, last });
+            }
+            Err(e) => info!("joining through {addr} failed (attempt {attempt}/{attempts}): {e}"),
+        }
+        attempt += 1;
+        tokio::time::sleep(config.retry_delay).await;
+    }
+}
+
+/// A peer to bootstrap from, with the cert pin it serves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Seed {
+    pub addr: String,
+    pub pin: SpkiHash,
+}
+
+/// Parses a seed file, one `<addr> <pin>` pair per line, the pin in hex as
+/// `pin_to_hex` writes it. There are no bare addresses, a seed we can't pin
+/// could be anyone. Blank lines and anything after a `#` are ignored.
+pub fn parse_seedfile(text: &str) -> Result<Vec<Seed>> {
+    let mut seeds = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(addr), Some(pin), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(anyhow!(
+                "seed file line {}: expected `<addr> <pin>`, the pin being what the peer printed on startup",
+                n + 1
+            ));
+        };
+        let pin = pin_from_hex(pin).map_err(|e| anyhow!("seed file line {}: {e}", n + 1))?;
+        seeds.push(Seed {
+            addr: addr.to_string(),
+            pin,
+        });
+    }
+    Ok(seeds)
+}
+
+pub fn load_seedfile(path: impl AsRef<Path>) -> Result<Vec<Seed>> {
+    parse_seedfile(&fs::read_to_string(path)?)
+}
+
+/// `join` through every seed at once, done as soon as one of them lets us
+/// in. The others are abandoned. Fails with the error of the last seed to
+/// give up.
+pub async fn join_any(
+    seeds: &[Seed],
+    pins: &PinSet,
+    local: &Handshake,
+    cluster: ClusterMap,
+    config: &JoinConfig,
+) -> Result<(), JoinError> {
+    pins.write().unwrap().extend(seeds.iter().map(|s| s.pin));
+
+    let mut tries = JoinSet::new();
+    for seed in seeds {
+        let (addr, pins, local) = (seed.addr.clone(), pins.clone(), local.clone());
+        let (cluster, config) = (cluster.clone(), config.clone());
+        tries.spawn(async move { join(&addr, &pins, &local, cluster, &config).await });
+    }
+
+    let mut last = JoinError::NoSeeds;
+    while let Some(res) = tries.join_next().await {
+        match res {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => last = e,
+            Err(e) => {
+                last = JoinError::BootstrapFailed {
+                    attempts: config.attempts,
+                    last: e.into(),
+                }
+            }
+        }
+    }
+    Err(last)
+}
+
+/// Downloads `path` from a peer's `/files` route into `out`, chunk by chunk.
+/// Returns the number of bytes written.
+pub async fn fetch_file<W: AsyncWrite + Unpin>(
+    addr: &str,
+    pins: &PinSet,
+    path: &str,
+    out: &mut W,
+) -> Result<u64> {
+    let mut written = 0;
+    fetch_range(addr, pins, path, 0, out, &mut written).await?;
+    Ok(written)
+}
+
+/// Downloads `path` from byte `offset` on into `out`. `written` counts the
+/// bytes that reached `out` and is kept up to date when the transfer
+/// fails, so the caller knows where to resume.
+pub async fn fetch_range<W: AsyncWrite + Unpin>(
+    addr: &str,
+    pins: &PinSet,
+    path: &str,
+    offset: u64,
+    out: &mut W,
+    written: &mut u64,
+) -> Result<()> {
+    fetch_range_with(addr, pins, path, offset, out, written, &mut |_, _| {}).await
+}
+
+/// Like `fetch_range`, calling `progress(written, total)` after every chunk,
+/// `total` being the file's full length.
+pub async fn fetch_range_with<W: AsyncWrite + Unpin>(
+    addr: &str,
+    pins: &PinSet,
+    path: &str,
+    offset: u64,
+    out: &mut W,
+    written: &mut u64,
+    progress: &mut (dyn FnMut(u64, u64) + Send),
+) -> Result<()> {
+    let conn = connect(addr, pins).await?;
+
+    let (mut send, mut recv) = conn.open_bi().await?;
+
+    let req = format!("GET /range/{offset}/{}\r\n", path.trim_start_matches('/'));
+    send.write_all(req.as_bytes()).await?;
+    send.finish()?;
+
+    let mut header = [0u8; 8];
+    recv.read_exact(&mut header).await?;
+    // errors come back as a json body instead of the length header, no
+    // real length has `{` as its top byte
+    if header[0] == b'{' {
+        let rest = recv.read_to_end(64 * 1024).await?;
+        let body = [&header[..], &rest].concat();
+        return Err(anyhow!(
+            "fetching {path} failed: {}",
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    let len = u64::from_be_bytes(header);
+    progress(*written, offset + len);
+
+    let mut received = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    while received < len {
+        let want = buf.len().min((len - received) as usize);
+        let Some(n) = recv.read(&mut buf[..want]).await? else {
+            break;
+        };
+        out.write_all(&buf[..n]).await?;
+        received += n as u64;
+        *written += n as u64;
+        progress(*written, offset + len);
+    }
+    if received != len {
+        return Err(anyhow!("{path} truncated, got {received} of {len} bytes"));
+    }
+    Ok(())
+}
@@ -0,0 +1,284 @@
+//! Keeps the adopted plan in line with the cluster and with how it performs.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    dht::{DHT, NodeId, NodePerf},
+    gpu::{Gpu, OffloadPolicy},
+    metrics::SchedulerMetrics,
+    model::ModelMetadata,
+    now_ms,
+    reputation::Reputation,
+    scheduling::{
+        CapacityLedger, RttMatrix, SchedulingError, SchedulingParams, schedule_pipelines,
+    },
+    server::{ClusterMap, ServerState},
+    utils::total_cmp_f64,
+};
+
+#[derive(Debug, Clone)]
+pub struct AlphaTunerConfig {
+    // p95 request latency the tuner steers towards
+    pub target_p95: Duration,
+    // latencies collected before alpha is adjusted
+    pub window: usize,
+    // fraction of the relative SLO error applied per adjustment, small
+    // values keep alpha from oscillating around the target
+    pub gain: f64,
+    // relative error around the target that is left alone
+    pub deadband: f64,
+    pub min_alpha: f64,
+    pub max_alpha: f64,
+}
+
+impl Default for AlphaTunerConfig {
+    fn default() -> Self {
+        Self {
+            target_p95: Duration::from_millis(500),
+            window: 200,
+            gain: 0.2,
+            deadband: 0.05,
+            min_alpha: 0.1,
+            max_alpha: 4.0,
+        }
+    }
+}
+
+/// Learns `alpha` from observed request latency. Over the SLO, alpha goes
+/// down so Z(k) prefers fewer, shallower replicas; with slack it goes up to
+/// buy throughput with more replicas.
+#[derive(Debug, Clone)]
+pub struct AlphaTuner {
+    config: AlphaTunerConfig,
+    latencies_ms: VecDeque<f64>,
+}
+
+impl AlphaTuner {
+    pub fn new(config: AlphaTunerConfig) -> Self {
+        Self {
+            config,
+            latencies_ms: VecDeque::new(),
+        }
+    }
+
+    pub fn observe(&mut self, latency: Duration) {
+        if self.latencies_ms.len() == self.config.window {
+            self.latencies_ms.pop_front();
+        }
+        self.latencies_ms.push_back(latency.as_secs_f64() * 1000.0);
+    }
+
+    pub fn p95_ms(&self) -> Option<f64> {
+        if self.latencies_ms.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<f64> = self.latencies_ms.iter().copied().collect();
+        sorted.sort_by(|a, b| total_cmp_f64(*a, *b));
+        let idx = ((sorted.len() as f64 * 0.95).ceil() as usize).saturating_sub(1);
+        Some(sorted[idx])
+    }
+
+    /// The alpha to use next, `None` while the window is still filling or
+    /// the p95 is within the deadband. A new window starts after each
+    /// adjustment so the next one only sees latencies under the new plan.
+    pub fn adjust(&mut self, alpha: f64) -> Option<f64> {
+        if self.latencies_ms.len() < self.config.window {
+            return None;
+        }
+        let p95 = self.p95_ms()?;
+        let target = self.config.target_p95.as_secs_f64() * 1000.0;
+        if target <= 0.0 {
+            return None;
+        }
+
+        let err = ((p95 - target) / target).clamp(-1.0, 1.0);
+        if err.abs() <= self.config.deadband {
+            return None;
+        }
+
+        self.latencies_ms.clear();
+        let next = (alpha * (1.0 - self.config.gain * err))
+            .clamp(self.config.min_alpha, self.config.max_alpha);
+        (next != alpha).then_some(next)
+    }
+}
+
+/// Snapshots the nodes that can take work, those refreshed within `max_age`
+/// and not draining, as the scheduler's gpus for `meta`'s model, ordered by
+/// node id, their compute scaled by `reputation`. RTTs come from each
+/// node's `NodePerf::rtt`, falling back to the other direction when only
+/// one side measured the pair.
+pub fn build_scheduler_input(
+    dht: &DHT,
+    max_age: Duration,
+    meta: &ModelMetadata,
+    reputation: &Reputation,
+) -> (Vec<Gpu>, RttMatrix) {
+    build_scheduler_input_with(dht, max_age, meta, reputation, OffloadPolicy::GpuOnly)
+}
+
+/// `build_scheduler_input` with layer caps bounded as `offload` says.
+pub fn build_scheduler_input_with(
+    dht: &DHT,
+    max_age: Duration,
+    meta: &ModelMetadata,
+    reputation: &Reputation,
+    offload: OffloadPolicy,
+) -> (Vec<Gpu>, RttMatrix) {
+    let now = now_ms();
+    let max_age = max_age.as_millis() as u64;
+    let map = dht.inner.read().unwrap();
+
+    let mut live: Vec<(&NodeId, &NodePerf)> = map
+        .iter()
+        .filter(|(_, p)| !p.draining && now.saturating_sub(p.timestamp_ms) <= max_age)
+        .collect();
+    live.sort_by_key(|(id, _)| **id);
+
+    let gpus = live
+        .iter()
+        .map(|(_, perf)| Gpu::from_node_perf_with(perf, meta, reputation, offload))
+        .collect();
+
+    let mut rtt = RttMatrix::new();
+    for (i, (id_i, perf_i)) in live.iter().enumerate() {
+        for (j, (id_j, perf_j)) in live.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            let measured = perf_i.rtt.get(*id_j).or_else(|| perf_j.rtt.get(*id_i));
+            if let Some(&ms) = measured {
+                rtt.insert((i, j), ms as f64);
+            }
+        }
+    }
+
+    (gpus, rtt)
+}
+
+/// The gpus `build_scheduler_input` would give, from the gossiped cluster
+/// map instead of the DHT, ordered by node id.
+pub async fn cluster_gpus(
+    cluster: &ClusterMap,
+    max_age: Duration,
+    meta: &ModelMetadata,
+    reputation: &Reputation,
+) -> Vec<Gpu> {
+    let now = now_ms();
+    let max_age = max_age.as_millis() as u64;
+    let map = cluster.read().await;
+
+    let mut live: Vec<&NodePerf> = map
+        .values()
+        .filter(|p| !p.draining && now.saturating_sub(p.timestamp_ms) <= max_age)
+        .collect();
+    live.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    live.into_iter()
+        .map(|perf| Gpu::from_node_perf(perf, meta, reputation))
+        .collect()
+}
+
+/// Owns the scheduling params of one model and adopts the plans it computes
+/// into the server state. Controllers of models sharing nodes share a
+/// ledger so they don't hand out the same capacity twice.
+pub struct SchedulerController {
+    pub params: SchedulingParams,
+    // DP time budget, see `schedule_pipelines`
+    pub budget: Option<Duration>,
+    pub state: ServerState,
+    // adapts `params.alpha` when set
+    pub tuner: Option<AlphaTuner>,
+    pub ledger: Arc<Mutex<CapacityLedger>>,
+}
+
+impl SchedulerController {
+    /// Computes and adopts a plan within the capacity other models left,
+    /// `Ok(false)` if the local node refused it. `gpus` carry each node's
+    /// total capacity.
+    pub async fn reschedule(&mut self, gpus: &[Gpu]) -> Result<bool, SchedulingError> {
+        let model_id = self.params.model_id.clone();
+        let available = self.ledger.lock().unwrap().available(gpus, &model_id);
+
+        let metrics = &self.state.scheduler_metrics;
+        metrics.reschedule_total.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let plan = schedule_pipelines(&available, &self.params, self.budget);
+        metrics.observe_duration(started.elapsed());
+        let plan = plan?;
+
+        let k = plan.k;
+        let usage = CapacityLedger::usage(&plan);
+        if !self.state.adopt_plan(plan).await {
+            return Ok(false);
+        }
+        self.ledger.lock().unwrap().commit(&model_id, usage);
+
+        let active: usize = self
+            .state
+            .plans
+            .read()
+            .await
+            .values()
+            .map(|p| p.pipelines.len())
+            .sum();
+        SchedulerMetrics::set(&metrics.selected_k, k as u64);
+        SchedulerMetrics::set(&metrics.pipelines_active, active as u64);
+        Ok(true)
+    }
+
+    /// Resolves when an adopted plan was refused and another is needed.
+    pub async fn wait_for_reschedule(&self) {
+        self.state.reschedule.notified().await;
+    }
+
+    /// Schedules `meta`'s model on the gossiped cluster, then again each
+    /// time the server asks for it (a refused plan, a node down without a
+    /// standby) or `interval` passed. A refused plan waits out the whole
+    /// interval, the same cluster would give the same plan. Never returns.
+    pub async fn run(&mut self, meta: &ModelMetadata, max_age: Duration, interval: Duration) {
+        loop {
+            let gpus =
+                cluster_gpus(&self.state.cluster, max_age, meta, &self.state.reputation).await;
+            match self.reschedule(&gpus).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("plan for {} refused, retrying in {interval:?}", meta.name);
+                    tokio::time::sleep(interval).await;
+                    continue;
+                }
+                Err(e) => warn!("scheduling {} failed: {e}", meta.name),
+            }
+            tokio::select! {
+                _ = self.wait_for_reschedule() => {}
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+    }
+
+    pub fn observe_latency(&mut self, latency: Duration) {
+        if let Some(tuner) = &mut self.tuner {
+            tuner.observe(latency);
+        }
+    }
+
+    /// Lets the tuner move alpha and reschedules if it did. Returns whether
+    /// a new plan was adopted.
+    pub async fn tune_alpha(&mut self, gpus: &[Gpu]) -> Result<bool, SchedulingError> {
+        let Some(alpha) = self
+            .tuner
+            .as_mut()
+            .and_then(|t| t.adjust(self.params.alpha))
+        else {
+            return Ok(false);
+        };
+
+        debug!("alpha {} -> {alpha}", self.params.alpha);
+        self.params.alpha = alpha;
+        self.reschedule(gpus).await
+    }
+}
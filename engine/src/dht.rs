@@ -1,6 +1,30 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    sync::RwLock,
+    time::Duration,
+};
 
-use serde::{Deserialize, Serialize};
+use anyhow::{Result, bail};
+use libp2p::{
+    PeerId, StreamProtocol,
+    identity::{Keypair, PublicKey},
+    kad::{
+        self, K_VALUE, PeerRecord, ProviderRecord, QueryId, Quorum, Record, RecordKey,
+        store::{self, MemoryStore, MemoryStoreConfig, RecordStore},
+    },
+};
+use serde::{Deserialize, Serialize, Serializer};
+use tokio::sync::oneshot;
+
+use crate::{
+    client::SpkiHash,
+    executor::InferenceRequest,
+    gguf,
+    gpu::{Capability, SystemInfo},
+    model::{ModelId, ModelMetadata},
+    now_ms, tokens_from_bytes,
+};
 
 pub type NodeId = u64;
 pub type RamCapacity = usize;
@@ -9,13 +33,116 @@ pub struct DHT {
     pub inner: RwLock<HashMap<NodeId, NodePerf>>,
 }
 
+/// A node's gossiped record. The maps and sets are written in key order, so
+/// equal records always encode to the same bytes, whatever the hash seed.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NodePerf {
     pub node_id: String,
+    // where the node's server listens, so peers can gossip back
+    #[serde(default)]
+    pub addr: String,
+    // tokens of KV cache that fit in host RAM
     pub ram_tokens: usize,
-    pub layer_latency: HashMap<LayerId, f32>,
+    // tokens of KV cache that fit in GPU VRAM, 0 on a node without a GPU
+    #[serde(default)]
+    pub vram_tokens: usize,
+    // measured latency of each layer, per model the node profiled
+    #[serde(serialize_with = "sorted_latencies")]
+    pub layer_latency: HashMap<ModelId, HashMap<LayerId, f32>>,
+    #[serde(serialize_with = "sorted_map")]
     pub rtt: HashMap<NodeId, f32>,
     pub timestamp_ms: u64,
+    // sha256 of the node's cert SPKI, peers pin it to talk to the node
+    #[serde(default)]
+    pub cert_pin: Option<SpkiHash>,
+    // the node is leaving, routers must not send it new requests
+    #[serde(default)]
+    pub draining: bool,
+    // speed relative to the reference GPU, see `calibration`. None until
+    // the node has benchmarked itself
+    #[serde(default)]
+    pub compute_factor: Option<f64>,
+    // features the node's runtime supports
+    #[serde(default, serialize_with = "sorted_set")]
+    pub capabilities: HashSet<Capability>,
+    // where the node runs, see `SchedulingParams::one_replica_per_region`
+    #[serde(default)]
+    pub region: String,
+    // client facing bandwidth, 0 until measured
+    #[serde(default)]
+    pub network_bandwidth: usize,
+}
+
+fn sorted_map<K: Ord + Serialize, V: Serialize, S: Serializer>(
+    map: &HashMap<K, V>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+fn sorted_latencies<S: Serializer>(
+    map: &HashMap<ModelId, HashMap<LayerId, f32>>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_map(
+        map.iter()
+            .map(|(model, layers)| (model, layers.iter().collect::<BTreeMap<_, _>>()))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+fn sorted_set<T: Ord + Serialize, S: Serializer>(
+    set: &HashSet<T>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_seq(set.iter().collect::<BTreeSet<_>>())
+}
+
+impl NodePerf {
+    /// The record a node with `info` advertises for `meta`, before anything
+    /// has been measured. `vram_tokens` and `ram_tokens` are how many tokens
+    /// of the model's KV cache fit in VRAM and host RAM. Latencies and RTTs
+    /// start empty and are filled in as the node profiles.
+    pub fn from_system_info(info: &SystemInfo, meta: &ModelMetadata, node_id: String) -> NodePerf {
+        let per_token = meta.kv_cache_bytes_per_token();
+        NodePerf {
+            node_id,
+            addr: String::new(),
+            ram_tokens: tokens_from_bytes(info.ram, per_token),
+            vram_tokens: tokens_from_bytes(info.gpu_vram, per_token),
+            layer_latency: HashMap::new(),
+            rtt: HashMap::new(),
+            timestamp_ms: now_ms(),
+            cert_pin: None,
+            draining: false,
+            compute_factor: None,
+            capabilities: Capability::detect(info),
+            region: String::new(),
+            network_bandwidth: 0,
+        }
+    }
+}
+
+/// A perf record plus how long ago it was last refreshed, as shown by
+/// `engine dht-dump`.
+#[derive(Debug, Serialize)]
+pub struct PerfDump {
+    #[serde(flatten)]
+    pub perf: NodePerf,
+    pub age_secs: f64,
+}
+
+/// Every record sorted by node id, ages measured from `now_ms`.
+pub fn dump_perfs<'a>(perfs: impl IntoIterator<Item = &'a NodePerf>, now_ms: u64) -> Vec<PerfDump> {
+    let mut dump: Vec<PerfDump> = perfs
+        .into_iter()
+        .map(|p| PerfDump {
+            perf: p.clone(),
+            age_secs: now_ms.saturating_sub(p.timestamp_ms) as f64 / 1000.0,
+        })
+        .collect();
+    dump.sort_by(|a, b| a.perf.node_id.cmp(&b.perf.node_id));
+    dump
 }
 
 pub struct PerfMap {
@@ -24,9 +151,655 @@ pub struct PerfMap {
 
 pub type LayerId = u32;
 
+/// Bumped whenever messages between nodes change incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a node checks with a peer before joining its swarm. Nodes that
+/// run another protocol or serve other weights can't share pipelines.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    // empty when the node serves no model yet
+    pub model_id: ModelId,
+    // see `model::model_hash`
+    pub model_hash: String,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            model_id: ModelId::new(),
+            model_hash: String::new(),
+        }
+    }
+}
+
+impl Handshake {
+    /// Errors with the first thing `peer` disagrees on.
+    pub fn check(&self, peer: &Handshake) -> Result<()> {
+        if peer.protocol_version != self.protocol_version {
+            bail!(
+                "protocol version {} doesn't match ours, {}",
+                peer.protocol_version,
+                self.protocol_version
+            );
+        }
+        if peer.model_id != self.model_id {
+            bail!(
+                "model {:?} doesn't match ours, {:?}",
+                peer.model_id,
+                self.model_id
+            );
+        }
+        if peer.model_hash != self.model_hash {
+            bail!(
+                "weights of {} differ, hash {} against our {}",
+                self.model_id,
+                peer.model_hash,
+                self.model_hash
+            );
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum GossipMsg {
     Perf(NodePerf),
     SyncRequest,
     SyncResponse(Vec<NodePerf>),
+    // sent by a joining node before anything else
+    Hello(Handshake),
+    Welcome,
+    // why the peer refused our `Hello`
+    Refused(String),
+    // the client gave up on the request with this id, stop generating.
+    // Only honoured on the connection the request came in on
+    Cancel(String),
+    // a prompt to complete, answered with one JSON `TokenChunk` per line
+    Infer(InferenceRequest),
+}
+
+/// Wire format of perf records stored in the DHT.
+pub trait PerfCodec {
+    fn encode(&self, perf: &NodePerf) -> Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> Result<NodePerf>;
+}
+
+/// Compact binary records, the RTT and latency maps are a fraction of
+/// their JSON size. Used for everything stored in the DHT, inside a
+/// `FramedCodec`.
+pub struct BincodeCodec;
+
+impl PerfCodec for BincodeCodec {
+    fn encode(&self, perf: &NodePerf) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(perf)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<NodePerf> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Readable records, for debugging output.
+pub struct JsonCodec;
+
+impl PerfCodec for JsonCodec {
+    fn encode(&self, perf: &NodePerf) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(perf)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<NodePerf> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Puts `FRAME_MAGIC` and a flag byte in front of `inner`'s encoding, the
+/// flag saying whether the rest is zstd compressed, so readers take records
+/// of either form and unframed ones from older nodes. Worth it once RTT
+/// maps have hundreds of entries.
+pub struct FramedCodec<C> {
+    pub inner: C,
+    // compress what `encode` writes, `decode` reads both regardless
+    pub compress: bool,
+}
+
+impl<C: PerfCodec> PerfCodec for FramedCodec<C> {
+    fn encode(&self, perf: &NodePerf) -> Result<Vec<u8>> {
+        frame(&self.inner.encode(perf)?, self.compress)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<NodePerf> {
+        self.inner.decode(&unframe(bytes, MAX_UNFRAMED_BYTES)?)
+    }
+}
+
+// starts every `frame`. Not valid UTF-8, so no JSON message starts with it,
+// and read as the length prefix of a bincode record it'd claim megabytes of
+// node id, far past any record's size
+pub const FRAME_MAGIC: [u8; 3] = [0xf5, b'f', b'x'];
+
+// flag bytes of `frame`, after the magic
+pub const FRAME_RAW: u8 = 0x00;
+pub const FRAME_ZSTD: u8 = 0x01;
+
+// largest payload `FramedCodec` decompresses a record to
+const MAX_UNFRAMED_BYTES: usize = 1024 * 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// `payload` behind the magic and its flag byte, compressed if `compress`
+/// is set.
+pub fn frame(payload: &[u8], compress: bool) -> Result<Vec<u8>> {
+    let mut out = FRAME_MAGIC.to_vec();
+    out.push(if compress { FRAME_ZSTD } else { FRAME_RAW });
+    if compress {
+        out.extend(zstd::bulk::compress(payload, ZSTD_LEVEL)?);
+    } else {
+        out.extend_from_slice(payload);
+    }
+    Ok(out)
+}
+
+/// The payload of a `frame`, refusing to decompress past `limit` bytes.
+/// Bytes without the magic are taken as an unframed payload.
+pub fn unframe(bytes: &[u8], limit: usize) -> Result<Cow<'_, [u8]>> {
+    let Some(bytes) = bytes.strip_prefix(&FRAME_MAGIC) else {
+        return Ok(Cow::Borrowed(bytes));
+    };
+    match bytes.split_first() {
+        Some((&FRAME_RAW, rest)) => Ok(Cow::Borrowed(rest)),
+        Some((&FRAME_ZSTD, rest)) => Ok(Cow::Owned(zstd::bulk::decompress(rest, limit)?)),
+        Some((flag, _)) => bail!("unknown frame flag {flag:#04x}"),
+        None => bail!("empty frame"),
+    }
+}
+
+/// What perf records in the DHT are read with.
+pub const PERF_CODEC: FramedCodec<BincodeCodec> = FramedCodec {
+    inner: BincodeCodec,
+    compress: false,
+};
+
+// namespace of a deployment that doesn't pick its own
+pub const DEFAULT_NAMESPACE: &str = "fluxstate";
+
+fn perf_prefix(namespace: &str) -> String {
+    format!("{namespace}/perf/")
+}
+
+pub fn perf_key(namespace: &str, node_id: &str) -> RecordKey {
+    RecordKey::new(&format!("{}{node_id}", perf_prefix(namespace)))
+}
+
+/// Provider key of a model layer, nodes holding its weights provide it.
+pub fn holder_key(namespace: &str, model_id: &str, layer: LayerId) -> RecordKey {
+    RecordKey::new(&format!("{namespace}/layer/{model_id}/{layer}"))
+}
+
+/// Record key of a model's `LayerManifest`.
+pub fn manifest_key(namespace: &str, model_id: &str) -> RecordKey {
+    RecordKey::new(&format!("{namespace}/manifest/{model_id}"))
+}
+
+fn is_perf_key(namespace: &str, key: &RecordKey) -> bool {
+    key.as_ref().starts_with(perf_prefix(namespace).as_bytes())
+}
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    // prefix of every key we read or write, deployments sharing a DHT must
+    // use different ones or they overwrite each other's records
+    pub namespace: String,
+    // hard cap on the number of records the local store will hold
+    pub max_records: usize,
+    // largest record value we accept, signed perf records are well under this
+    pub max_value_bytes: usize,
+    // once fewer than this many slots are free, the oldest perf records
+    // get evicted to make room for new ones
+    pub eviction_headroom: usize,
+    // distinct copies a perf read waits for, more trades latency for not
+    // reading a stale record from one side of a partition
+    pub read_quorum: Quorum,
+    // peers that must store a published perf record for the put to succeed
+    pub write_quorum: Quorum,
+    // how long peers keep our perf and holder records, see `Republisher`
+    pub record_ttl: Duration,
+    // how far in the future a perf record's timestamp may be. A copy past
+    // this would win every freshness comparison, so it is thrown away
+    pub max_clock_skew: Duration,
+    // zstd compress the perf records we publish, see `FramedCodec`
+    pub compress_records: bool,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            max_records: 4096,
+            max_value_bytes: 64 * 1024,
+            eviction_headroom: 256,
+            read_quorum: Quorum::One,
+            write_quorum: Quorum::One,
+            record_ttl: Duration::from_secs(60 * 60),
+            max_clock_skew: Duration::from_secs(30),
+            compress_records: false,
+        }
+    }
+}
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/fluxstate/kad/1.0.0");
+
+/// Kademlia settings matching `config`. Kademlia's own republishing is off,
+/// it would re-put the perf record we stored last instead of a fresh one,
+/// `Republisher` takes care of it.
+pub fn kad_config(config: &DhtConfig) -> kad::Config {
+    let mut kad = kad::Config::new(PROTOCOL);
+    kad.set_record_ttl(Some(config.record_ttl))
+        .set_provider_record_ttl(Some(config.record_ttl))
+        .set_publication_interval(None)
+        .set_provider_publication_interval(None);
+    kad
+}
+
+/// Re-puts the local perf record and re-provides the layers we hold before
+/// peers let them expire. Poll it from the swarm loop.
+///
+/// The interval is half the record TTL, so one failed round still leaves
+/// the records alive until the next.
+pub struct Republisher {
+    interval_ms: u64,
+    next_at_ms: u64,
+}
+
+impl Republisher {
+    pub fn new(config: &DhtConfig) -> Self {
+        Self {
+            interval_ms: Self::interval(config).as_millis() as u64,
+            // the first poll publishes
+            next_at_ms: 0,
+        }
+    }
+
+    pub fn interval(config: &DhtConfig) -> Duration {
+        config.record_ttl / 2
+    }
+
+    /// Republishes if the interval has passed since the last time, returns
+    /// whether it did.
+    pub fn poll(
+        &mut self,
+        kad: &mut kad::Behaviour<BoundedStore>,
+        perf: &NodePerf,
+        held_layers: &[(ModelId, LayerId)],
+        config: &DhtConfig,
+        now_ms: u64,
+    ) -> Result<bool> {
+        if now_ms < self.next_at_ms {
+            return Ok(false);
+        }
+        self.next_at_ms = now_ms + self.interval_ms;
+
+        publish_perf(kad, perf, config)?;
+        for (model_id, layer) in held_layers {
+            kad.start_providing(holder_key(&config.namespace, model_id, *layer))?;
+        }
+        Ok(true)
+    }
+}
+
+/// Copies a quorum asks for, out of the default replication factor.
+pub fn quorum_count(quorum: Quorum) -> usize {
+    let total = K_VALUE.get();
+    match quorum {
+        Quorum::One => 1,
+        Quorum::Majority => total / 2 + 1,
+        Quorum::All => total,
+        Quorum::N(n) => n.get().min(total),
+    }
+}
+
+/// Stores our perf record in the DHT, acknowledged by `write_quorum` peers.
+pub fn publish_perf(
+    kad: &mut kad::Behaviour<BoundedStore>,
+    perf: &NodePerf,
+    config: &DhtConfig,
+) -> Result<QueryId> {
+    let key = perf_key(&config.namespace, &perf.node_id);
+    let codec = FramedCodec {
+        inner: BincodeCodec,
+        compress: config.compress_records,
+    };
+    let record = Record::new(key, codec.encode(perf)?);
+    Ok(kad.put_record(record, config.write_quorum)?)
+}
+
+/// Canonical per layer checksums of a model, see `gguf::layer_digest`.
+/// Published by an authority node so downloaders check layers against it
+/// instead of trusting whatever checksum the sender claims.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerManifest {
+    pub model_id: ModelId,
+    pub layers: HashMap<LayerId, [u8; 32]>,
+    // digest of the layer file holders serve, what `transfer::fetch_layer`
+    // checks a download against
+    pub files: HashMap<LayerId, FileDigest>,
+}
+
+// size of the pieces a `FileDigest` hashes
+pub const FILE_CHUNK_BYTES: u64 = 4 * 1024 * 1024;
+
+/// sha256 of each `FILE_CHUNK_BYTES` piece of a file, the last one shorter,
+/// so a download is checked piece by piece and resumes after the last good
+/// one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileDigest {
+    pub len: u64,
+    pub chunks: Vec<[u8; 32]>,
+}
+
+impl FileDigest {
+    /// Length of chunk `i`, None past the end of the file.
+    pub fn chunk_len(&self, i: usize) -> Option<u64> {
+        let start = i as u64 * FILE_CHUNK_BYTES;
+        (i < self.chunks.len() && start < self.len)
+            .then(|| (self.len - start).min(FILE_CHUNK_BYTES))
+    }
+}
+
+// what `manifest_key` stores, the signature covers `manifest` as is
+#[derive(Serialize, Deserialize)]
+struct SignedManifest {
+    manifest: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl LayerManifest {
+    /// Errors unless the fetched tensors of `layer`, name and data in file
+    /// order, hash to the manifest's digest.
+    pub fn verify<'a>(
+        &self,
+        layer: LayerId,
+        tensors: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+    ) -> Result<()> {
+        let Some(expected) = self.layers.get(&layer) else {
+            bail!("manifest of {} has no layer {layer}", self.model_id);
+        };
+        if gguf::layer_digest(tensors) != *expected {
+            bail!("layer {layer} of {} fails its checksum", self.model_id);
+        }
+        Ok(())
+    }
+}
+
+/// Stores the manifest under `manifest_key`, signed with `authority`. Only
+/// the authority node calls this, and like any record it has to be put
+/// again within `record_ttl`.
+pub fn publish_manifest(
+    kad: &mut kad::Behaviour<BoundedStore>,
+    manifest: &LayerManifest,
+    authority: &Keypair,
+    config: &DhtConfig,
+) -> Result<QueryId> {
+    let key = manifest_key(&config.namespace, &manifest.model_id);
+    let record = Record::new(key, sign_manifest(manifest, authority)?);
+    Ok(kad.put_record(record, config.write_quorum)?)
+}
+
+/// The record value `publish_manifest` stores.
+pub fn sign_manifest(manifest: &LayerManifest, authority: &Keypair) -> Result<Vec<u8>> {
+    let manifest = bincode::serialize(manifest)?;
+    let signature = authority.sign(&manifest)?;
+    Ok(bincode::serialize(&SignedManifest {
+        manifest,
+        signature,
+    })?)
+}
+
+/// Decodes the manifest of `model_id` found in the DHT, refusing it unless
+/// `authority` signed it. The record's publisher field is set by whoever
+/// stored it, so it proves nothing.
+pub fn manifest_from_record(
+    record: &Record,
+    model_id: &str,
+    authority: &PublicKey,
+) -> Result<LayerManifest> {
+    let signed: SignedManifest = bincode::deserialize(&record.value)?;
+    if !authority.verify(&signed.manifest, &signed.signature) {
+        bail!("manifest of {model_id} not signed by the authority");
+    }
+    let manifest: LayerManifest = bincode::deserialize(&signed.manifest)?;
+    // a genuine manifest of another model put under this key
+    if manifest.model_id != model_id {
+        bail!("manifest under {model_id} is for {}", manifest.model_id);
+    }
+    Ok(manifest)
+}
+
+/// Starts a lookup of a node's perf record. Feed the query's `FoundRecord`
+/// events to `reads` to get the record once `read_quorum` copies are in.
+pub fn fetch_node(
+    kad: &mut kad::Behaviour<BoundedStore>,
+    reads: &mut PendingReads,
+    node_id: &str,
+    config: &DhtConfig,
+) -> QueryId {
+    let id = kad.get_record(perf_key(&config.namespace, node_id));
+    reads.pending.insert(
+        id,
+        PendingRead {
+            node_id: node_id.to_string(),
+            needed: quorum_count(config.read_quorum),
+            max_skew_ms: config.max_clock_skew.as_millis() as u64,
+            found: vec![],
+        },
+    );
+    id
+}
+
+struct PendingRead {
+    node_id: String,
+    needed: usize,
+    max_skew_ms: u64,
+    found: Vec<NodePerf>,
+}
+
+impl PendingRead {
+    /// Whether a copy can be trusted to be the node's own record: it
+    /// decodes, names the node we looked up and isn't dated in the future.
+    fn accept(&self, perf: &NodePerf, now_ms: u64) -> bool {
+        perf.node_id == self.node_id && perf.timestamp_ms <= now_ms + self.max_skew_ms
+    }
+}
+
+/// Perf lookups waiting for their read quorum. kademlia reports each copy
+/// it finds as a separate event and keeps going, so the count is kept here.
+#[derive(Default)]
+pub struct PendingReads {
+    pending: HashMap<QueryId, PendingRead>,
+}
+
+impl PendingReads {
+    /// Records one copy, unless it is invalid. Once the quorum of valid
+    /// copies is reached the query is stopped and the freshest is returned,
+    /// not the first to arrive.
+    pub fn on_found(
+        &mut self,
+        kad: &mut kad::Behaviour<BoundedStore>,
+        id: QueryId,
+        found: PeerRecord,
+    ) -> Option<NodePerf> {
+        let read = self.pending.get_mut(&id)?;
+        if let Ok(perf) = PERF_CODEC.decode(&found.record.value)
+            && read.accept(&perf, crate::now_ms())
+        {
+            read.found.push(perf);
+        }
+        if read.found.len() < read.needed {
+            return None;
+        }
+
+        if let Some(mut query) = kad.query_mut(&id) {
+            query.finish();
+        }
+        self.finish(id)
+    }
+
+    /// The query ended, with or without the quorum. Returns the freshest
+    /// copy if any came in, short of the quorum it may be stale.
+    pub fn on_finished(&mut self, id: QueryId) -> Option<NodePerf> {
+        self.finish(id)
+    }
+
+    fn finish(&mut self, id: QueryId) -> Option<NodePerf> {
+        let read = self.pending.remove(&id)?;
+        read.found.into_iter().max_by_key(|p| p.timestamp_ms)
+    }
+}
+
+/// A lookup of the nodes holding a layer, for the swarm loop to start with
+/// `fetch_holders` and answer once the query ends.
+pub struct HolderQuery {
+    pub model_id: ModelId,
+    pub layer: LayerId,
+    pub reply: oneshot::Sender<HashSet<PeerId>>,
+}
+
+/// Starts the provider lookup of `query`. Feed the query's
+/// `FoundProviders` events to `lookups`.
+pub fn fetch_holders(
+    kad: &mut kad::Behaviour<BoundedStore>,
+    lookups: &mut PendingHolders,
+    query: HolderQuery,
+    config: &DhtConfig,
+) -> QueryId {
+    let id = kad.get_providers(holder_key(&config.namespace, &query.model_id, query.layer));
+    lookups.pending.insert(id, (HashSet::new(), query.reply));
+    id
+}
+
+/// Provider lookups in flight, with the providers found so far.
+#[derive(Default)]
+pub struct PendingHolders {
+    pending: HashMap<QueryId, (HashSet<PeerId>, oneshot::Sender<HashSet<PeerId>>)>,
+}
+
+impl PendingHolders {
+    pub fn on_found(&mut self, id: QueryId, providers: HashSet<PeerId>) {
+        if let Some((found, _)) = self.pending.get_mut(&id) {
+            found.extend(providers);
+        }
+    }
+
+    /// The query ended, answers it with every provider found.
+    pub fn on_finished(&mut self, id: QueryId) {
+        if let Some((found, reply)) = self.pending.remove(&id) {
+            // the transfer gave up waiting, fine
+            let _ = reply.send(found);
+        }
+    }
+}
+
+/// A kademlia record store that stays within `DhtConfig::max_records` by
+/// evicting the least recently written perf records, instead of rejecting
+/// puts once the underlying `MemoryStore` is full.
+///
+/// Layer-holder (and any other non perf) records are never evicted here,
+/// they are few and losing them would break layer lookups.
+pub struct BoundedStore {
+    inner: MemoryStore,
+    config: DhtConfig,
+    // perf record keys, least recently written first
+    perf_order: VecDeque<RecordKey>,
+}
+
+impl BoundedStore {
+    pub fn new(local_id: PeerId, config: DhtConfig) -> Self {
+        let store_config = MemoryStoreConfig {
+            max_records: config.max_records,
+            max_value_bytes: config.max_value_bytes,
+            ..Default::default()
+        };
+
+        Self {
+            inner: MemoryStore::with_config(local_id, store_config),
+            config,
+            perf_order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.records().count()
+    }
+
+    fn evict_for(&mut self, key: &RecordKey) {
+        // overwriting an existing record doesn't need a new slot
+        if self.inner.get(key).is_some() {
+            return;
+        }
+
+        let limit = self
+            .config
+            .max_records
+            .saturating_sub(self.config.eviction_headroom);
+
+        let mut len = self.len();
+        while len >= limit {
+            let Some(oldest) = self.perf_order.pop_front() else {
+                break;
+            };
+            self.inner.remove(&oldest);
+            len -= 1;
+        }
+    }
+}
+
+impl RecordStore for BoundedStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        if !is_perf_key(&self.config.namespace, &r.key) {
+            return self.inner.put(r);
+        }
+
+        let key = r.key.clone();
+        self.evict_for(&key);
+        self.inner.put(r)?;
+
+        // move the key to the back, it is now the freshest perf record
+        self.perf_order.retain(|k| k != &key);
+        self.perf_order.push_back(key);
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.perf_order.retain(|key| key != k);
+        self.inner.remove(k);
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        self.inner.add_provider(record)
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.inner.remove_provider(k, p)
+    }
 }
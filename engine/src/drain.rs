@@ -0,0 +1,78 @@
+//! Lets a node leave the swarm without failing the requests it is serving.
+//!
+//! Draining flips a flag that gossip publishes in the node's `NodePerf`, so
+//! routers stop sending new requests to its stages, then waits for the stage
+//! computations already running here to finish.
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+// top bit of `Drain::state`, the rest counts the work in flight
+const DRAINING: usize = 1 << (usize::BITS - 1);
+
+#[derive(Debug, Default)]
+pub struct Drain {
+    // the draining flag and the in-flight count in one word, so work can't
+    // start between `begin` and `wait_idle` seeing the count
+    state: AtomicUsize,
+    idle: Notify,
+}
+
+impl Drain {
+    pub fn is_draining(&self) -> bool {
+        self.state.load(Ordering::SeqCst) & DRAINING != 0
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.load(Ordering::SeqCst) & !DRAINING
+    }
+
+    /// Registers a stage computation or request, `None` once the node is
+    /// draining. The work counts as in flight until the guard drops.
+    pub fn start_work(self: &Arc<Self>) -> Option<WorkGuard> {
+        self.state
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |s| {
+                (s & DRAINING == 0).then_some(s + 1)
+            })
+            .ok()?;
+        Some(WorkGuard {
+            drain: self.clone(),
+        })
+    }
+
+    pub fn begin(&self) {
+        self.state.fetch_or(DRAINING, Ordering::SeqCst);
+    }
+
+    /// Waits for in-flight work to finish, false if `timeout` ran out first.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                let idle = self.idle.notified();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+}
+
+pub struct WorkGuard {
+    drain: Arc<Drain>,
+}
+
+impl Drop for WorkGuard {
+    fn drop(&mut self) {
+        if self.drain.state.fetch_sub(1, Ordering::SeqCst) & !DRAINING == 1 {
+            self.drain.idle.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,903 @@
+//! Runs the stages of a plan on whatever runtime the node has.
+//!
+//! The executor only knows `InferenceBackend`, so the orchestration around
+//! it doesn't depend on candle, llama.cpp or a GPU being present.
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{
+    dht::LayerId,
+    latency::LayerLatencies,
+    model::ModelId,
+    now_ms,
+    reputation::Reputation,
+    router::ReplicaId,
+    sampler::{RepetitionPenalty, Sampler, SamplingConfig},
+    scheduling::{Pipeline, Stage},
+    server::PlanState,
+    tokenizer::{DecodeState, Tokenizer},
+};
+
+/// Hidden states passed from one stage to the next, row major.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Activation {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+impl Activation {
+    pub fn new(shape: Vec<usize>, data: Vec<f32>) -> Result<Activation> {
+        let len: usize = shape.iter().product();
+        if len != data.len() {
+            bail!("shape {shape:?} needs {len} values, got {}", data.len());
+        }
+        Ok(Activation { shape, data })
+    }
+
+    /// The entry stage's input: the prompt's token ids as a `[1, n]`
+    /// tensor. f32 holds every id below 2^24 exactly.
+    pub fn from_tokens(ids: &[u32]) -> Activation {
+        Activation {
+            shape: vec![1, ids.len()],
+            data: ids.iter().map(|&id| id as f32).collect(),
+        }
+    }
+
+    /// Sequence positions the activation covers, its second dimension
+    /// (`[batch, positions, ..]`).
+    pub fn positions(&self) -> usize {
+        self.shape.get(1).copied().unwrap_or(1)
+    }
+
+    /// The innermost row, which for the last stage's output is the logits
+    /// of the last position.
+    pub fn last_row(&self) -> &[f32] {
+        let width = self.shape.last().copied().unwrap_or(0).min(self.data.len());
+        &self.data[self.data.len() - width..]
+    }
+}
+
+/// A prompt to complete, as a client sends it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InferenceRequest {
+    // picked by the client, which cancels the request by it
+    pub request_id: String,
+    pub model_id: ModelId,
+    pub prompt: String,
+    pub max_tokens: usize,
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+    // fixes the sampler's draws, the same seed gives the same completion
+    #[serde(default)]
+    pub seed: Option<u64>,
+    // see `RepetitionPenalty`, 1.0 is off
+    #[serde(default = "default_repetition_penalty")]
+    pub repetition_penalty: f32,
+    // how many of the latest ids, prompt included, the penalty looks at
+    #[serde(default = "default_repetition_window")]
+    pub repetition_window: usize,
+}
+
+fn default_repetition_penalty() -> f32 {
+    1.0
+}
+
+fn default_repetition_window() -> usize {
+    64
+}
+
+impl InferenceRequest {
+    pub fn sampler(&self) -> Box<dyn Sampler> {
+        self.sampling.sampler(self.seed)
+    }
+
+    pub fn repetition_penalty(&self) -> RepetitionPenalty {
+        RepetitionPenalty {
+            penalty: self.repetition_penalty,
+            window: self.repetition_window,
+        }
+    }
+}
+
+/// A fresh random request id.
+pub fn new_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Requests being generated on this node, by id, so a client's `Cancel`
+/// reaches the executor running its request. Each request belongs to the
+/// connection it came in on, only that connection can cancel it: request
+/// ids travel in the clear and anyone could send a `Cancel` naming one.
+#[derive(Debug, Default)]
+pub struct Inflight {
+    // (owning connection, token) by request id
+    tokens: Mutex<HashMap<String, (usize, CancellationToken)>>,
+}
+
+impl Inflight {
+    /// Registers a request sent over connection `owner`. Its executor
+    /// takes `guard.token` as its cancel token, dropping the guard frees
+    /// the slot. Fails if the id is already running.
+    pub fn start(self: &Arc<Self>, request_id: &str, owner: usize) -> Result<InflightGuard> {
+        let token = CancellationToken::new();
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.contains_key(request_id) {
+            bail!("request {request_id} is already running");
+        }
+        tokens.insert(request_id.to_string(), (owner, token.clone()));
+        Ok(InflightGuard {
+            inflight: self.clone(),
+            request_id: request_id.to_string(),
+            token,
+        })
+    }
+
+    /// Cancels the request if connection `owner` started it, false if it
+    /// isn't running here or belongs to someone else.
+    pub fn cancel(&self, request_id: &str, owner: usize) -> bool {
+        match self.tokens.lock().unwrap().get(request_id) {
+            Some((started_by, token)) if *started_by == owner => {
+                token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct InflightGuard {
+    inflight: Arc<Inflight>,
+    request_id: String,
+    pub token: CancellationToken,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.inflight
+            .tokens
+            .lock()
+            .unwrap()
+            .remove(&self.request_id);
+    }
+}
+
+/// A request accepted by the server, waiting for `serve` to run it.
+pub struct Job {
+    pub request: InferenceRequest,
+    // the request's `InflightGuard::token`
+    pub cancel: CancellationToken,
+    // the chunks streamed back to the client, the last one has `done` set
+    pub chunks: mpsc::UnboundedSender<TokenChunk>,
+    // the replica `ServerState::route` picked, a pipeline of the plan
+    pub replica: ReplicaId,
+}
+
+impl Job {
+    // ends the job before it ran, the client gets a single failed chunk
+    fn fail(self, reason: String) {
+        let _ = self.chunks.send(TokenChunk {
+            token: None,
+            text: String::new(),
+            done: true,
+            finish_reason: Some(FinishReason::Failed(StreamError {
+                produced: 0,
+                failed_stage: 0,
+                reason,
+            })),
+        });
+    }
+}
+
+/// Runs the jobs arriving on `jobs` until every sender is gone, those
+/// arriving together batched by `collect_batch` and completed with
+/// `complete_batch` on the pipeline of their replica. The plan is looked up
+/// in `plans` for every batch, so a newly adopted one takes over from the
+/// next batch on. A client that went away only loses its chunks.
+pub async fn serve(
+    executor: &PipelineExecutor<'_>,
+    plans: &PlanState,
+    tokenizer: &Tokenizer,
+    jobs: &mut mpsc::Receiver<Job>,
+    config: &BatchConfig,
+) {
+    loop {
+        let batch = collect_batch(jobs, config).await;
+        if batch.is_empty() {
+            return;
+        }
+        let plan = plans.read().await.get(&executor.model_id).cloned();
+
+        let mut by_replica: BTreeMap<ReplicaId, Vec<Job>> = BTreeMap::new();
+        for job in batch {
+            by_replica.entry(job.replica).or_default().push(job);
+        }
+        for (replica, jobs) in by_replica {
+            match plan.as_ref().and_then(|p| p.pipelines.get(replica)) {
+                Some(pipeline) => executor.complete_batch(pipeline, tokenizer, jobs),
+                None => {
+                    for job in jobs {
+                        job.fail(format!("no replica {replica} in the adopted plan"));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// a job of `complete_batch` and where its generation is at
+struct BatchRow {
+    job: Job,
+    sampler: Box<dyn Sampler>,
+    penalty: RepetitionPenalty,
+    ids: Vec<u32>,
+    decode: DecodeState,
+    produced: usize,
+    caches: Vec<KvCache>,
+    next: Activation,
+}
+
+impl BatchRow {
+    fn finish(mut self, reason: FinishReason) {
+        let _ = self.job.chunks.send(TokenChunk {
+            token: None,
+            text: self.decode.finish(),
+            done: true,
+            finish_reason: Some(reason),
+        });
+    }
+}
+
+/// Tokenizes `prompt` into what the entry stage of a replica takes.
+pub fn entry_input(tokenizer: &Tokenizer, prompt: &str) -> Activation {
+    Activation::from_tokens(&tokenizer.encode(prompt))
+}
+
+/// Keys and values of the positions a stage has already processed for one
+/// request, so each decode step only runs the new position through the
+/// stage's layers. Positions past `max_seq_len` push the oldest ones out.
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    pub max_seq_len: usize,
+    // one entry per cached position, oldest first, in whatever layout the
+    // backend writes
+    layers: HashMap<usize, VecDeque<Vec<f32>>>,
+}
+
+impl KvCache {
+    pub fn new(max_seq_len: usize) -> Self {
+        Self {
+            max_seq_len,
+            layers: HashMap::new(),
+        }
+    }
+
+    /// Positions cached for `layer`.
+    pub fn len(&self, layer: usize) -> usize {
+        self.layers.get(&layer).map_or(0, VecDeque::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.values().all(VecDeque::is_empty)
+    }
+
+    /// What `layer` cached so far, oldest position first.
+    pub fn entries(&self, layer: usize) -> impl Iterator<Item = &[f32]> {
+        self.layers
+            .get(&layer)
+            .into_iter()
+            .flatten()
+            .map(Vec::as_slice)
+    }
+
+    /// Appends the keys and values of the next position of `layer`,
+    /// evicting the oldest position once `max_seq_len` are held.
+    pub fn push(&mut self, layer: usize, kv: Vec<f32>) {
+        let entries = self.layers.entry(layer).or_default();
+        if self.max_seq_len == 0 {
+            return;
+        }
+        while entries.len() >= self.max_seq_len {
+            entries.pop_front();
+        }
+        entries.push_back(kv);
+    }
+}
+
+/// The activations of several requests stacked into one `[batch,
+/// positions, ..]` tensor, each padded with zeros to the longest one.
+/// `lengths[i]` is how many positions of row i are real, the padding must
+/// not be attended to or cached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub act: Activation,
+    pub lengths: Vec<usize>,
+}
+
+impl Batch {
+    /// Stacks `[1, positions, ..]` activations whose remaining dimensions
+    /// agree.
+    pub fn pack(rows: &[Activation]) -> Result<Batch> {
+        let Some(first) = rows.first() else {
+            bail!("empty batch");
+        };
+        let inner = first.shape.get(2..).unwrap_or_default();
+        if let Some(row) = rows
+            .iter()
+            .find(|r| r.shape.first() != Some(&1) || r.shape.get(2..).unwrap_or_default() != inner)
+        {
+            bail!("can't batch shape {:?} with {:?}", row.shape, first.shape);
+        }
+
+        let width: usize = inner.iter().product();
+        let lengths: Vec<usize> = rows.iter().map(Activation::positions).collect();
+        let padded = lengths.iter().copied().max().unwrap_or(0);
+        let mut data = Vec::with_capacity(rows.len() * padded * width);
+        for row in rows {
+            data.extend_from_slice(&row.data);
+            data.resize(data.len() + (padded - row.positions()) * width, 0.0);
+        }
+
+        let mut shape = vec![rows.len(), padded];
+        shape.extend_from_slice(inner);
+        Ok(Batch {
+            act: Activation::new(shape, data)?,
+            lengths,
+        })
+    }
+
+    /// Splits the batch back into one activation per request, without the
+    /// padding.
+    pub fn unpack(self) -> Vec<Activation> {
+        let padded = self.act.positions();
+        let inner = self.act.shape.get(2..).unwrap_or_default();
+        let width: usize = inner.iter().product();
+        self.lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| {
+                let start = i * padded * width;
+                let mut shape = vec![1, len];
+                shape.extend_from_slice(inner);
+                Activation {
+                    shape,
+                    data: self.act.data[start..start + len * width].to_vec(),
+                }
+            })
+            .collect()
+    }
+}
+
+pub trait InferenceBackend: Send + Sync {
+    /// Runs `layers` of the model over `input`, in order. `input` holds
+    /// only the positions not in `cache` yet, the backend attends over
+    /// the cached ones and appends the new ones to it.
+    fn forward(
+        &self,
+        layers: Range<usize>,
+        input: Activation,
+        cache: &mut KvCache,
+    ) -> Result<Activation>;
+
+    /// `forward` for several requests at once, `caches[i]` being row i's.
+    /// Backends that can't batch keep this default, which runs the rows
+    /// one after the other.
+    fn forward_batch(
+        &self,
+        layers: Range<usize>,
+        batch: Batch,
+        caches: &mut [&mut KvCache],
+    ) -> Result<Batch> {
+        let rows = batch.unpack();
+        if rows.len() != caches.len() {
+            bail!("{} rows but {} caches", rows.len(), caches.len());
+        }
+        let mut out = Vec::with_capacity(rows.len());
+        for (row, cache) in rows.into_iter().zip(caches.iter_mut()) {
+            out.push(self.forward(layers.clone(), row, cache)?);
+        }
+        Batch::pack(&out)
+    }
+}
+
+/// Applies `x * scale + bias` once per layer, no weights involved. Caches
+/// one empty entry per position it is given.
+#[derive(Debug, Clone, Copy)]
+pub struct MockBackend {
+    pub scale: f32,
+    pub bias: f32,
+}
+
+impl Default for MockBackend {
+    // the identity
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            bias: 0.0,
+        }
+    }
+}
+
+impl InferenceBackend for MockBackend {
+    fn forward(
+        &self,
+        layers: Range<usize>,
+        mut input: Activation,
+        cache: &mut KvCache,
+    ) -> Result<Activation> {
+        for layer in layers {
+            for _ in 0..input.positions() {
+                cache.push(layer, vec![]);
+            }
+            for x in &mut input.data {
+                *x = *x * self.scale + self.bias;
+            }
+        }
+        Ok(input)
+    }
+
+    // one pass over the whole batch, the padding is transformed too but
+    // never cached
+    fn forward_batch(
+        &self,
+        layers: Range<usize>,
+        mut batch: Batch,
+        caches: &mut [&mut KvCache],
+    ) -> Result<Batch> {
+        if batch.lengths.len() != caches.len() {
+            bail!("{} rows but {} caches", batch.lengths.len(), caches.len());
+        }
+        for layer in layers {
+            for (cache, &len) in caches.iter_mut().zip(&batch.lengths) {
+                for _ in 0..len {
+                    cache.push(layer, vec![]);
+                }
+            }
+            for x in &mut batch.act.data {
+                *x = *x * self.scale + self.bias;
+            }
+        }
+        Ok(batch)
+    }
+}
+
+/// How long `collect_batch` holds the first request back for others to
+/// join it, and how many it takes at most.
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    pub max_batch: usize,
+    pub window: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_batch: 8,
+            window: Duration::from_millis(5),
+        }
+    }
+}
+
+/// Waits for the next request on `rx`, then takes whatever else arrives
+/// within `config.window`, up to `config.max_batch` in total. Empty once
+/// every sender is gone.
+pub async fn collect_batch<T>(rx: &mut mpsc::Receiver<T>, config: &BatchConfig) -> Vec<T> {
+    let Some(first) = rx.recv().await else {
+        return vec![];
+    };
+    let mut batch = vec![first];
+    let deadline = tokio::time::Instant::now() + config.window;
+    while batch.len() < config.max_batch {
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(next)) => batch.push(next),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    batch
+}
+
+/// What one stage hands the next. A `Cancel` travels down the pipeline in
+/// place of the activation, so stages after a cancelled one stop too.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StageFrame {
+    Activation(Activation),
+    Cancel,
+}
+
+pub struct PipelineExecutor<'a> {
+    pub model_id: ModelId,
+    pub backend: &'a dyn InferenceBackend,
+    // where per layer timings go, if they are published
+    pub latencies: Option<&'a LayerLatencies>,
+    // cancelled when the client went away, checked between layers
+    pub cancel: CancellationToken,
+    // positions each stage keeps in its KV cache per request
+    pub max_seq_len: usize,
+    // told which node's stage failed, if reputations are kept
+    pub reputation: Option<&'a Reputation>,
+}
+
+impl PipelineExecutor<'_> {
+    /// Runs one stage a layer at a time, stopping at the next layer once
+    /// `cancel` fires. An incoming `Cancel` cancels this stage as well and
+    /// is passed on. `cache` is the stage's cache for the request.
+    pub fn run_stage(
+        &self,
+        stage: &Stage,
+        input: StageFrame,
+        cache: &mut KvCache,
+    ) -> Result<StageFrame> {
+        let mut act = match input {
+            StageFrame::Activation(act) => act,
+            StageFrame::Cancel => {
+                self.cancel.cancel();
+                return Ok(StageFrame::Cancel);
+            }
+        };
+
+        let range = stage.range;
+        for layer in range.start..range.end {
+            if self.cancel.is_cancelled() {
+                return Ok(StageFrame::Cancel);
+            }
+            let started = Instant::now();
+            act = self.backend.forward(layer..layer + 1, act, cache)?;
+
+            if let Some(latencies) = self.latencies {
+                let ms = started.elapsed().as_secs_f32() * 1000.0;
+                latencies.record(&self.model_id, layer as LayerId, ms, now_ms());
+            }
+        }
+        Ok(StageFrame::Activation(act))
+    }
+
+    /// Runs every stage of a replica in this process, for tests and single
+    /// node setups.
+    pub fn run_pipeline(&self, pipeline: &Pipeline, input: Activation) -> Result<StageFrame> {
+        let mut caches = self.new_caches(pipeline);
+        self.run_step(pipeline, input, &mut caches)
+            .map_err(|(_, e)| e)
+    }
+
+    /// Empty caches for a new request, one per stage of `pipeline`.
+    pub fn new_caches(&self, pipeline: &Pipeline) -> Vec<KvCache> {
+        vec![KvCache::new(self.max_seq_len); pipeline.stages.len()]
+    }
+
+    // like `run_pipeline` on the request's caches, but says which stage
+    // failed
+    fn run_step(
+        &self,
+        pipeline: &Pipeline,
+        input: Activation,
+        caches: &mut [KvCache],
+    ) -> Result<StageFrame, (usize, anyhow::Error)> {
+        let mut frame = StageFrame::Activation(input);
+        for (i, (stage, cache)) in pipeline.stages.iter().zip(caches).enumerate() {
+            frame = self.run_stage(stage, frame, cache).map_err(|e| {
+                if let Some(reputation) = self.reputation {
+                    reputation.record_failure(&stage.gpu.node_id);
+                }
+                (i, e)
+            })?;
+        }
+        Ok(frame)
+    }
+
+    /// Runs one pass of several requests through the replica together, one
+    /// `forward_batch` call per layer. `caches[i]` holds request i's caches,
+    /// as made by `new_caches`. A cancel or a failure stops the whole batch.
+    pub fn run_batch(
+        &self,
+        pipeline: &Pipeline,
+        inputs: Vec<Activation>,
+        caches: &mut [Vec<KvCache>],
+    ) -> Result<Option<Vec<Activation>>, (usize, anyhow::Error)> {
+        let mut batch = Batch::pack(&inputs).map_err(|e| (0, e))?;
+        for (i, stage) in pipeline.stages.iter().enumerate() {
+            let mut stage_caches: Vec<&mut KvCache> =
+                caches.iter_mut().filter_map(|c| c.get_mut(i)).collect();
+            for layer in stage.range.start..stage.range.end {
+                if self.cancel.is_cancelled() {
+                    return Ok(None);
+                }
+                let started = Instant::now();
+                batch = self
+                    .backend
+                    .forward_batch(layer..layer + 1, batch, &mut stage_caches)
+                    .map_err(|e| {
+                        if let Some(reputation) = self.reputation {
+                            reputation.record_failure(&stage.gpu.node_id);
+                        }
+                        (i, e)
+                    })?;
+
+                // the layer's cost per request, what a request alone would
+                // come close to
+                if let Some(latencies) = self.latencies {
+                    let ms = started.elapsed().as_secs_f32() * 1000.0 / batch.lengths.len() as f32;
+                    latencies.record(&self.model_id, layer as LayerId, ms, now_ms());
+                }
+            }
+        }
+        Ok(Some(batch.unpack()))
+    }
+
+    /// Runs up to `steps` passes through the replica, each fed the output
+    /// of the previous one, and hands every output to `emit` as soon as it
+    /// is ready. A failing stage ends the generation with a `Truncated`
+    /// item instead of losing what was produced. Returns the outputs.
+    pub fn generate(
+        &self,
+        pipeline: &Pipeline,
+        input: Activation,
+        steps: usize,
+        mut emit: impl FnMut(StreamItem),
+    ) -> Vec<Activation> {
+        let mut outputs: Vec<Activation> = Vec::with_capacity(steps);
+        let mut caches = self.new_caches(pipeline);
+        let mut next = input;
+        for _ in 0..steps {
+            match self.run_step(pipeline, next, &mut caches) {
+                Ok(StageFrame::Activation(out)) => {
+                    emit(StreamItem::Output(out.clone()));
+                    next = out.clone();
+                    outputs.push(out);
+                }
+                Ok(StageFrame::Cancel) => {
+                    emit(StreamItem::Cancelled);
+                    break;
+                }
+                Err((failed_stage, e)) => {
+                    emit(StreamItem::Truncated(StreamError {
+                        produced: outputs.len(),
+                        failed_stage,
+                        reason: e.to_string(),
+                    }));
+                    break;
+                }
+            }
+        }
+        outputs
+    }
+
+    /// Completes `request` on `pipeline`, one token per pass, handing each
+    /// token to `emit` as it is sampled. Generation stops at the model's EOS
+    /// token or after `max_tokens`; the last chunk has `done` set and says
+    /// which. A cancel or a failing stage also ends it with a `done` chunk.
+    pub fn complete(
+        &self,
+        pipeline: &Pipeline,
+        tokenizer: &Tokenizer,
+        request: &InferenceRequest,
+        emit: impl FnMut(TokenChunk),
+    ) -> FinishReason {
+        self.complete_with(pipeline, tokenizer, request, &mut |_, _| None, emit)
+    }
+
+    /// `complete` that survives stage failures: `failover` is asked for a
+    /// stand-in for the failed stage, e.g. a promoted standby, and the
+    /// generation goes on there. The stand-in has none of the request's KV
+    /// cache, so every stage's cache is rebuilt by feeding the prompt and
+    /// the tokens so far through the pipeline again. Fails like `complete`
+    /// once `failover` has no stand-in.
+    pub fn complete_with(
+        &self,
+        pipeline: &Pipeline,
+        tokenizer: &Tokenizer,
+        request: &InferenceRequest,
+        failover: &mut dyn FnMut(usize, &Stage) -> Option<Stage>,
+        mut emit: impl FnMut(TokenChunk),
+    ) -> FinishReason {
+        let mut pipeline = pipeline.clone();
+        let sampler = request.sampler();
+        let penalty = request.repetition_penalty();
+        let mut ids = tokenizer.encode(&request.prompt);
+        let mut decode = DecodeState::default();
+        let mut produced = 0;
+        // the whole prompt on the first pass, then only the latest token,
+        // the stages have the rest cached
+        let mut caches = self.new_caches(&pipeline);
+        let mut next = Activation::from_tokens(&ids);
+
+        let reason = loop {
+            if produced == request.max_tokens {
+                break FinishReason::Length;
+            }
+            let out = match self.run_step(&pipeline, next, &mut caches) {
+                Ok(StageFrame::Activation(out)) => out,
+                Ok(StageFrame::Cancel) => break FinishReason::Cancelled,
+                Err((failed_stage, e)) => {
+                    let Some(stage) = failover(failed_stage, &pipeline.stages[failed_stage]) else {
+                        break FinishReason::Failed(StreamError {
+                            produced,
+                            failed_stage,
+                            reason: e.to_string(),
+                        });
+                    };
+                    warn!(
+                        "stage {failed_stage} failed ({e}), continuing on {}",
+                        stage.gpu.node_id
+                    );
+                    pipeline.stages[failed_stage] = stage;
+                    // the stages before the failed one cached the token
+                    // already, starting over is simpler than trimming them
+                    caches = self.new_caches(&pipeline);
+                    next = Activation::from_tokens(&ids);
+                    continue;
+                }
+            };
+
+            let mut logits = out.last_row().to_vec();
+            penalty.apply(&mut logits, &ids);
+            let token = sampler.sample(&logits);
+            if Some(token) == tokenizer.eos() {
+                break FinishReason::Stop;
+            }
+            ids.push(token);
+            next = Activation::from_tokens(&[token]);
+            produced += 1;
+            emit(TokenChunk {
+                token: Some(token),
+                text: tokenizer.decode_incremental(&mut decode, &[token]),
+                done: false,
+                finish_reason: None,
+            });
+        };
+
+        emit(TokenChunk {
+            token: None,
+            text: decode.finish(),
+            done: true,
+            finish_reason: Some(reason.clone()),
+        });
+        reason
+    }
+
+    /// `complete` for several jobs at once, one `run_batch` pass per token
+    /// with every job still generating. Each job's chunks go to its own
+    /// sender. A job whose token was cancelled is finished between passes,
+    /// a failing stage finishes all of them.
+    pub fn complete_batch(&self, pipeline: &Pipeline, tokenizer: &Tokenizer, jobs: Vec<Job>) {
+        let mut rows: Vec<BatchRow> = jobs
+            .into_iter()
+            .map(|job| {
+                let ids = tokenizer.encode(&job.request.prompt);
+                BatchRow {
+                    sampler: job.request.sampler(),
+                    penalty: job.request.repetition_penalty(),
+                    next: Activation::from_tokens(&ids),
+                    ids,
+                    decode: DecodeState::default(),
+                    produced: 0,
+                    caches: self.new_caches(pipeline),
+                    job,
+                }
+            })
+            .collect();
+
+        while !rows.is_empty() {
+            let (done, running): (Vec<_>, Vec<_>) = rows.into_iter().partition(|r| {
+                r.job.cancel.is_cancelled() || r.produced == r.job.request.max_tokens
+            });
+            for row in done {
+                let reason = if row.job.cancel.is_cancelled() {
+                    FinishReason::Cancelled
+                } else {
+                    FinishReason::Length
+                };
+                row.finish(reason);
+            }
+            rows = running;
+            if rows.is_empty() {
+                break;
+            }
+
+            let inputs = rows.iter().map(|r| r.next.clone()).collect();
+            let mut caches: Vec<Vec<KvCache>> = rows
+                .iter_mut()
+                .map(|r| std::mem::take(&mut r.caches))
+                .collect();
+            let outs = match self.run_batch(pipeline, inputs, &mut caches) {
+                Ok(Some(outs)) => outs,
+                Ok(None) => {
+                    for row in rows {
+                        row.finish(FinishReason::Cancelled);
+                    }
+                    return;
+                }
+                Err((failed_stage, e)) => {
+                    for row in rows {
+                        let error = StreamError {
+                            produced: row.produced,
+                            failed_stage,
+                            reason: e.to_string(),
+                        };
+                        row.finish(FinishReason::Failed(error));
+                    }
+                    return;
+                }
+            };
+
+            let mut running = Vec::with_capacity(rows.len());
+            for ((mut row, out), caches) in rows.into_iter().zip(outs).zip(caches) {
+                let mut logits = out.last_row().to_vec();
+                row.penalty.apply(&mut logits, &row.ids);
+                let token = row.sampler.sample(&logits);
+                if Some(token) == tokenizer.eos() {
+                    row.finish(FinishReason::Stop);
+                    continue;
+                }
+                row.ids.push(token);
+                row.next = Activation::from_tokens(&[token]);
+                row.produced += 1;
+                row.caches = caches;
+                let _ = row.job.chunks.send(TokenChunk {
+                    token: Some(token),
+                    text: tokenizer.decode_incremental(&mut row.decode, &[token]),
+                    done: false,
+                    finish_reason: None,
+                });
+                running.push(row);
+            }
+            rows = running;
+        }
+    }
+}
+
+/// One generated token as streamed to the client. The last chunk of a
+/// completion has `done` set and carries no token of its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenChunk {
+    // None on the last chunk
+    pub token: Option<u32>,
+    pub text: String,
+    pub done: bool,
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FinishReason {
+    // the model produced its EOS token
+    Stop,
+    // max_tokens was reached
+    Length,
+    Cancelled,
+    Failed(StreamError),
+}
+
+/// What the client of a generation receives, in order. Anything other
+/// than `Output` ends the stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StreamItem {
+    Output(Activation),
+    Truncated(StreamError),
+    Cancelled,
+}
+
+/// Why a generation stopped early. The outputs before it are valid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamError {
+    // outputs delivered before the failure
+    pub produced: usize,
+    // index of the failed stage in the pipeline
+    pub failed_stage: usize,
+    pub reason: String,
+}
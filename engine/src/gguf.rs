@@ -0,0 +1,335 @@
+//! Reads GGUF model files through a memory map.
+//!
+//! Only the header, metadata and tensor table are parsed on open. Tensor
+//! data stays in the mapping and is paged in when a stage actually reads
+//! the byte range of a tensor it holds, so opening a 70B model costs a few
+//! megabytes of RAM, not the whole file.
+//!
+//! A file that doesn't parse, or whose tensor table points outside of it,
+//! fails to open with `ModelError::CorruptGguf`.
+use std::{collections::HashMap, fs::File, path::Path};
+
+use anyhow::Result;
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    dht::LayerId,
+    model::{Dtype, ModelError},
+    scheduling::LayerRange,
+};
+
+const MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+fn corrupt(detail: impl Into<String>) -> anyhow::Error {
+    ModelError::CorruptGguf {
+        detail: detail.into(),
+    }
+    .into()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    Uint(u64),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::Uint(v) => Some(v),
+            GgufValue::Int(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TensorInfo {
+    pub name: String,
+    pub dims: Vec<u64>,
+    // raw ggml type id, kept for types `Dtype` doesn't model
+    pub ggml_type: u32,
+    pub dtype: Option<Dtype>,
+    // absolute byte offset of the tensor data in the file
+    pub offset: usize,
+    pub size: usize,
+}
+
+impl TensorInfo {
+    pub fn elements(&self) -> usize {
+        self.dims.iter().product::<u64>() as usize
+    }
+
+    /// The block index of `blk.{n}.*` tensors, `None` for embeddings,
+    /// output norm and the like.
+    pub fn layer(&self) -> Option<usize> {
+        self.name.strip_prefix("blk.")?.split('.').next()?.parse().ok()
+    }
+}
+
+pub struct GgufFile {
+    mmap: Mmap,
+    // bytes up to the data section, header, metadata and tensor table
+    header_len: usize,
+    pub version: u32,
+    pub metadata: HashMap<String, GgufValue>,
+    pub tensors: Vec<TensorInfo>,
+}
+
+impl GgufFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<GgufFile> {
+        let file = File::open(path)?;
+        // SAFETY: model files are not modified while a node serves them
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let mut r = Reader { buf: &mmap, pos: 0 };
+        if r.take(4)? != MAGIC {
+            return Err(corrupt("bad magic, not a GGUF file"));
+        }
+        let version = r.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(corrupt(format!("unsupported version {version}")));
+        }
+        let tensor_count = r.u64()?;
+        let kv_count = r.u64()?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..kv_count {
+            let key = r.string()?;
+            let ty = r.u32()?;
+            metadata.insert(key, r.value(ty)?);
+        }
+
+        // (name, dims, type, offset relative to the data section)
+        let mut raw = vec![];
+        for _ in 0..tensor_count {
+            let name = r.string()?;
+            let n_dims = r.u32()?;
+            let dims = (0..n_dims).map(|_| r.u64()).collect::<Result<Vec<_>>>()?;
+            let ggml_type = r.u32()?;
+            let offset = r.u64()?;
+            raw.push((name, dims, ggml_type, offset));
+        }
+
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(GgufValue::as_u64)
+            .filter(|&a| a > 0)
+            .unwrap_or(DEFAULT_ALIGNMENT);
+        let header_len = r.pos;
+        let data_start = (header_len as u64)
+            .div_ceil(alignment)
+            .checked_mul(alignment)
+            .ok_or_else(|| corrupt(format!("alignment {alignment} out of range")))?;
+
+        // tensors of types we can't size run up to the next tensor
+        let mut starts: Vec<u64> = raw.iter().map(|t| t.3).collect();
+        starts.sort_unstable();
+        let data_len = (mmap.len() as u64).saturating_sub(data_start);
+
+        let mut tensors = Vec::with_capacity(raw.len());
+        for (name, dims, ggml_type, rel) in raw {
+            let dtype = dtype_from_ggml(ggml_type);
+            let too_big = || corrupt(format!("tensor {name} has dims {dims:?}"));
+            let elements = dims
+                .iter()
+                .try_fold(1u64, |n, &d| n.checked_mul(d))
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(too_big)?;
+            let size = match dtype {
+                Some(d) => elements
+                    .div_ceil(d.block_size())
+                    .checked_mul(d.block_bytes())
+                    .ok_or_else(too_big)? as u64,
+                None => {
+                    let next = starts.iter().find(|&&s| s > rel).copied();
+                    next.unwrap_or(data_len).saturating_sub(rel)
+                }
+            };
+
+            let end = data_start
+                .checked_add(rel)
+                .and_then(|offset| offset.checked_add(size));
+            if end.is_none_or(|end| end > mmap.len() as u64) {
+                return Err(corrupt(format!(
+                    "tensor {name} at offset {rel} runs past the end of the file"
+                )));
+            }
+            let offset = data_start + rel;
+            tensors.push(TensorInfo {
+                name,
+                dims,
+                ggml_type,
+                dtype,
+                offset: offset as usize,
+                size: size as usize,
+            });
+        }
+
+        Ok(GgufFile {
+            mmap,
+            header_len,
+            version,
+            metadata,
+            tensors,
+        })
+    }
+
+    /// The header, metadata and tensor table as stored, without the tensor
+    /// data.
+    pub fn header_bytes(&self) -> &[u8] {
+        &self.mmap[..self.header_len]
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+
+    /// The tensor's data, borrowed from the mapping. Only these pages are
+    /// read from disk.
+    pub fn tensor_bytes(&self, tensor: &TensorInfo) -> &[u8] {
+        &self.mmap[tensor.offset..tensor.offset + tensor.size]
+    }
+
+    /// The tensors of the transformer blocks in `layers`, in file order.
+    pub fn tensors_for_layers(&self, layers: &LayerRange) -> Vec<&TensorInfo> {
+        self.tensors
+            .iter()
+            .filter(|t| t.layer().is_some_and(|l| layers.contains(l)))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    /// `layer_digest` of every transformer block, for the integrity
+    /// manifest.
+    pub fn layer_digests(&self) -> HashMap<LayerId, [u8; 32]> {
+        let mut layers: Vec<usize> = self.tensors.iter().filter_map(|t| t.layer()).collect();
+        layers.sort_unstable();
+        layers.dedup();
+
+        layers
+            .into_iter()
+            .map(|l| {
+                let tensors = self.tensors_for_layers(&LayerRange {
+                    start: l,
+                    end: l + 1,
+                });
+                let digest = layer_digest(
+                    tensors
+                        .into_iter()
+                        .map(|t| (t.name.as_str(), self.tensor_bytes(t))),
+                );
+                (l as LayerId, digest)
+            })
+            .collect()
+    }
+}
+
+/// sha256 over a layer's tensors in file order, each as its name followed
+/// by its data. The length prefixes keep a byte moved from one tensor to
+/// the next from hashing the same.
+pub fn layer_digest<'a>(tensors: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for (name, data) in tensors {
+        hasher.update((name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(data);
+    }
+    hasher.finalize().into()
+}
+
+fn dtype_from_ggml(ty: u32) -> Option<Dtype> {
+    match ty {
+        0 => Some(Dtype::F32),
+        1 => Some(Dtype::F16),
+        8 => Some(Dtype::Q8_0),
+        12 => Some(Dtype::Q4K),
+        30 => Some(Dtype::BF16),
+        _ => None,
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let Some(end) = self.pos.checked_add(n).filter(|&e| e <= self.buf.len()) else {
+            return Err(corrupt(format!("header truncated at byte {}", self.pos)));
+        };
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u64()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn value(&mut self, ty: u32) -> Result<GgufValue> {
+        let v = match ty {
+            0 => GgufValue::Uint(self.array::<1>()?[0] as u64),
+            1 => GgufValue::Int(self.array::<1>()?[0] as i8 as i64),
+            2 => GgufValue::Uint(u16::from_le_bytes(self.array()?) as u64),
+            3 => GgufValue::Int(i16::from_le_bytes(self.array()?) as i64),
+            4 => GgufValue::Uint(self.u32()? as u64),
+            5 => GgufValue::Int(i32::from_le_bytes(self.array()?) as i64),
+            6 => GgufValue::Float(f32::from_le_bytes(self.array()?) as f64),
+            7 => GgufValue::Bool(self.array::<1>()?[0] != 0),
+            8 => GgufValue::Str(self.string()?),
+            9 => {
+                let item_ty = self.u32()?;
+                let len = self.u64()?;
+                let mut items = vec![];
+                for _ in 0..len {
+                    items.push(self.value(item_ty)?);
+                }
+                GgufValue::Array(items)
+            }
+            10 => GgufValue::Uint(self.u64()?),
+            11 => GgufValue::Int(i64::from_le_bytes(self.array()?)),
+            12 => GgufValue::Float(f64::from_le_bytes(self.array()?)),
+            _ => return Err(corrupt(format!("unknown value type {ty}"))),
+        };
+        Ok(v)
+    }
+}
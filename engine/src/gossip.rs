@@ -1,25 +1,358 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use rand::Rng;
+use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
-    build_local_perf,
-    server::{ClusterMap, send_perf},
+    RamConfig, build_local_perf,
+    client::{PinSet, SpkiHash, send_perf_with},
+    dht::{Handshake, NodePerf},
+    drain::Drain,
+    gpu::{Capability, SystemInfo},
+    latency::LayerLatencies,
+    metrics::GossipMetrics,
+    now_ms,
+    reputation::Reputation,
+    server::ClusterMap,
+    utils::SharedRng,
 };
 
-pub async fn start_gossip_loop(cluster: ClusterMap, node_id: String) {
-    let peers: Vec<String> = vec![];
+/// Time source for the gossip loop, swapped for `sim::SimClock` in the
+/// simulation harness.
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> u64;
+    fn sleep(&self, d: Duration) -> impl Future<Output = ()> + Send;
+}
 
-    loop {
-        let perf = build_local_perf(node_id.clone());
+/// How perf records reach other nodes, swapped for `sim::SimTransport` in
+/// the simulation harness.
+pub trait Transport: Send + Sync {
+    fn send_perf(&self, peer: &str, perf: NodePerf) -> impl Future<Output = Result<()>> + Send;
+
+    /// Called for every record in the cluster map each tick.
+    fn learn_peer(&self, _perf: &NodePerf) {}
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        now_ms()
+    }
 
+    fn sleep(&self, d: Duration) -> impl Future<Output = ()> + Send {
+        tokio::time::sleep(d)
+    }
+}
+
+/// Gossip over QUIC. Peers are trusted by cert pin: the seeds' pins are in
+/// `pins` from the start, and a pin some record claims for an address is
+/// only added once the node there passed our handshake with it. Anyone
+/// can gossip a record, so a pin it carries proves nothing by itself.
+pub struct QuicTransport {
+    pub pins: PinSet,
+    // pins records claim for each address, not trusted yet
+    pub claimed: Mutex<HashMap<String, SpkiHash>>,
+    // sent ahead of every push, see `client::send_perf`
+    pub handshake: Handshake,
+    // zstd compress the records we push, for big RTT maps
+    pub compress: bool,
+}
+
+impl Transport for QuicTransport {
+    fn send_perf(&self, peer: &str, perf: NodePerf) -> impl Future<Output = Result<()>> + Send {
+        let claimed = self
+            .claimed
+            .lock()
+            .unwrap()
+            .get(peer)
+            .copied()
+            .filter(|pin| !self.pins.read().unwrap().contains(pin));
+        async move {
+            let Some(pin) = claimed else {
+                return send_perf_with(peer, perf, &self.pins, &self.handshake, self.compress)
+                    .await;
+            };
+            // the node at `peer` must present exactly the claimed pin
+            let only = PinSet::default();
+            only.write().unwrap().insert(pin);
+            send_perf_with(peer, perf, &only, &self.handshake, self.compress).await?;
+            self.pins.write().unwrap().insert(pin);
+            self.claimed.lock().unwrap().remove(peer);
+            Ok(())
+        }
+    }
+
+    fn learn_peer(&self, perf: &NodePerf) {
+        if let Some(pin) = perf.cert_pin
+            && !perf.addr.is_empty()
         {
-            let mut map = cluster.write().await;
-            map.insert(perf.node_id.clone(), perf.clone());
+            self.claimed.lock().unwrap().insert(perf.addr.clone(), pin);
         }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    pub interval: Duration,
+    // records not refreshed for this long are treated as dead nodes
+    pub stale_after: Duration,
+    // longest a failing peer is left alone before we try it again
+    pub max_backoff: Duration,
+    // how the published ram_tokens and vram_tokens are derived
+    pub ram: RamConfig,
+    // published on top of what `Capability::detect` finds, e.g. fp8
+    pub capabilities: HashSet<Capability>,
+    // published as `NodePerf::region` and `NodePerf::network_bandwidth`
+    pub region: String,
+    pub network_bandwidth: usize,
+    // each sleep is stretched or shortened by up to this fraction of the
+    // interval, so nodes started together drift apart instead of ticking
+    // in lockstep
+    pub jitter: f64,
+}
 
-        for peer in &peers {
-            let _ = send_perf(peer, perf.clone()).await;
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(2),
+            stale_after: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(30),
+            ram: RamConfig::default(),
+            capabilities: HashSet::new(),
+            region: String::new(),
+            network_bandwidth: 0,
+            jitter: 0.1,
         }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PeerState {
+    consecutive_failures: u32,
+    retry_at_ms: u64,
+}
+
+/// Per peer exponential backoff, so a down peer isn't hit every tick while
+/// it waits to be evicted. The skip doubles with each consecutive failure,
+/// starting at one interval and capped at `max_backoff`.
+#[derive(Debug, Default)]
+pub struct PeerBackoff {
+    peers: Mutex<HashMap<String, PeerState>>,
+}
 
-        tokio::time::sleep(Duration::from_secs(2)).await;
+impl PeerBackoff {
+    pub fn should_attempt(&self, peer: &str, now_ms: u64) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers.get(peer).is_none_or(|s| now_ms >= s.retry_at_ms)
     }
+
+    pub fn record_success(&self, peer: &str) {
+        self.peers.lock().unwrap().remove(peer);
+    }
+
+    pub fn record_failure(&self, peer: &str, now_ms: u64, config: &GossipConfig) {
+        let mut peers = self.peers.lock().unwrap();
+        let state = peers.entry(peer.to_string()).or_default();
+        state.consecutive_failures += 1;
+
+        let exp = (state.consecutive_failures - 1).min(16);
+        let delay = config
+            .interval
+            .saturating_mul(1 << exp)
+            .min(config.max_backoff);
+        state.retry_at_ms = now_ms + delay.as_millis() as u64;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GossipEvent {
+    // the node's record went stale and was evicted
+    PeerDown(String),
+}
+
+/// Pauses gossip at runtime, for maintenance. While paused the node neither
+/// publishes its record nor merges the ones it receives, but keeps serving
+/// requests. Clones control the same loop.
+#[derive(Debug, Clone, Default)]
+pub struct GossipHandle {
+    paused: Arc<AtomicBool>,
+}
+
+impl GossipHandle {
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+pub struct GossipNode<C, T> {
+    pub cluster: ClusterMap,
+    pub node_id: String,
+    pub addr: String,
+    pub local_pin: Option<SpkiHash>,
+    // addresses to gossip to besides the ones learned from the cluster map
+    pub seeds: Vec<String>,
+    pub config: GossipConfig,
+    pub clock: C,
+    pub transport: T,
+    pub backoff: PeerBackoff,
+    // shared with `ServerState::drain`, published as `NodePerf::draining`
+    pub drain: Arc<Drain>,
+    // published as `NodePerf::compute_factor`
+    pub compute_factor: Option<f64>,
+    // memory of the machine, probed once since every probe runs nvidia-smi
+    pub system: SystemInfo,
+    // shared with `ServerState::latencies`, published as
+    // `NodePerf::layer_latency`
+    pub latencies: Arc<LayerLatencies>,
+    // shared with `ServerState::metrics`
+    pub metrics: Arc<GossipMetrics>,
+    // shared with `ServerState::reputation`, peers missing a round lose some
+    pub reputation: Arc<Reputation>,
+    // startup delay and jitter, shared with `RouterConfig::rng`
+    pub rng: SharedRng,
+    // shared with `ServerState::gossip`, checked every tick
+    pub handle: GossipHandle,
+    // where membership changes are reported, if anyone listens
+    pub events: Option<UnboundedSender<GossipEvent>>,
+}
+
+/// Drops records whose last update is older than `stale_after_ms`. The
+/// local node's own record is never evicted. Returns the evicted ids.
+pub fn evict_stale(
+    map: &mut HashMap<String, NodePerf>,
+    now_ms: u64,
+    stale_after_ms: u64,
+    local_id: &str,
+) -> Vec<String> {
+    let stale: Vec<String> = map
+        .values()
+        .filter(|p| p.node_id != local_id)
+        .filter(|p| now_ms.saturating_sub(p.timestamp_ms) > stale_after_ms)
+        .map(|p| p.node_id.clone())
+        .collect();
+
+    for id in &stale {
+        map.remove(id);
+    }
+    stale
+}
+
+/// One gossip round: refresh our own record, evict dead nodes, and push our
+/// record to every known peer. Does nothing while the node's handle is
+/// paused.
+pub async fn gossip_tick<C: Clock, T: Transport>(node: &GossipNode<C, T>) {
+    if node.handle.is_paused() {
+        return;
+    }
+    let now = node.clock.now_ms();
+    let metrics = &node.metrics;
+    GossipMetrics::inc(&metrics.gossip_rounds);
+
+    let mut perf = build_local_perf(
+        node.node_id.clone(),
+        &node.system,
+        &node.config.ram,
+        &node.latencies,
+        &node.config.capabilities,
+    );
+    perf.timestamp_ms = now;
+    perf.addr = node.addr.clone();
+    perf.cert_pin = node.local_pin;
+    perf.draining = node.drain.is_draining();
+    perf.compute_factor = node.compute_factor;
+    perf.region = node.config.region.clone();
+    perf.network_bandwidth = node.config.network_bandwidth;
+
+    let mut peers = node.seeds.clone();
+    let mut node_ids: HashMap<String, String> = HashMap::new();
+    {
+        let mut map = node.cluster.write().await;
+        map.insert(perf.node_id.clone(), perf.clone());
+
+        let stale_after = node.config.stale_after.as_millis() as u64;
+        let evicted = evict_stale(&mut map, now, stale_after, &node.node_id);
+        GossipMetrics::add(&metrics.peers_evicted, evicted.len() as u64);
+        if let Some(events) = &node.events {
+            for id in evicted {
+                let _ = events.send(GossipEvent::PeerDown(id));
+            }
+        }
+
+        for p in map.values_mut() {
+            node.transport.learn_peer(p);
+            if p.node_id == node.node_id || p.addr.is_empty() {
+                continue;
+            }
+            node_ids.insert(p.addr.clone(), p.node_id.clone());
+            if !peers.contains(&p.addr) {
+                peers.push(p.addr.clone());
+            }
+        }
+    }
+
+    for peer in &peers {
+        if !node.backoff.should_attempt(peer, now) {
+            continue;
+        }
+        match node.transport.send_perf(peer, perf.clone()).await {
+            Ok(()) => {
+                GossipMetrics::inc(&metrics.perf_sends_ok);
+                node.backoff.record_success(peer);
+                if let Some(id) = node_ids.get(peer) {
+                    node.reputation.record_success(id);
+                }
+            }
+            Err(_) => {
+                GossipMetrics::inc(&metrics.perf_sends_failed);
+                node.backoff.record_failure(peer, now, &node.config);
+                if let Some(id) = node_ids.get(peer) {
+                    node.reputation.record_failure(id);
+                }
+            }
+        }
+    }
+}
+
+/// Ticks every `interval`, give or take `jitter`. The first tick waits a
+/// random part of an interval so a fleet started at once doesn't gossip in
+/// bursts.
+pub async fn start_gossip_loop<C: Clock, T: Transport>(node: &GossipNode<C, T>) {
+    let config = &node.config;
+
+    let first = node.rng.with(|rng| rng.r#gen::<f64>());
+    node.clock.sleep(config.interval.mul_f64(first)).await;
+    loop {
+        gossip_tick(node).await;
+
+        let jitter = config.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + node.rng.with(|rng| rng.gen_range(-jitter..=jitter));
+        node.clock.sleep(config.interval.mul_f64(factor)).await;
+    }
+}
+
+/// Leaves the swarm gracefully: announces that the node is draining right
+/// away instead of waiting for the next tick, then waits up to `timeout`
+/// for in-flight stage work. Returns false if work was still running.
+pub async fn leave<C: Clock, T: Transport>(node: &GossipNode<C, T>, timeout: Duration) -> bool {
+    node.drain.begin();
+    gossip_tick(node).await;
+    node.drain.wait_idle(timeout).await
 }
@@ -7,31 +7,280 @@
 // network_bandwidth
 //
 //
-#[derive(Debug, Clone)]
-struct Node {
-    addr: String,
-    region: String,
-    gpu_score: usize,
-    gpu_cores: usize,
-    network_bandwidth: usize,
-    layer_capacity: usize,
+use std::{cmp::Ordering, collections::HashSet, env, fs, process::Command, str::FromStr};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    calibration, dht::NodePerf, model::ModelMetadata, reputation::Reputation,
+    scheduling::LayerRange, utils::total_cmp_f64,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Node {
+    pub addr: String,
+    pub region: String,
+    pub gpu_score: usize,
+    pub gpu_cores: usize,
+    pub network_bandwidth: usize,
+    pub layer_capacity: usize,
+    pub system: SystemInfo,
 }
 
 impl Node {
     pub fn new(addr: String) -> Node {
         // identify location if location permission is off request permission or terminate
+        // for now the operator tells us through FLUX_REGION
+        let region = env::var("FLUX_REGION").unwrap_or_else(|_| "unknown".into());
 
         // identify gpu on system and derive cores and information about the gpu
+        let system = SystemInfo::detect();
 
         // calculate the network_bandwidth
+        // TODO: measured once we have peers to measure against
 
         // build the Node
-        todo!()
+        Node {
+            addr,
+            region,
+            // reference score until `calibrate` runs
+            gpu_score: calibration::REFERENCE_COMPUTE_CAP,
+            gpu_cores: 0,
+            network_bandwidth: 0,
+            // depends on the model's bytes per layer, unknown at probe time
+            layer_capacity: 0,
+            system,
+        }
+    }
+
+    /// Benchmarks the device and sets `gpu_score` on the shared scale,
+    /// returning the factor to publish. Keeps the neutral score on failure.
+    pub fn calibrate(&mut self) -> Option<f64> {
+        let factor = calibration::calibrate()?;
+        self.gpu_score = calibration::compute_cap(factor);
+        Some(factor)
+    }
+
+    /// Whether a stage serving `layers` fits in free VRAM: the weights, the
+    /// KV cache for `max_seq_len` tokens, and room for the activations
+    /// coming in and going out. Always true when free VRAM couldn't be
+    /// read, refusing every plan would leave the node idle.
+    pub fn can_host(&self, layers: &LayerRange, meta: &ModelMetadata, max_seq_len: usize) -> bool {
+        let n = layers.len();
+        let needed = n * meta.weight_bytes_per_layer()
+            + n * meta.kv_cache_bytes_per_layer(max_seq_len)
+            + 2 * meta.activation_bytes(max_seq_len);
+        self.system.gpu_vram_free.is_none_or(|free| needed <= free)
+    }
+
+    pub fn gpu(&self, node_id: &str) -> Gpu {
+        Gpu {
+            node_id: node_id.to_string(),
+            layer_cap: self.layer_capacity,
+            compute_cap: self.gpu_score,
+            region: self.region.clone(),
+            network_bandwidth: self.network_bandwidth,
+            capabilities: Capability::detect(&self.system),
+        }
+    }
+}
+
+/// A feature a node's runtime supports. Models may require some, see
+/// `SchedulingParams::required_capabilities`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Capability {
+    Fp16,
+    Bf16,
+    Fp8,
+    FlashAttention,
+    Cuda,
+    Metal,
+}
+
+impl Capability {
+    /// What can be told from the probe alone: an nvidia GPU runs CUDA and
+    /// fp16. The rest has to be configured, see `build_local_perf`.
+    pub fn detect(info: &SystemInfo) -> HashSet<Capability> {
+        let mut caps = HashSet::new();
+        if info.gpu_vram > 0 {
+            caps.extend([Capability::Cuda, Capability::Fp16]);
+        }
+        caps
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Capability> {
+        Ok(match s {
+            "fp16" => Capability::Fp16,
+            "bf16" => Capability::Bf16,
+            "fp8" => Capability::Fp8,
+            "flash-attention" => Capability::FlashAttention,
+            "cuda" => Capability::Cuda,
+            "metal" => Capability::Metal,
+            _ => bail!("unknown capability {s:?}"),
+        })
+    }
+}
+
+/// Raw memory figures of the local machine, in bytes.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SystemInfo {
+    pub ram: usize,
+    pub gpu_vram: usize,
+    // VRAM not in use when the probe ran, None when it couldn't be read,
+    // e.g. without nvidia-smi
+    pub gpu_vram_free: Option<usize>,
+}
+
+impl SystemInfo {
+    /// Best effort probe, sizes that can't be read are reported as 0 and
+    /// free VRAM as unknown.
+    pub fn detect() -> SystemInfo {
+        SystemInfo {
+            ram: detect_ram().unwrap_or(0),
+            gpu_vram: detect_gpu_vram("memory.total").unwrap_or(0),
+            gpu_vram_free: detect_gpu_vram("memory.free"),
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Total VRAM in bytes, summed over the devices nvidia-smi lists.
+pub fn detect_vram() -> Option<usize> {
+    detect_gpu_vram("memory.total")
+}
+
+/// Total host RAM in bytes, from /proc/meminfo.
+pub fn detect_ram() -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kib: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+// `field` is an nvidia-smi memory query, memory.total or memory.free
+fn detect_gpu_vram(field: &str) -> Option<usize> {
+    let out = Command::new("nvidia-smi")
+        .args([
+            &format!("--query-gpu={field}"),
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    // one line per device, in MiB
+    let stdout = String::from_utf8(out.stdout).ok()?;
+    let mib: usize = stdout
+        .lines()
+        .filter_map(|l| l.trim().parse::<usize>().ok())
+        .sum();
+    Some(mib * 1024 * 1024)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct Gpu {
+    // node the gpu belongs to
+    pub node_id: String,
     pub layer_cap: usize,
     pub compute_cap: usize,
+    pub region: String,
+    // client facing bandwidth, the entry stage of a pipeline prefers more
+    pub network_bandwidth: usize,
+    // features the node supports, a model requiring one it lacks isn't
+    // placed on it
+    pub capabilities: HashSet<Capability>,
+}
+
+/// Which memory bounds the layers a node is given.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OffloadPolicy {
+    // layers live in VRAM only. Nodes without a GPU run on the CPU anyway
+    // and are bounded by host RAM
+    #[default]
+    GpuOnly,
+    // layers that don't fit in VRAM spill over to host RAM, slower but
+    // more of the model per node
+    CpuOffload,
+}
+
+impl OffloadPolicy {
+    /// Tokens of KV cache `perf` offers under this policy.
+    pub fn tokens(&self, perf: &NodePerf) -> usize {
+        match self {
+            OffloadPolicy::GpuOnly if perf.vram_tokens > 0 => perf.vram_tokens,
+            OffloadPolicy::GpuOnly => perf.ram_tokens,
+            OffloadPolicy::CpuOffload => perf.vram_tokens.saturating_add(perf.ram_tokens),
+        }
+    }
+}
+
+impl Gpu {
+    /// `from_node_perf_with` under `OffloadPolicy::GpuOnly`.
+    pub fn from_node_perf(perf: &NodePerf, meta: &ModelMetadata, reputation: &Reputation) -> Gpu {
+        Gpu::from_node_perf_with(perf, meta, reputation, OffloadPolicy::GpuOnly)
+    }
+
+    /// What the scheduler sees of a node, from its gossiped record.
+    ///
+    /// - `layer_cap`: `offload` picks how many tokens of this model's KV
+    ///   cache the node offers, see `OffloadPolicy::tokens`, so it holds
+    ///   `tokens * kv_cache_bytes_per_token / weight_bytes_per_layer`
+    ///   layers, at most the whole model.
+    /// - `compute_cap`: the inverse of the median measured layer latency for
+    ///   this model (`layer_latency[meta.name]`), relative to
+    ///   `calibration::REFERENCE_LAYER_LATENCY`. Without measurements the
+    ///   calibration factor is used, and without that the reference score.
+    ///   Either way it is scaled by the node's score in `reputation`, so a
+    ///   flaky node loses to an equally fast reliable one.
+    pub fn from_node_perf_with(
+        perf: &NodePerf,
+        meta: &ModelMetadata,
+        reputation: &Reputation,
+        offload: OffloadPolicy,
+    ) -> Gpu {
+        let bytes = offload
+            .tokens(perf)
+            .saturating_mul(meta.kv_cache_bytes_per_token());
+        let layer_cap = (bytes / meta.weight_bytes_per_layer().max(1)).min(meta.model_layers);
+
+        let mut latencies: Vec<f32> = perf
+            .layer_latency
+            .get(&meta.name)
+            .map(|l| l.values().copied().filter(|&ms| ms > 0.0).collect())
+            .unwrap_or_default();
+        latencies.sort_by(|a, b| total_cmp_f64(*a as f64, *b as f64));
+
+        let factor = match latencies.get(latencies.len() / 2) {
+            Some(&median_ms) => {
+                calibration::REFERENCE_LAYER_LATENCY.as_secs_f64() * 1000.0 / median_ms as f64
+            }
+            None => perf.compute_factor.unwrap_or(1.0),
+        };
+
+        Gpu {
+            node_id: perf.node_id.clone(),
+            layer_cap,
+            compute_cap: calibration::compute_cap(factor * reputation.get(&perf.node_id) as f64),
+            region: perf.region.clone(),
+            network_bandwidth: perf.network_bandwidth,
+            capabilities: perf.capabilities.clone(),
+        }
+    }
+
+    /// Order used by the scheduler: non-increasing `layer_cap`, then
+    /// non-increasing `compute_cap`, then `node_id`. Gpus with equal caps
+    /// thus sort the same whatever order the cluster was read in, and a
+    /// snapshot always yields the same plan. Only gpus of one node can
+    /// still tie, the stable sort keeps those in input order.
+    pub fn cmp_for_scheduling(a: &Gpu, b: &Gpu) -> Ordering {
+        b.layer_cap
+            .cmp(&a.layer_cap)
+            .then_with(|| b.compute_cap.cmp(&a.compute_cap))
+            .then_with(|| a.node_id.cmp(&b.node_id))
+    }
 }
@@ -0,0 +1,91 @@
+//! Layer latencies measured on this node, published as
+//! `NodePerf::layer_latency`.
+//!
+//! Each layer keeps a time-decayed moving average: a new sample replaces
+//! more of the old value the longer ago that value was last updated, so one
+//! slow measurement (a GC pause, a cold cache) fades instead of steering
+//! the scheduler forever. Layers not measured for `max_age` stop being
+//! published and the scheduler falls back to the calibration factor.
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use crate::{dht::LayerId, model::ModelId};
+
+#[derive(Debug, Clone)]
+pub struct LatencyConfig {
+    // after this long an old value is worth half of a new sample
+    pub half_life: Duration,
+    // values not refreshed for this long are dropped
+    pub max_age: Duration,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        Self {
+            half_life: Duration::from_secs(60),
+            max_age: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    ms: f32,
+    at_ms: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct LayerLatencies {
+    config: LatencyConfig,
+    samples: Mutex<HashMap<ModelId, HashMap<LayerId, Sample>>>,
+}
+
+impl LayerLatencies {
+    pub fn new(config: LatencyConfig) -> Self {
+        Self {
+            config,
+            samples: Mutex::default(),
+        }
+    }
+
+    /// Folds a measurement of `layer` into its average. The old value's
+    /// weight is `0.5^(elapsed / half_life)`.
+    pub fn record(&self, model_id: &str, layer: LayerId, ms: f32, now_ms: u64) {
+        if !ms.is_finite() || ms < 0.0 {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        let layers = samples.entry(model_id.to_string()).or_default();
+
+        let sample = match layers.get(&layer) {
+            Some(old) => {
+                let elapsed = now_ms.saturating_sub(old.at_ms) as f64;
+                let half_life = self.config.half_life.as_millis().max(1) as f64;
+                let keep = 0.5f64.powf(elapsed / half_life) as f32;
+                Sample {
+                    ms: keep * old.ms + (1.0 - keep) * ms,
+                    at_ms: now_ms.max(old.at_ms),
+                }
+            }
+            None => Sample { ms, at_ms: now_ms },
+        };
+        layers.insert(layer, sample);
+    }
+
+    /// The averages to publish, dropping the ones older than `max_age`.
+    pub fn report(&self, now_ms: u64) -> HashMap<ModelId, HashMap<LayerId, f32>> {
+        let max_age = self.config.max_age.as_millis() as u64;
+        let mut samples = self.samples.lock().unwrap();
+        for layers in samples.values_mut() {
+            layers.retain(|_, s| now_ms.saturating_sub(s.at_ms) <= max_age);
+        }
+        samples.retain(|_, layers| !layers.is_empty());
+
+        samples
+            .iter()
+            .map(|(model, layers)| {
+                let layers = layers.iter().map(|(&l, s)| (l, s.ms)).collect();
+                (model.clone(), layers)
+            })
+            .collect()
+    }
+}
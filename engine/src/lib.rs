@@ -0,0 +1,107 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    dht::NodePerf,
+    gpu::{Capability, SystemInfo},
+    latency::LayerLatencies,
+};
+
+pub mod calibration;
+pub mod client;
+pub mod controller;
+pub mod dht;
+pub mod drain;
+pub mod executor;
+pub mod gossip;
+pub mod gguf;
+pub mod gpu;
+pub mod latency;
+pub mod metrics;
+pub mod model;
+pub mod replay;
+pub mod reputation;
+pub mod router;
+pub mod sampler;
+pub mod scheduling;
+pub mod server;
+pub mod sim;
+pub mod tokenizer;
+pub mod transfer;
+pub mod utils;
+
+/// How host RAM is turned into the `ram_tokens` a node advertises, and VRAM
+/// into its `vram_tokens`.
+#[derive(Debug, Clone)]
+pub struct RamConfig {
+    // kept free for the OS and the inference runtime, never advertised
+    pub reserved_ram_bytes: usize,
+    // host memory one token of context costs, the KV cache of all layers.
+    // `ModelMetadata::kv_cache_bytes_per_token` gives it for a model
+    pub bytes_per_token: usize,
+}
+
+impl Default for RamConfig {
+    fn default() -> Self {
+        Self {
+            reserved_ram_bytes: 4 << 30,
+            // 32 layers of 4096 wide f16 keys and values
+            bytes_per_token: 512 << 10,
+        }
+    }
+}
+
+/// Whole tokens of context that fit in `bytes`, rounding down. A
+/// `bytes_per_token` of 0 is taken as 1.
+pub fn tokens_from_bytes(bytes: usize, bytes_per_token: usize) -> usize {
+    bytes / bytes_per_token.max(1)
+}
+
+/// Tokens of context that fit in `total_ram` bytes once the reservation is
+/// taken off: `(total_ram - reserved_ram_bytes) / bytes_per_token`.
+pub fn ram_tokens(total_ram: usize, config: &RamConfig) -> usize {
+    tokens_from_bytes(
+        total_ram.saturating_sub(config.reserved_ram_bytes),
+        config.bytes_per_token,
+    )
+}
+
+/// The record this node publishes. Memory comes from `system`, probed once
+/// at startup, while `layer_latency` is the current decayed averages. The
+/// reservation only applies to host RAM. `capabilities` are the configured
+/// ones, added to what `Capability::detect` finds.
+pub fn build_local_perf(
+    node_id: String,
+    system: &SystemInfo,
+    ram: &RamConfig,
+    latencies: &LayerLatencies,
+    capabilities: &HashSet<Capability>,
+) -> NodePerf {
+    let now = now_ms();
+    let mut detected = Capability::detect(system);
+    detected.extend(capabilities);
+    NodePerf {
+        node_id,
+        addr: String::new(),
+        ram_tokens: ram_tokens(system.ram, ram),
+        vram_tokens: tokens_from_bytes(system.gpu_vram, ram.bytes_per_token),
+        layer_latency: latencies.report(now),
+        rtt: HashMap::new(),
+        timestamp_ms: now,
+        cert_pin: None,
+        draining: false,
+        compute_factor: None,
+        capabilities: detected,
+        region: String::new(),
+        network_bandwidth: 0,
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
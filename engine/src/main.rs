@@ -1,28 +1,44 @@
 use clap::{Parser, Subcommand};
 use std::{
-    collections::HashMap,
-    env,
-    sync::{Arc, RwLock},
-    time::SystemTime,
+    collections::{HashMap, HashSet},
+    env, fs,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
+use tokio::sync::mpsc::{self, UnboundedSender, unbounded_channel};
+use tokio_util::sync::CancellationToken;
 
-use crate::{
-    dht::NodePerf,
-    gossip::start_gossip_loop,
-    server::{ClusterMap, request_sync, start_server},
+use engine::{
+    RamConfig, build_local_perf,
+    client::{
+        JoinConfig, PinSet, Seed, join_any, load_seedfile, pin_from_hex, pin_to_hex, request_sync,
+    },
+    controller::SchedulerController,
+    dht::{Handshake, dump_perfs},
+    executor::{BatchConfig, MockBackend, PipelineExecutor, serve},
+    gguf::GgufFile,
+    gossip::{
+        GossipConfig, GossipEvent, GossipNode, PeerBackoff, QuicTransport, SystemClock, leave,
+        start_gossip_loop,
+    },
+    gpu::{Capability, Node},
+    latency::LayerLatencies,
+    model::{ModelMetadata, load_metadata, metadata_from_gguf, model_hash},
+    now_ms,
+    scheduling::SchedulingParams,
+    server::{HostLimits, ServerOptions, ServerState, generate_identity, start_server},
+    tokenizer::Tokenizer,
+    transfer::Prefetcher,
+    utils::SharedRng,
 };
 
-mod client;
-mod dht;
-mod gossip;
-mod gpu;
-mod model;
-mod scheduling;
-mod server;
-
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
+    /// Seed for everything random on the node, for reproducible runs
+    #[arg(long, global = true)]
+    seed: Option<u64>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -32,35 +48,251 @@ enum Commands {
     Start {
         #[arg(long)]
         addr: String,
+        /// Extra cert subject alternative name, DNS name or IP (repeatable)
+        #[arg(long = "san")]
+        sans: Vec<String>,
+        /// GGUF file this node serves, joiners must serve the same one
+        #[arg(long)]
+        model: Option<PathBuf>,
+        /// Zstd compress the perf records this node gossips, worth it once
+        /// RTT maps get large
+        #[arg(long)]
+        compress: bool,
+        /// Capability to advertise beyond the detected ones, e.g. fp8 or
+        /// flash-attention (repeatable)
+        #[arg(long = "capability")]
+        capabilities: Vec<Capability>,
     },
     Join {
         #[arg(long)]
         addr: String,
+        #[arg(
+            long,
+            requires = "peer_pin",
+            required_unless_present = "bootstrap_file"
+        )]
+        peer: Option<String>,
+        /// Cert pin of the peer, printed by that node on startup
+        #[arg(long, requires = "peer")]
+        peer_pin: Option<String>,
+        /// File of seed peers tried along with --peer, one `<addr> <pin>`
+        /// per line: the peer's host:port and the cert pin it printed on
+        /// startup. Bare addresses are refused, every peer is pinned. `#`
+        /// starts a comment
+        #[arg(long)]
+        bootstrap_file: Option<PathBuf>,
+        /// Extra cert subject alternative name, DNS name or IP (repeatable)
+        #[arg(long = "san")]
+        sans: Vec<String>,
+        /// GGUF file this node serves, joiners must serve the same one
+        #[arg(long)]
+        model: Option<PathBuf>,
+        /// Zstd compress the perf records this node gossips, worth it once
+        /// RTT maps get large
+        #[arg(long)]
+        compress: bool,
+        /// Capability to advertise beyond the detected ones, e.g. fp8 or
+        /// flash-attention (repeatable)
+        #[arg(long = "capability")]
+        capabilities: Vec<Capability>,
+    },
+    /// Print what this node would advertise, without joining a swarm
+    Info {
+        #[arg(long, default_value = "0.0.0.0:0")]
+        addr: String,
+        /// Capability to advertise beyond the detected ones (repeatable)
+        #[arg(long = "capability")]
+        capabilities: Vec<Capability>,
+    },
+    /// Sync the cluster map from a peer and dump every perf record as JSON
+    DhtDump {
         #[arg(long)]
         peer: String,
+        /// Cert pin of the peer, printed by that node on startup
+        #[arg(long)]
+        peer_pin: String,
+        /// GGUF file the peer serves, it only answers nodes with the same
+        #[arg(long)]
+        model: Option<PathBuf>,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
     },
 }
 
-struct ModelMetadata {
-    name: String,
-    model_layers: usize,
+/// Applies gossip membership changes to the server state.
+fn watch_peers(state: ServerState) -> UnboundedSender<GossipEvent> {
+    let (tx, mut rx) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            match event {
+                GossipEvent::PeerDown(id) => state.peer_down(&id).await,
+            }
+        }
+    });
+    tx
+}
+
+/// Our side of the join handshake, for the model at `path` if any.
+fn local_handshake(path: Option<&PathBuf>) -> anyhow::Result<Handshake> {
+    let Some(path) = path else {
+        return Ok(Handshake::default());
+    };
+    Ok(Handshake {
+        model_id: load_metadata(path)?.name,
+        model_hash: model_hash(path)?,
+        ..Handshake::default()
+    })
+}
+
+// tokens of context the stages of this node are sized for
+const MAX_SEQ_LEN: usize = 4096;
+
+/// What this node can hold of the model at `path`, plans giving it more
+/// are refused. None without a model.
+fn host_limits(
+    node_id: &str,
+    addr: &str,
+    path: Option<&PathBuf>,
+) -> anyhow::Result<Option<Arc<HostLimits>>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let meta = load_metadata(path)?;
+    Ok(Some(Arc::new(HostLimits {
+        node_id: node_id.to_string(),
+        node: Node::new(addr.to_string()),
+        models: HashMap::from([(meta.name.clone(), meta)]),
+        max_seq_len: MAX_SEQ_LEN,
+    })))
 }
 
-fn build_local_perf(node_id: String) -> NodePerf {
-    NodePerf {
-        node_id,
-        ram_tokens: 1024,
-        layer_latency: HashMap::new(),
-        rtt: HashMap::new(),
-        timestamp_ms: now_ms(),
+// requests waiting for the executor, more are refused as busy
+const JOB_QUEUE: usize = 64;
+
+/// Runs the requests queued for the model at `path` and installs the queue
+/// in `state`. There is no inference runtime yet, stages run on
+/// `MockBackend`. Nothing without a model.
+fn spawn_executor(state: &mut ServerState, path: Option<&PathBuf>) -> anyhow::Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let gguf = GgufFile::open(path)?;
+    let model_id = metadata_from_gguf(&gguf)?.name;
+    let tokenizer = Tokenizer::from_gguf(&gguf)?;
+    let (tx, mut jobs) = mpsc::channel(JOB_QUEUE);
+    state.jobs = Some(tx);
+
+    let plans = state.plans.clone();
+    let latencies = state.latencies.clone();
+    let reputation = state.reputation.clone();
+    tokio::spawn(async move {
+        let backend = MockBackend::default();
+        let executor = PipelineExecutor {
+            model_id,
+            backend: &backend,
+            latencies: Some(&latencies),
+            cancel: CancellationToken::new(),
+            max_seq_len: MAX_SEQ_LEN,
+            reputation: Some(&reputation),
+        };
+        let config = BatchConfig::default();
+        serve(&executor, &plans, &tokenizer, &mut jobs, &config).await;
+    });
+    Ok(())
+}
+
+// how long the DP may search before the greedy plan is taken
+const SCHEDULE_BUDGET: Duration = Duration::from_secs(1);
+// how often the plan is recomputed without anything asking for it
+const RESCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+fn scheduling_params(meta: &ModelMetadata) -> SchedulingParams {
+    SchedulingParams {
+        model_id: meta.name.clone(),
+        model_layer: meta.model_layers,
+        alpha: 1.0,
+        r_rtt: 1.0,
+        t_comp: 10.0,
+        max_stages_per_replica: None,
+        affinities: vec![],
+        layer_compute_weights: vec![],
+        // a warm spare where the cluster has one, prefetched by the node
+        standby_count: 1,
+        explain: false,
+        min_compute_cap: 0,
+        hop_rtt: Default::default(),
+        client_regions: vec![],
+        load_budget: None,
+        one_replica_per_region: false,
+        max_pipeline_rtt: None,
+        max_dp_states: None,
+        layer_overlap: 0,
+        required_capabilities: Default::default(),
     }
 }
 
-fn now_ms() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64
+/// Schedules the model at `path` on the gossiped cluster, and prefetches
+/// the layers of the stages this node stands by for into the directory of
+/// the model, which it serves under `/files`. Nothing without a model.
+fn spawn_scheduler(
+    state: &mut ServerState,
+    path: Option<&PathBuf>,
+    pins: &PinSet,
+    max_age: Duration,
+) -> anyhow::Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let meta = load_metadata(path)?;
+    let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+    let root = state.files_root.get_or_insert(dir).clone();
+
+    let (prefetch, requests) = unbounded_channel();
+    state.prefetch = Some(prefetch);
+    // there is no swarm loop in the binary yet, so holder lookups find
+    // nobody and no manifest is known. Prefetches fail and the standby
+    // starts cold
+    let (queries, _) = mpsc::channel(1);
+    let prefetcher = Prefetcher {
+        state: state.clone(),
+        queries,
+        pins: pins.clone(),
+        manifests: HashMap::new(),
+        root,
+    };
+    tokio::spawn(async move { prefetcher.run(requests).await });
+
+    let mut controller = SchedulerController {
+        params: scheduling_params(&meta),
+        budget: Some(SCHEDULE_BUDGET),
+        state: state.clone(),
+        tuner: None,
+        ledger: Arc::default(),
+    };
+    tokio::spawn(async move {
+        controller.run(&meta, max_age, RESCHEDULE_INTERVAL).await;
+    });
+    Ok(())
+}
+
+// how long a leaving node waits for its in-flight stage work
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Gossips until ctrl-c, then drains before returning.
+async fn run_until_shutdown(node: &GossipNode<SystemClock, QuicTransport>) {
+    tokio::select! {
+        _ = start_gossip_loop(node) => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    println!("draining, waiting for in-flight work");
+    if !leave(node, DRAIN_TIMEOUT).await {
+        println!(
+            "drain timed out with {} stage(s) still running",
+            node.drain.in_flight()
+        );
+    }
 }
 
 #[tokio::main]
@@ -69,37 +301,190 @@ async fn main() -> anyhow::Result<()> {
 
     let node_id = env::var("NODE_ID").unwrap_or_else(|_| "node-1".into());
 
-    let cluster: ClusterMap = Arc::new(RwLock::new(HashMap::new()));
+    let rng = SharedRng::new(cli.seed);
+    let mut state = ServerState::default();
+    state.router_config.rng = rng.clone();
+    let cluster = state.cluster.clone();
+    let pins = PinSet::default();
 
     match cli.command {
-        Commands::Start { addr } => {
-            let cluster_clone = cluster.clone();
+        Commands::Start {
+            addr,
+            sans,
+            model,
+            compress,
+            capabilities,
+        } => {
+            state.handshake = local_handshake(model.as_ref())?;
+            state.host = host_limits(&node_id, &addr, model.as_ref())?;
+            spawn_executor(&mut state, model.as_ref())?;
+            let mut local_node = Node::new(addr.clone());
+            let config = GossipConfig {
+                capabilities: HashSet::from_iter(capabilities),
+                region: local_node.region.clone(),
+                network_bandwidth: local_node.network_bandwidth,
+                ..GossipConfig::default()
+            };
+            spawn_scheduler(&mut state, model.as_ref(), &pins, config.stale_after)?;
+            let opts = ServerOptions {
+                cert_sans: sans,
+                ..ServerOptions::default()
+            };
+            let cert = generate_identity(&addr, &opts)?;
+            let local_pin = cert.pin()?;
+            println!("cert pin: {}", pin_to_hex(&local_pin));
+
+            let node = GossipNode {
+                cluster,
+                node_id,
+                addr: addr.clone(),
+                local_pin: Some(local_pin),
+                seeds: vec![],
+                config,
+                clock: SystemClock,
+                transport: QuicTransport {
+                    pins,
+                    claimed: Default::default(),
+                    handshake: state.handshake.clone(),
+                    compress,
+                },
+                backoff: PeerBackoff::default(),
+                drain: state.drain.clone(),
+                compute_factor: local_node.calibrate(),
+                system: local_node.system,
+                latencies: state.latencies.clone(),
+                metrics: state.metrics.clone(),
+                reputation: state.reputation.clone(),
+                rng: rng.clone(),
+                handle: state.gossip.clone(),
+                events: Some(watch_peers(state.clone())),
+            };
 
             tokio::spawn(async move {
-                start_server(&addr, cluster_clone).await.unwrap();
+                start_server(&addr, state, cert, opts).await.unwrap();
             });
 
-            start_gossip_loop(cluster, node_id).await;
+            run_until_shutdown(&node).await;
         }
 
-        Commands::Join { addr, peer } => {
-            let cluster_clone = cluster.clone();
+        Commands::Join {
+            addr,
+            peer,
+            peer_pin,
+            bootstrap_file,
+            sans,
+            model,
+            compress,
+            capabilities,
+        } => {
+            let mut seeds = match &bootstrap_file {
+                Some(path) => load_seedfile(path)?,
+                None => vec![],
+            };
+            if let (Some(addr), Some(pin)) = (peer, peer_pin) {
+                seeds.push(Seed {
+                    addr,
+                    pin: pin_from_hex(&pin)?,
+                });
+            }
+
+            state.handshake = local_handshake(model.as_ref())?;
+            state.host = host_limits(&node_id, &addr, model.as_ref())?;
+            spawn_executor(&mut state, model.as_ref())?;
+            let mut local_node = Node::new(addr.clone());
+            let config = GossipConfig {
+                capabilities: HashSet::from_iter(capabilities),
+                region: local_node.region.clone(),
+                network_bandwidth: local_node.network_bandwidth,
+                ..GossipConfig::default()
+            };
+            spawn_scheduler(&mut state, model.as_ref(), &pins, config.stale_after)?;
+            let local = state.handshake.clone();
+            let opts = ServerOptions {
+                cert_sans: sans,
+                ..ServerOptions::default()
+            };
+            let cert = generate_identity(&addr, &opts)?;
+            let local_pin = cert.pin()?;
+            println!("cert pin: {}", pin_to_hex(&local_pin));
+
+            let node = GossipNode {
+                cluster: cluster.clone(),
+                node_id,
+                addr: addr.clone(),
+                local_pin: Some(local_pin),
+                seeds: seeds.iter().map(|s| s.addr.clone()).collect(),
+                config,
+                clock: SystemClock,
+                transport: QuicTransport {
+                    pins: pins.clone(),
+                    claimed: Default::default(),
+                    handshake: local.clone(),
+                    compress,
+                },
+                backoff: PeerBackoff::default(),
+                drain: state.drain.clone(),
+                compute_factor: local_node.calibrate(),
+                system: local_node.system,
+                latencies: state.latencies.clone(),
+                metrics: state.metrics.clone(),
+                reputation: state.reputation.clone(),
+                rng: rng.clone(),
+                handle: state.gossip.clone(),
+                events: Some(watch_peers(state.clone())),
+            };
 
             tokio::spawn(async move {
-                start_server(&addr, cluster_clone).await.unwrap();
+                start_server(&addr, state, cert, opts).await.unwrap();
             });
 
-            // sync from existing node
-            request_sync(&peer, cluster.clone()).await?;
+            // sync from existing nodes
+            join_any(&seeds, &pins, &local, cluster, &JoinConfig::default()).await?;
+
+            run_until_shutdown(&node).await;
+        }
+
+        Commands::Info { addr, capabilities } => {
+            let mut node = Node::new(addr);
+            let mut perf = build_local_perf(
+                node_id.clone(),
+                &node.system,
+                &RamConfig::default(),
+                &LayerLatencies::default(),
+                &HashSet::from_iter(capabilities),
+            );
+            perf.compute_factor = node.calibrate();
+            perf.region = node.region.clone();
+            perf.network_bandwidth = node.network_bandwidth;
+            let gpu = node.gpu(&node_id);
 
-            start_gossip_loop(cluster, node_id).await;
+            let info = serde_json::json!({
+                "node": node,
+                "perf": perf,
+                "gpu": gpu,
+            });
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
+
+        Commands::DhtDump {
+            peer,
+            peer_pin,
+            model,
+            out,
+        } => {
+            pins.write().unwrap().insert(pin_from_hex(&peer_pin)?);
+            let local = local_handshake(model.as_ref())?;
+            request_sync(&peer, &pins, &local, cluster.clone()).await?;
+
+            let map = cluster.read().await;
+            let dump = dump_perfs(map.values(), now_ms());
+            let json = serde_json::to_string_pretty(&dump)?;
+            match out {
+                Some(path) => fs::write(path, json)?,
+                None => println!("{json}"),
+            }
         }
     }
 
     Ok(())
 }
-
-struct SystemInfo {
-    ram: usize,
-    gpu_vram: usize,
-}
@@ -0,0 +1,103 @@
+//! Counters served in Prometheus text format on `GET /metrics`.
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+#[derive(Debug, Default)]
+pub struct GossipMetrics {
+    pub gossip_rounds: AtomicU64,
+    pub perf_sends_ok: AtomicU64,
+    pub perf_sends_failed: AtomicU64,
+    pub peers_evicted: AtomicU64,
+    // incoming perf records newer than what we had
+    pub records_merged: AtomicU64,
+}
+
+impl GossipMetrics {
+    pub fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, out: &mut String) {
+        let counters = [
+            ("gossip_rounds", &self.gossip_rounds),
+            ("perf_sends_ok", &self.perf_sends_ok),
+            ("perf_sends_failed", &self.perf_sends_failed),
+            ("peers_evicted", &self.peers_evicted),
+            ("records_merged", &self.records_merged),
+        ];
+        for (name, counter) in counters {
+            let _ = writeln!(out, "# TYPE flux_{name}_total counter");
+            let _ = writeln!(out, "flux_{name}_total {}", counter.load(Ordering::Relaxed));
+        }
+    }
+}
+
+// upper bounds of the scheduling duration buckets, in seconds
+const DURATION_BUCKETS: [f64; 8] = [0.001, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0];
+
+/// Scheduler behavior, updated by `controller::SchedulerController`.
+#[derive(Debug, Default)]
+pub struct SchedulerMetrics {
+    pub reschedule_total: AtomicU64,
+    // k̂ of the last adopted plan
+    pub selected_k: AtomicU64,
+    // replicas over every adopted plan
+    pub pipelines_active: AtomicU64,
+    // cumulative counts per bucket, then the +Inf bucket
+    duration_buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    duration_sum_micros: AtomicU64,
+}
+
+impl SchedulerMetrics {
+    pub fn set(gauge: &AtomicU64, v: u64) {
+        gauge.store(v, Ordering::Relaxed);
+    }
+
+    pub fn observe_duration(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.duration_buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.duration_buckets[DURATION_BUCKETS.len()].fetch_add(1, Ordering::Relaxed);
+        self.duration_sum_micros
+            .fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, out: &mut String) {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+
+        let _ = writeln!(out, "# TYPE flux_reschedule_total counter");
+        let _ = writeln!(
+            out,
+            "flux_reschedule_total {}",
+            load(&self.reschedule_total)
+        );
+        for (name, gauge) in [
+            ("selected_k", &self.selected_k),
+            ("pipelines_active", &self.pipelines_active),
+        ] {
+            let _ = writeln!(out, "# TYPE flux_{name} gauge");
+            let _ = writeln!(out, "flux_{name} {}", load(gauge));
+        }
+
+        let name = "flux_scheduling_duration_seconds";
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.duration_buckets) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {}", load(bucket));
+        }
+        let count = load(&self.duration_buckets[DURATION_BUCKETS.len()]);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let sum = load(&self.duration_sum_micros) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
@@ -1,3 +1,180 @@
+use std::{fmt, path::Path};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::gguf::GgufFile;
+
+/// Names a model served by the swarm, plans and layer records are per model.
+pub type ModelId = String;
+
 pub struct Model {
     layers: usize,
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelError {
+    // the file isn't a GGUF file, or contradicts itself, e.g. a download
+    // that stopped halfway
+    CorruptGguf { detail: String },
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::CorruptGguf { detail } => write!(f, "corrupt GGUF file: {detail}"),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+/// Element type of a tensor. Block quantized types (`Q8_0`, `Q4K`) pack a
+/// fixed number of elements together with their scales, so their size is
+/// only exact per block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Dtype {
+    F32,
+    F16,
+    BF16,
+    // 32 × i8 plus an f16 scale
+    Q8_0,
+    // 256 element super-block: f16 d, f16 dmin, 12 bytes of scales, 128 of nibbles
+    Q4K,
+}
+
+impl Dtype {
+    /// Elements per block, 1 for plain float types.
+    pub fn block_size(&self) -> usize {
+        match self {
+            Dtype::F32 | Dtype::F16 | Dtype::BF16 => 1,
+            Dtype::Q8_0 => 32,
+            Dtype::Q4K => 256,
+        }
+    }
+
+    /// Bytes taken by one block.
+    pub fn block_bytes(&self) -> usize {
+        match self {
+            Dtype::F32 => 4,
+            Dtype::F16 | Dtype::BF16 => 2,
+            Dtype::Q8_0 => 34,
+            Dtype::Q4K => 144,
+        }
+    }
+
+    /// Average bytes per element, fractional for quantized types.
+    pub fn bytes_per_element(&self) -> f64 {
+        self.block_bytes() as f64 / self.block_size() as f64
+    }
+
+    /// Bytes needed to store `elements`, rounded up to whole blocks.
+    pub fn bytes_for(&self, elements: usize) -> usize {
+        elements.div_ceil(self.block_size()) * self.block_bytes()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub name: String,
+    pub model_layers: usize,
+    pub hidden_size: usize,
+    pub n_kv_heads: usize,
+    pub head_dim: usize,
+    // width of the feed forward block
+    pub ffn_size: usize,
+    // weights
+    pub dtype: Dtype,
+    // activations and kv cache, never block quantized
+    pub act_dtype: Dtype,
+}
+
+impl ModelMetadata {
+    /// Weights of one transformer block: q and o projections, k and v
+    /// projections over the kv heads, and a gated feed forward block.
+    pub fn weight_bytes_per_layer(&self) -> usize {
+        let h = self.hidden_size;
+        let kv = self.n_kv_heads * self.head_dim;
+        self.dtype
+            .bytes_for(2 * h * h + 2 * h * kv + 3 * h * self.ffn_size)
+    }
+
+    /// KV cache for one layer: a key and a value vector per kv head and position.
+    pub fn kv_cache_bytes_per_layer(&self, seq_len: usize) -> usize {
+        self.act_dtype
+            .bytes_for(2 * self.n_kv_heads * self.head_dim * seq_len)
+    }
+
+    /// KV cache one token of context costs across every layer of the model.
+    pub fn kv_cache_bytes_per_token(&self) -> usize {
+        self.kv_cache_bytes_per_layer(1) * self.model_layers
+    }
+
+    /// Serialized size of the hidden states handed from one stage to the next.
+    pub fn activation_bytes(&self, tokens: usize) -> usize {
+        self.act_dtype.bytes_for(self.hidden_size * tokens)
+    }
+}
+
+/// Reads the model shape from a GGUF file's metadata, without touching the
+/// tensor data. The weight dtype is the one of the first block's tensors.
+pub fn load_metadata(path: impl AsRef<Path>) -> Result<ModelMetadata> {
+    let gguf = GgufFile::open(path)?;
+    metadata_from_gguf(&gguf)
+}
+
+/// Hex sha256 of the model file's header, metadata and tensor table, what
+/// nodes compare to know they serve the same weights. Hashing the tensor
+/// data would read the whole file at startup. Weights that differ under
+/// the same header are caught by the layer manifest on transfer instead.
+pub fn model_hash(path: impl AsRef<Path>) -> Result<String> {
+    let gguf = GgufFile::open(path)?;
+    let mut hasher = Sha256::new();
+    hasher.update(gguf.header_bytes());
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+pub fn metadata_from_gguf(gguf: &GgufFile) -> Result<ModelMetadata> {
+    let arch = gguf
+        .get("general.architecture")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("general.architecture missing"))?;
+    let key = |k: &str| {
+        gguf.get(&format!("{arch}.{k}"))
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize)
+            .ok_or_else(|| anyhow!("{arch}.{k} missing"))
+    };
+
+    let hidden_size = key("embedding_length")?;
+    let n_heads = key("attention.head_count")?;
+    let n_kv_heads = key("attention.head_count_kv").unwrap_or(n_heads);
+    let head_dim = key("attention.key_length").unwrap_or(hidden_size / n_heads.max(1));
+
+    let dtype = gguf
+        .tensors
+        .iter()
+        .filter(|t| t.layer() == Some(0))
+        .find_map(|t| t.dtype)
+        .unwrap_or(Dtype::F16);
+
+    Ok(ModelMetadata {
+        name: gguf
+            .get("general.name")
+            .and_then(|v| v.as_str())
+            .unwrap_or(arch)
+            .to_string(),
+        model_layers: key("block_count")?,
+        hidden_size,
+        n_kv_heads,
+        head_dim,
+        ffn_size: key("feed_forward_length")?,
+        dtype,
+        act_dtype: Dtype::F16,
+    })
+}
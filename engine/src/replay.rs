@@ -0,0 +1,85 @@
+//! Recorded cluster snapshots fed back into the scheduler.
+//!
+//! A snapshot holds what the scheduler saw of a cluster, so the plan it
+//! produced can be reproduced later. The fixtures under `fixtures/snapshots`
+//! are a few representative clusters to replay by hand.
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dht::NodePerf,
+    gpu::Gpu,
+    model::ModelMetadata,
+    reputation::Reputation,
+    scheduling::{PipelinePlan, SchedulingParams, phase1_naive},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterSnapshot {
+    pub meta: ModelMetadata,
+    pub perfs: Vec<NodePerf>,
+    // measured hop latencies as (from, to, ms), indices into `perfs`
+    pub rtt: Vec<(usize, usize, f64)>,
+    pub alpha: f64,
+    pub t_comp: f64,
+}
+
+impl ClusterSnapshot {
+    pub fn load(path: impl AsRef<Path>) -> Result<ClusterSnapshot> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Scheduler input for the snapshot: a gpu per record in order, the
+    /// measured hops, and r_RTT as their mean. Every node counts as fully
+    /// reliable, reputations are local to the node that kept them.
+    pub fn scheduler_input(&self) -> (Vec<Gpu>, SchedulingParams) {
+        let gpus = self
+            .perfs
+            .iter()
+            .map(|p| Gpu::from_node_perf(p, &self.meta, &Reputation::default()))
+            .collect();
+
+        let r_rtt = if self.rtt.is_empty() {
+            0.0
+        } else {
+            self.rtt.iter().map(|(_, _, ms)| ms).sum::<f64>() / self.rtt.len() as f64
+        };
+        let params = SchedulingParams {
+            model_id: self.meta.name.clone(),
+            model_layer: self.meta.model_layers,
+            alpha: self.alpha,
+            r_rtt,
+            t_comp: self.t_comp,
+            max_stages_per_replica: None,
+            affinities: vec![],
+            layer_compute_weights: vec![],
+            standby_count: 0,
+            explain: false,
+            min_compute_cap: 0,
+            hop_rtt: self.rtt.iter().map(|&(a, b, ms)| ((a, b), ms)).collect(),
+            client_regions: vec![],
+            load_budget: None,
+            one_replica_per_region: false,
+            max_pipeline_rtt: None,
+            max_dp_states: None,
+            layer_overlap: 0,
+            required_capabilities: Default::default(),
+        };
+        (gpus, params)
+    }
+}
+
+/// Schedules the snapshot at `path` with the DP and no time budget, so the
+/// same snapshot always gives the same plan.
+pub fn replay_snapshot(path: impl AsRef<Path>) -> Result<PipelinePlan> {
+    let snapshot = ClusterSnapshot::load(path)?;
+    let (gpus, params) = snapshot.scheduler_input();
+    Ok(phase1_naive(&gpus, &params)?)
+}
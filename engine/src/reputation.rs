@@ -0,0 +1,67 @@
+//! How reliable each peer has been, as seen from this node.
+//!
+//! A node's caps say what it could do, not whether it does it. Every stage
+//! failure and every gossip round a peer misses lowers its score, every
+//! success brings it back up a little, so a node that fails once recovers
+//! but a flaky one stays low. The score is kept here only, never gossiped,
+//! and scales the compute the scheduler sees for the peer, see
+//! `Gpu::from_node_perf`.
+use std::{collections::HashMap, sync::Mutex};
+
+#[derive(Debug, Clone)]
+pub struct ReputationConfig {
+    // fraction of the score lost per failure
+    pub penalty: f32,
+    // fraction of the distance to 1.0 regained per success
+    pub recovery: f32,
+    // lowest score, so a node can always earn its way back
+    pub floor: f32,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            penalty: 0.25,
+            recovery: 0.05,
+            floor: 0.05,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Reputation {
+    config: ReputationConfig,
+    scores: Mutex<HashMap<String, f32>>,
+}
+
+impl Reputation {
+    pub fn new(config: ReputationConfig) -> Self {
+        Self {
+            config,
+            scores: Mutex::default(),
+        }
+    }
+
+    /// The node's score in `floor..=1.0`, 1.0 for nodes never seen failing.
+    pub fn get(&self, node_id: &str) -> f32 {
+        self.scores
+            .lock()
+            .unwrap()
+            .get(node_id)
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    pub fn record_failure(&self, node_id: &str) {
+        let mut scores = self.scores.lock().unwrap();
+        let score = scores.entry(node_id.to_string()).or_insert(1.0);
+        *score = (*score * (1.0 - self.config.penalty)).max(self.config.floor);
+    }
+
+    pub fn record_success(&self, node_id: &str) {
+        let mut scores = self.scores.lock().unwrap();
+        if let Some(score) = scores.get_mut(node_id) {
+            *score += (1.0 - *score) * self.config.recovery;
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Picks which pipeline replica serves an inference request.
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::seq::SliceRandom;
+
+use crate::{scheduling::PipelinePlan, utils::SharedRng};
+
+/// Index of a pipeline in `PipelinePlan::pipelines`.
+pub type ReplicaId = usize;
+
+#[derive(Debug, Default)]
+struct Replica {
+    // node ids serving a stage of this replica
+    nodes: HashSet<String>,
+    in_flight: usize,
+    // some node of the replica is draining, it takes no new requests
+    draining: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouterConfig {
+    // requests a replica holds at once, running and waiting. None is
+    // unbounded
+    pub queue_depth: Option<usize>,
+    // suggested to clients turned away because every replica is full
+    pub retry_after: Duration,
+    // breaks ties between equally loaded replicas
+    pub rng: SharedRng,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            queue_depth: Some(32),
+            retry_after: Duration::from_secs(1),
+            rng: SharedRng::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteError {
+    // no replica to route to, or all of them draining
+    NoReplicas,
+    // every replica's queue is full
+    TooBusy { retry_after: Duration },
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::NoReplicas => write!(f, "no replica to route to"),
+            RouteError::TooBusy { retry_after } => {
+                write!(f, "every replica is busy, retry in {retry_after:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// Tracks in-flight requests per replica and sends new ones to the least
+/// loaded replica, breaking ties at random so equal replicas share load.
+/// A replica whose queue is full takes no more requests until one ends.
+#[derive(Debug)]
+pub struct Router {
+    replicas: Mutex<Vec<Replica>>,
+    config: RouterConfig,
+}
+
+impl Router {
+    pub fn new(replicas: usize) -> Router {
+        Router {
+            replicas: Mutex::new((0..replicas).map(|_| Replica::default()).collect()),
+            config: RouterConfig::default(),
+        }
+    }
+
+    pub fn from_plan(plan: &PipelinePlan) -> Router {
+        Router::from_plan_with(plan, RouterConfig::default())
+    }
+
+    pub fn from_plan_with(plan: &PipelinePlan, config: RouterConfig) -> Router {
+        let replicas = plan
+            .pipelines
+            .iter()
+            .map(|p| Replica {
+                nodes: p.stages.iter().map(|s| s.gpu.node_id.clone()).collect(),
+                ..Default::default()
+            })
+            .collect();
+        Router {
+            replicas: Mutex::new(replicas),
+            config,
+        }
+    }
+
+    /// Takes replicas with a stage on a draining node out of rotation, and
+    /// puts the others back. Requests already routed to them are untouched.
+    pub fn set_draining(&self, draining: &HashSet<String>) {
+        let mut replicas = self.replicas.lock().unwrap();
+        for r in replicas.iter_mut() {
+            r.draining = !r.nodes.is_disjoint(draining);
+        }
+    }
+
+    /// Picks a replica and counts the request against it, callers must
+    /// `finish` it once the request is done. The least loaded replica is
+    /// never full unless all of them are, then the request is refused
+    /// with `TooBusy`.
+    pub fn route(&self) -> Result<ReplicaId, RouteError> {
+        let mut replicas = self.replicas.lock().unwrap();
+        let min = replicas
+            .iter()
+            .filter(|r| !r.draining)
+            .map(|r| r.in_flight)
+            .min()
+            .ok_or(RouteError::NoReplicas)?;
+        if self.config.queue_depth.is_some_and(|depth| min >= depth) {
+            return Err(RouteError::TooBusy {
+                retry_after: self.config.retry_after,
+            });
+        }
+
+        let candidates: Vec<ReplicaId> = (0..replicas.len())
+            .filter(|&i| !replicas[i].draining && replicas[i].in_flight == min)
+            .collect();
+        let replica = self
+            .config
+            .rng
+            .with(|rng| candidates.choose(rng).copied())
+            .ok_or(RouteError::NoReplicas)?;
+
+        replicas[replica].in_flight += 1;
+        Ok(replica)
+    }
+
+    pub fn finish(&self, replica: ReplicaId) {
+        let mut replicas = self.replicas.lock().unwrap();
+        if let Some(r) = replicas.get_mut(replica) {
+            r.in_flight = r.in_flight.saturating_sub(1);
+        }
+    }
+
+    /// Like `route`, but the request is finished when the guard drops.
+    pub fn acquire(self: &Arc<Self>) -> Result<RouteGuard, RouteError> {
+        let replica = self.route()?;
+        Ok(RouteGuard {
+            router: self.clone(),
+            replica,
+        })
+    }
+
+    pub fn in_flight(&self) -> Vec<usize> {
+        let replicas = self.replicas.lock().unwrap();
+        replicas.iter().map(|r| r.in_flight).collect()
+    }
+}
+
+pub struct RouteGuard {
+    router: Arc<Router>,
+    pub replica: ReplicaId,
+}
+
+impl Drop for RouteGuard {
+    fn drop(&mut self) {
+        self.router.finish(self.replica);
+    }
+}
@@ -0,0 +1,236 @@
+//! Picks the next token from the logits the last stage produces.
+//!
+//! Which strategy runs is chosen per request through `SamplingConfig`. The
+//! random ones draw from a `StdRng` seeded from the request when it gives
+//! a seed, so a seeded request generates the same text every time.
+use std::{collections::HashSet, sync::Mutex};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::total_cmp_f64;
+
+pub trait Sampler: Send + Sync {
+    fn sample(&self, logits: &[f32]) -> u32;
+}
+
+/// Always the most likely token.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Greedy;
+
+impl Sampler for Greedy {
+    fn sample(&self, logits: &[f32]) -> u32 {
+        argmax(logits)
+    }
+}
+
+/// Draws from the softmax of `logits / temperature`. A temperature of 0 or
+/// less is greedy.
+#[derive(Debug)]
+pub struct Temperature {
+    pub temperature: f32,
+    rng: Mutex<StdRng>,
+}
+
+impl Temperature {
+    pub fn new(temperature: f32, seed: Option<u64>) -> Self {
+        Self {
+            temperature,
+            rng: seeded(seed),
+        }
+    }
+}
+
+impl Sampler for Temperature {
+    fn sample(&self, logits: &[f32]) -> u32 {
+        let candidates = ranked(logits);
+        draw(&candidates, self.temperature, &self.rng)
+    }
+}
+
+/// Like `Temperature`, among the `k` most likely tokens only.
+#[derive(Debug)]
+pub struct TopK {
+    pub k: usize,
+    pub temperature: f32,
+    rng: Mutex<StdRng>,
+}
+
+impl TopK {
+    pub fn new(k: usize, temperature: f32, seed: Option<u64>) -> Self {
+        Self {
+            k,
+            temperature,
+            rng: seeded(seed),
+        }
+    }
+}
+
+impl Sampler for TopK {
+    fn sample(&self, logits: &[f32]) -> u32 {
+        let mut candidates = ranked(logits);
+        candidates.truncate(self.k.max(1));
+        draw(&candidates, self.temperature, &self.rng)
+    }
+}
+
+/// Like `Temperature`, among the fewest most likely tokens whose
+/// probabilities add up to at least `p` (nucleus sampling).
+#[derive(Debug)]
+pub struct TopP {
+    pub p: f32,
+    pub temperature: f32,
+    rng: Mutex<StdRng>,
+}
+
+impl TopP {
+    pub fn new(p: f32, temperature: f32, seed: Option<u64>) -> Self {
+        Self {
+            p,
+            temperature,
+            rng: seeded(seed),
+        }
+    }
+}
+
+impl Sampler for TopP {
+    fn sample(&self, logits: &[f32]) -> u32 {
+        let mut candidates = ranked(logits);
+        let probs = softmax(&candidates, self.temperature.max(f32::EPSILON));
+        let mut mass = 0.0;
+        let keep = probs
+            .iter()
+            .position(|&q| {
+                mass += q;
+                mass >= self.p as f64
+            })
+            .map_or(candidates.len(), |i| i + 1);
+        candidates.truncate(keep.max(1));
+        draw(&candidates, self.temperature, &self.rng)
+    }
+}
+
+/// Scales down the logits of tokens seen in the last `window` ids, so the
+/// model is less likely to loop: positive logits are divided by `penalty`,
+/// negative ones multiplied. 1.0 leaves logits as they are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepetitionPenalty {
+    pub penalty: f32,
+    pub window: usize,
+}
+
+impl RepetitionPenalty {
+    pub fn apply(&self, logits: &mut [f32], ids: &[u32]) {
+        if self.penalty == 1.0 || self.penalty <= 0.0 {
+            return;
+        }
+        let recent = &ids[ids.len().saturating_sub(self.window)..];
+        let mut seen = HashSet::new();
+        for &id in recent {
+            if !seen.insert(id) {
+                continue;
+            }
+            if let Some(x) = logits.get_mut(id as usize) {
+                *x = if *x > 0.0 {
+                    *x / self.penalty
+                } else {
+                    *x * self.penalty
+                };
+            }
+        }
+    }
+}
+
+/// Strategy asked for in an `InferenceRequest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SamplingConfig {
+    #[default]
+    Greedy,
+    Temperature {
+        temperature: f32,
+    },
+    TopK {
+        k: usize,
+        temperature: f32,
+    },
+    TopP {
+        p: f32,
+        temperature: f32,
+    },
+}
+
+impl SamplingConfig {
+    pub fn sampler(&self, seed: Option<u64>) -> Box<dyn Sampler> {
+        match *self {
+            SamplingConfig::Greedy => Box::new(Greedy),
+            SamplingConfig::Temperature { temperature } => {
+                Box::new(Temperature::new(temperature, seed))
+            }
+            SamplingConfig::TopK { k, temperature } => Box::new(TopK::new(k, temperature, seed)),
+            SamplingConfig::TopP { p, temperature } => Box::new(TopP::new(p, temperature, seed)),
+        }
+    }
+}
+
+fn seeded(seed: Option<u64>) -> Mutex<StdRng> {
+    Mutex::new(match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    })
+}
+
+// index of the highest logit, the first one on ties. NaNs never win
+fn argmax(logits: &[f32]) -> u32 {
+    let mut best = 0;
+    for (i, &x) in logits.iter().enumerate() {
+        if !x.is_nan() && (logits[best].is_nan() || x > logits[best]) {
+            best = i;
+        }
+    }
+    best as u32
+}
+
+// (token, logit) most likely first, NaNs left out
+fn ranked(logits: &[f32]) -> Vec<(u32, f32)> {
+    let mut candidates: Vec<(u32, f32)> = logits
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| !x.is_nan())
+        .map(|(i, &x)| (i as u32, x))
+        .collect();
+    candidates.sort_by(|a, b| total_cmp_f64(b.1 as f64, a.1 as f64));
+    candidates
+}
+
+// probabilities of `candidates`, which come most likely first
+fn softmax(candidates: &[(u32, f32)], temperature: f32) -> Vec<f64> {
+    let Some(&(_, max)) = candidates.first() else {
+        return vec![];
+    };
+    let weights: Vec<f64> = candidates
+        .iter()
+        .map(|&(_, x)| (((x - max) / temperature) as f64).exp())
+        .collect();
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|w| w / total).collect()
+}
+
+fn draw(candidates: &[(u32, f32)], temperature: f32, rng: &Mutex<StdRng>) -> u32 {
+    let Some(&(first, _)) = candidates.first() else {
+        return 0;
+    };
+    if temperature <= 0.0 {
+        return first;
+    }
+    let probs = softmax(candidates, temperature);
+    let mut x: f64 = rng.lock().unwrap().r#gen();
+    for (&(token, _), p) in candidates.iter().zip(probs) {
+        if x < p {
+            return token;
+        }
+        x -= p;
+    }
+    // rounding left a sliver past the last candidate
+    first
+}
@@ -103,21 +103,495 @@
 //! -----------------------------------------------------------------------------
 
 use core::{f32, f64};
-use std::collections::HashMap;
+use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::warn;
 
 use crate::{
+    calibration,
     dht::{LayerId, NodeId, NodePerf},
-    gpu::Gpu,
+    gpu::{Capability, Gpu},
+    model::ModelId,
+    utils::total_cmp_f64,
 };
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
+pub struct SchedulingParams {
+    // the model being placed, each model is scheduled on its own
+    pub model_id: ModelId,
+    // L, the number of layers in the model
+    pub model_layer: usize,
+    // α, how strongly Z(k) favors more replications
+    pub alpha: f64,
+    // r_RTT, average inter-stage hop latency
+    pub r_rtt: f64,
+    // T_comp, average per-replication compute time
+    pub t_comp: f64,
+    // upper bound on stages in a single replica, deep pipelines pay an RTT
+    // per hop no matter what Z(k) says
+    pub max_stages_per_replica: Option<usize>,
+    // placement constraints the DP must honor, gpu indices refer to the
+    // slice passed to phase1_naive
+    pub affinities: Vec<Affinity>,
+    // relative compute cost of each layer, one entry per layer. Stages are
+    // balanced by weighted compute instead of layer count when set, the
+    // capacity DP still counts layers. Empty means all layers cost the same
+    pub layer_compute_weights: Vec<f64>,
+    // spare gpus to keep warm as stand-ins for active stages, see `Standby`
+    pub standby_count: usize,
+    // record why k̂ won in `PipelinePlan::report`, for operators
+    pub explain: bool,
+    // stages slower than this are dropped from their pipeline when the
+    // others can hold the model without them, they would mostly add a hop.
+    // 0 keeps every stage
+    pub min_compute_cap: usize,
+    // measured hop costs between gpus, by index into the slice passed to
+    // the scheduler. When set, equally capable stages are ordered by the
+    // cost of the forward hops activations take instead of by region
+    pub hop_rtt: RttMatrix,
+    // regions prompts come from, a gpu in one of them is preferred as the
+    // entry stage of a pipeline
+    pub client_regions: Vec<String>,
+    // gpus get no more layers than they can download in time, see
+    // `LoadBudget`
+    pub load_budget: Option<LoadBudget>,
+    // every replica is kept inside a single region of its own, so losing a
+    // region takes down at most one replica. Caps k at the region count
+    pub one_replica_per_region: bool,
+    // latency budget for the forward hops of a replica, summed from
+    // `hop_rtt` (`r_rtt` for unmeasured pairs). A k whose replicas go over
+    // it loses to the next best k that fits
+    pub max_pipeline_rtt: Option<Duration>,
+    // DP states `schedule_pipelines` explores over all k before giving up
+    // on the DP for the greedy plan, so no input can hang the scheduler
+    pub max_dp_states: Option<usize>,
+    // layers each stage also preloads from the end of the stage before it,
+    // so it can cover part of that stage when its node fails, see
+    // `Stage::overlap`. Counted against the stage's layer cap, the DP
+    // already leaves it out of every stage but the first
+    pub layer_overlap: usize,
+    // features a gpu needs to get any of the model, e.g. fp8 for an fp8
+    // checkpoint. Gpus lacking one are left out, the greedy fallback
+    // included
+    pub required_capabilities: HashSet<Capability>,
+}
+
+/// How long a node may take to fetch the weights of its stage at startup.
+/// A gpu is given at most the layers its `network_bandwidth` (bytes per
+/// second) brings in within `max_load_time`. Gpus with an unmeasured
+/// bandwidth of 0 are not limited.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadBudget {
+    pub weight_bytes_per_layer: usize,
+    pub max_load_time: Duration,
+}
+
+impl LoadBudget {
+    /// Layers `gpu` can load in time, at most its `layer_cap`.
+    pub fn layer_cap(&self, gpu: &Gpu) -> usize {
+        if gpu.network_bandwidth == 0 {
+            return gpu.layer_cap;
+        }
+        let bytes = gpu.network_bandwidth as f64 * self.max_load_time.as_secs_f64();
+        let layers = bytes / self.weight_bytes_per_layer.max(1) as f64;
+        (layers as usize).min(gpu.layer_cap)
+    }
+}
+
+// the gpus with their caps cut to what they can load in time
+fn apply_load_budget(gpu_caps: &[Gpu], params: &SchedulingParams) -> Vec<Gpu> {
+    let Some(budget) = &params.load_budget else {
+        return gpu_caps.to_vec();
+    };
+    gpu_caps
+        .iter()
+        .map(|g| Gpu {
+            layer_cap: budget.layer_cap(g),
+            ..g.clone()
+        })
+        .collect()
+}
+
+// whether `gpu` has every capability the model requires
+fn capable(gpu: &Gpu, params: &SchedulingParams) -> bool {
+    params.required_capabilities.is_subset(&gpu.capabilities)
+}
+
+/// Cost in ms of sending activations from gpu a to gpu b, keyed `(a, b)`.
+/// The two directions of a pair can differ, a pair measured one way only is
+/// taken as symmetric.
+pub type RttMatrix = HashMap<(usize, usize), f64>;
+
+/// Forward cost of the hop `from -> to`, the reverse direction when only
+/// that was measured, `default` when neither was.
+pub fn hop_cost(rtt: &RttMatrix, from: usize, to: usize, default: f64) -> f64 {
+    rtt.get(&(from, to))
+        .or_else(|| rtt.get(&(to, from)))
+        .copied()
+        .unwrap_or(default)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Affinity {
+    // the gpu must serve stage `stage` (0 being the entry stage) of some replica
+    PinToStage { gpu_idx: usize, stage: usize },
+    // the two gpus must be used together in the same replica, or not at all
+    Colocate { a: usize, b: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchedulingError {
+    // no gpus to schedule on
+    NoGpus,
+    // the gpus together can't hold a single copy of the model
+    InsufficientCapacity { available: usize, required: usize },
+    // a NaN turned up in the named input or intermediate value
+    NaN(&'static str),
+    // the model has no layers to place
+    InvalidModel,
+    // one_replica_per_region tracks regions in a u64
+    TooManyRegions { count: usize },
+    // the DP trace doesn't describe a valid plan
+    Reconstruct(ReconstructError),
+    // no k in 1..=k_max can be assembled under the given constraints
+    NoFeasiblePlan,
+    // an affinity references a gpu that isn't in the input
+    InvalidAffinity(Affinity),
+    // the DP ran past its time budget
+    TimedOut,
+    // the DP explored more states than max_dp_states allows
+    StateLimit { limit: usize },
+    // layer_compute_weights doesn't have one entry per model layer
+    LayerWeightsMismatch { expected: usize, got: usize },
+    // feasible layouts exist but alpha, r_rtt and t_comp give none of them
+    // a finite Z(k), e.g. all zero when there is no profiling data yet
+    DegenerateParams,
+}
+
+impl fmt::Display for SchedulingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulingError::NoGpus => write!(f, "no gpus to schedule on"),
+            SchedulingError::InsufficientCapacity {
+                available,
+                required,
+            } => write!(
+                f,
+                "gpus hold {available} layers in total, the model needs {required}"
+            ),
+            SchedulingError::NaN(what) => write!(f, "{what} is NaN"),
+            SchedulingError::InvalidModel => write!(f, "the model has no layers"),
+            SchedulingError::TooManyRegions { count } => {
+                write!(
+                    f,
+                    "{count} regions, one replica per region supports up to 64"
+                )
+            }
+            SchedulingError::Reconstruct(e) => write!(f, "reconstructing the plan: {e}"),
+            SchedulingError::NoFeasiblePlan => {
+                write!(f, "no pipeline layout satisfies the scheduling constraints")
+            }
+            SchedulingError::InvalidAffinity(a) => write!(f, "invalid affinity {a:?}"),
+            SchedulingError::TimedOut => write!(f, "scheduling DP exceeded its time budget"),
+            SchedulingError::StateLimit { limit } => {
+                write!(f, "scheduling DP explored more than {limit} states")
+            }
+            SchedulingError::LayerWeightsMismatch { expected, got } => write!(
+                f,
+                "expected {expected} layer compute weights, got {got}"
+            ),
+            SchedulingError::DegenerateParams => {
+                write!(f, "scheduling params give no replica count a finite score")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SchedulingError {}
+
+impl From<ReconstructError> for SchedulingError {
+    fn from(e: ReconstructError) -> Self {
+        SchedulingError::Reconstruct(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReconstructError {
+    // the gpu (index into the sorted order) was placed in two pipelines
+    GpuReused { gpu_idx: usize },
+}
+
+impl fmt::Display for ReconstructError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReconstructError::GpuReused { gpu_idx } => {
+                write!(f, "gpu {gpu_idx} is assigned to more than one pipeline")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReconstructError {}
+
+/// Half-open block of model layers `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LayerRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl LayerRange {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn contains(&self, layer: usize) -> bool {
+        (self.start..self.end).contains(&layer)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stage {
+    // index of the gpu in the slice passed to the scheduler
+    pub gpu_idx: usize,
+    pub gpu: Gpu,
+    // the layers this stage runs, stages of a pipeline tile 0..model_layer
+    // in order
+    pub range: LayerRange,
+    // layers right before `range` the stage loads as well without running
+    // them, a spare copy of the previous stage's last layers
+    pub overlap: usize,
+}
+
+impl Stage {
+    /// Every layer the stage holds, `range` plus the overlap before it.
+    pub fn loaded(&self) -> LayerRange {
+        LayerRange {
+            start: self.range.start.saturating_sub(self.overlap),
+            end: self.range.end,
+        }
+    }
+}
+
+/// A gpu placed in a pipeline, with its index so the stage can be traced
+/// back to a node. `reconstruct` indexes the sorted order the DP walks,
+/// everything after it the slice passed to the scheduler.
+#[derive(Debug, Clone, Serialize)]
+pub struct StageAssignment {
+    pub gpu_idx: usize,
+    pub gpu: Gpu,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+/// A spare gpu that preloads the layers of one active stage and serves no
+/// traffic, so it can take the stage over without a reschedule when the
+/// stage's node goes down.
+#[derive(Debug, Clone, Serialize)]
+pub struct Standby {
+    // index of the gpu in the slice passed to the scheduler
+    pub gpu_idx: usize,
+    pub gpu: Gpu,
+    pub pipeline: usize,
+    pub stage: usize,
+    pub range: LayerRange,
+}
+
+/// Whether a plan is the DP's optimum or a fallback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum PlanQuality {
+    #[default]
+    Optimal,
+    // the DP gave up on time or state budget and `schedule_greedy` made
+    // the plan, it ignores most constraints
+    Degraded,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelinePlan {
+    pub model_id: ModelId,
+    // k̂, the number of pipeline replications
+    pub k: usize,
+    pub quality: PlanQuality,
+    pub pipelines: Vec<Pipeline>,
+    pub standby: Vec<Standby>,
+    // filled by the DP when `SchedulingParams::explain` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<SchedulingReport>,
+}
+
+/// How the DP scored every replica count it tried.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SchedulingReport {
+    // one entry per k in 1..=k_max, in order
+    pub candidates: Vec<KCandidate>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KCandidate {
+    pub k: usize,
+    // s*(k), the minimal stage count over the k replicas, None if infeasible
+    pub s_star: Option<usize>,
+    // the score, Z(k) unless a custom `ScoreFn` was given. None if it
+    // couldn't be computed
+    pub z: Option<f64>,
+    pub outcome: KOutcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum KOutcome {
+    Selected,
+    // a feasible k with a lower Z than the selected one
+    Outscored,
+    // k replicas can't be built under the capacities and constraints
+    Infeasible,
+    // the score wasn't finite, for Z(k) the latency terms add up to zero
+    DegenerateScore,
+    // a replica's forward hops add up to more than max_pipeline_rtt
+    OverRttBudget,
+}
+
+impl PipelinePlan {
+    /// `(pipeline, stage, stage info)` of the stage holding `layer` in each
+    /// replica.
+    pub fn stages_for_layer(&self, layer: usize) -> impl Iterator<Item = (usize, usize, &Stage)> {
+        self.pipelines.iter().enumerate().filter_map(move |(p, pipeline)| {
+            let (i, stage) = pipeline
+                .stages
+                .iter()
+                .enumerate()
+                .find(|(_, s)| s.range.contains(layer))?;
+            Some((p, i, stage))
+        })
+    }
+
+    /// Like `stages_for_layer`, but also the stages preloading `layer` as
+    /// their overlap, every node a transfer of the layer can come from.
+    pub fn holders_of_layer(&self, layer: usize) -> impl Iterator<Item = &Stage> {
+        self.pipelines
+            .iter()
+            .flat_map(|p| &p.stages)
+            .filter(move |s| s.loaded().contains(layer))
+    }
+
+    /// The plan as a Graphviz digraph, one cluster per replica with its
+    /// stages chained in order, e.g. for `dot -Tsvg`.
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with(&RttMatrix::new())
+    }
+
+    /// `to_dot` with the hops labeled by their cost in `hop_rtt`, which is
+    /// keyed by `Stage::gpu_idx`. Unmeasured hops stay unlabeled.
+    pub fn to_dot_with(&self, hop_rtt: &RttMatrix) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = format!("digraph \"{}\" {{\n  rankdir=LR;\n", escape(&self.model_id));
+        for (p, pipeline) in self.pipelines.iter().enumerate() {
+            out += &format!("  subgraph cluster_{p} {{\n    label=\"replica {p}\";\n");
+            for (s, stage) in pipeline.stages.iter().enumerate() {
+                // \n is dot's line break inside a label
+                let label = format!(
+                    "{}\\n{}..{} ({} layers)",
+                    escape(&stage.gpu.node_id),
+                    stage.range.start,
+                    stage.range.end,
+                    stage.range.len()
+                );
+                out += &format!("    p{p}s{s} [shape=box, label=\"{label}\"];\n");
+            }
+            for (s, pair) in pipeline.stages.windows(2).enumerate() {
+                let (from, to) = (pair[0].gpu_idx, pair[1].gpu_idx);
+                let ms = hop_rtt
+                    .get(&(from, to))
+                    .or_else(|| hop_rtt.get(&(to, from)));
+                let label = ms.map_or(String::new(), |ms| format!(" [label=\"{ms:.1} ms\"]"));
+                out += &format!("    p{p}s{s} -> p{p}s{}{label};\n", s + 1);
+            }
+            out += "  }\n";
+        }
+        out += "}\n";
+        out
+    }
+
+    /// Hands every stage on `node_id` to the standby preloading it. Returns
+    /// false if some stage had no standby, the plan then needs a reschedule.
+    pub fn promote_standby(&mut self, node_id: &str) -> bool {
+        // standbys on the dead node are gone as well
+        self.standby.retain(|sb| sb.gpu.node_id != node_id);
+
+        let mut covered = true;
+        for (p, pipeline) in self.pipelines.iter_mut().enumerate() {
+            for (s, stage) in pipeline.stages.iter_mut().enumerate() {
+                if stage.gpu.node_id != node_id {
+                    continue;
+                }
+                match self
+                    .standby
+                    .iter()
+                    .position(|sb| sb.pipeline == p && sb.stage == s)
+                {
+                    Some(i) => {
+                        let sb = self.standby.remove(i);
+                        stage.gpu_idx = sb.gpu_idx;
+                        stage.gpu = sb.gpu;
+                    }
+                    None => covered = false,
+                }
+            }
+        }
+        covered
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Partial {
+    // residual layer count r_j of this partially assigned pipeline
+    r: usize,
+    // stages already placed in the pipeline
+    stages: usize,
+    // bit j is set when the first gpu of colocate constraint j sits here
+    colocate: u64,
+    // region bit of the pipeline's gpus, 0 without one_replica_per_region
+    region: u64,
+}
+
+// layers left for the rest of a pipeline a gpu starts, the first stage has
+// nothing to overlap with
+fn start_residual(model_layer: usize, layer_cap: usize) -> usize {
+    model_layer.saturating_sub(layer_cap)
+}
+
+// layers a gpu takes off a pipeline it extends, the stage keeps room for
+// the overlap with the one before
+fn extend_cap(layer_cap: usize, layer_overlap: usize) -> usize {
+    layer_cap.saturating_sub(layer_overlap)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 struct DpState {
     // The state tracks r = (r1 ≤ r2 ≤ · · · ≤ rm)
     // as the sorted residual layer counts for partially assigned pipelines,
     // where each rj ∈ {1, 2, . . . , L − 1}
-    r: Vec<usize>,
+    r: Vec<Partial>,
     // f as the count of fully assigned pipelines (containing all L layers).
     f: usize,
+    // bit j is set when the first gpu of colocate constraint j was skipped
+    skipped: u64,
+    // regions some pipeline, partial or complete, has been started in
+    regions: u64,
 }
 
 impl DpState {
@@ -125,167 +599,1028 @@ impl DpState {
         Self {
             r: Vec::new(),
             f: 0,
+            skipped: 0,
+            regions: 0,
         }
     }
     fn normalize(&mut self) {
         self.r.sort_unstable();
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum Decision {
     Skip,
     Extend(usize),
     StartNew,
 }
 
+// back-pointer of a DP state: its dp1 value, and the decision achieving
+// it with the index of the state it leads to
+#[derive(Debug, Clone)]
 struct ResultState {
     stages: usize,
-    decision: Option<Decision>,
+    decision: Option<(Decision, usize)>,
 }
 
-pub fn phase1_naive(gpu_caps: &Vec<Gpu>, model_layer: usize, alpha: f64, r_rtt: f64, t_comp: f64) {
-    let mut sorted = gpu_caps.clone();
-    // non increasing order
-    sorted.sort_unstable_by(|b, a| b.layer_cap.cmp(&a.layer_cap));
+/// Affinities resolved against the sorted gpu order the DP walks.
+#[derive(Debug, Clone, Default)]
+struct AffinityIndex {
+    // stage each gpu is pinned to
+    pinned: Vec<Option<usize>>,
+    // colocate constraints where this gpu is the earlier member
+    opens: Vec<u64>,
+    // colocate constraints where this gpu is the later member
+    closes: Vec<u64>,
+    // bit of this gpu's region, all 0 unless one_replica_per_region is set
+    region: Vec<u64>,
+}
 
+impl AffinityIndex {
+    // `position[i]` is where input gpu i ended up after sorting, `n` or
+    // more for gpus left out. Affinities on those are invalid
+    fn build(
+        affinities: &[Affinity],
+        position: &[usize],
+        n: usize,
+    ) -> Result<Self, SchedulingError> {
+        let mut index = AffinityIndex {
+            pinned: vec![None; n],
+            opens: vec![0; n],
+            closes: vec![0; n],
+            region: vec![0; n],
+        };
+
+        let mut colocate_bit = 0;
+        for affinity in affinities {
+            let invalid = || SchedulingError::InvalidAffinity(affinity.clone());
+            match *affinity {
+                Affinity::PinToStage { gpu_idx, stage } => {
+                    let pos = *position
+                        .get(gpu_idx)
+                        .filter(|&&p| p < n)
+                        .ok_or_else(invalid)?;
+                    if index.pinned[pos].is_some_and(|s| s != stage) {
+                        return Err(invalid());
+                    }
+                    index.pinned[pos] = Some(stage);
+                }
+                Affinity::Colocate { a, b } => {
+                    let pa = *position.get(a).filter(|&&p| p < n).ok_or_else(invalid)?;
+                    let pb = *position.get(b).filter(|&&p| p < n).ok_or_else(invalid)?;
+                    if pa == pb || colocate_bit >= u64::BITS {
+                        return Err(invalid());
+                    }
+                    let bit = 1u64 << colocate_bit;
+                    colocate_bit += 1;
+                    index.opens[pa.min(pb)] |= bit;
+                    index.closes[pa.max(pb)] |= bit;
+                }
+            }
+        }
+
+        Ok(index)
+    }
+
+    // gives each distinct region of the sorted `gpus` a bit, returning how
+    // many there are
+    fn assign_regions(&mut self, gpus: &[Gpu]) -> Result<usize, SchedulingError> {
+        let mut bits: HashMap<&str, u64> = HashMap::new();
+        for (pos, gpu) in gpus.iter().enumerate() {
+            let next = bits.len();
+            let bit = *bits.entry(gpu.region.as_str()).or_insert_with(|| {
+                // out of range bits are caught below
+                1u64.checked_shl(next as u32).unwrap_or(0)
+            });
+            self.region[pos] = bit;
+        }
+        if bits.len() > u64::BITS as usize {
+            return Err(SchedulingError::TooManyRegions { count: bits.len() });
+        }
+        Ok(bits.len())
+    }
+}
+
+struct DpCtx<'a> {
+    gpus: &'a [Gpu],
+    params: &'a SchedulingParams,
+    affinity: &'a AffinityIndex,
+    k: usize,
+    deadline: Option<Instant>,
+    timed_out: Cell<bool>,
+    // states explored so far, over every k
+    explored: &'a Cell<usize>,
+    state_limit_hit: Cell<bool>,
+}
+
+/// Schedules with the Phase-1 DP, falling back to `schedule_greedy` if the
+/// DP hasn't finished within `budget` or explored more than
+/// `max_dp_states`. A `None` budget always waits for the DP optimum. The
+/// fallback plan is marked `PlanQuality::Degraded`.
+///
+/// The greedy fallback ignores `max_stages_per_replica`, affinities,
+/// `one_replica_per_region`, `max_pipeline_rtt` and `standby_count`, an
+/// online reschedule would rather have some plan than none.
+pub fn schedule_pipelines(
+    gpu_caps: &[Gpu],
+    params: &SchedulingParams,
+    budget: Option<Duration>,
+) -> Result<PipelinePlan, SchedulingError> {
+    schedule_pipelines_with(gpu_caps, params, budget, &DefaultScore)
+}
+
+/// `schedule_pipelines` picking k̂ with `score` instead of Z(k).
+pub fn schedule_pipelines_with(
+    gpu_caps: &[Gpu],
+    params: &SchedulingParams,
+    budget: Option<Duration>,
+    score: &dyn ScoreFn,
+) -> Result<PipelinePlan, SchedulingError> {
+    // said once here, the DP and the greedy fallback both leave them out
+    let incapable: BTreeSet<&str> = gpu_caps
+        .iter()
+        .filter(|g| !capable(g, params))
+        .map(|g| g.node_id.as_str())
+        .collect();
+    if !incapable.is_empty() {
+        let required: BTreeSet<_> = params.required_capabilities.iter().collect();
+        warn!("skipping nodes {incapable:?}, they lack some of {required:?}");
+    }
+
+    let deadline = budget.map(|b| Instant::now() + b);
+    match phase1(gpu_caps, params, score, deadline) {
+        Err(e @ (SchedulingError::TimedOut | SchedulingError::StateLimit { .. })) => {
+            warn!("{e}, using greedy plan");
+            let mut plan = schedule_greedy(gpu_caps, params)?;
+            plan.quality = PlanQuality::Degraded;
+            Ok(plan)
+        }
+        res => res,
+    }
+}
+
+pub fn phase1_naive(
+    gpu_caps: &[Gpu],
+    params: &SchedulingParams,
+) -> Result<PipelinePlan, SchedulingError> {
+    phase1(gpu_caps, params, &DefaultScore, None)
+}
+
+/// Rejects inputs no plan can come out of, so the DP itself can assume a
+/// non-empty cluster that holds at least one model copy.
+fn validate(gpu_caps: &[Gpu], params: &SchedulingParams) -> Result<(), SchedulingError> {
+    if gpu_caps.is_empty() {
+        return Err(SchedulingError::NoGpus);
+    }
+    if params.model_layer == 0 {
+        return Err(SchedulingError::InvalidModel);
+    }
+    for (name, v) in [
+        ("alpha", params.alpha),
+        ("r_rtt", params.r_rtt),
+        ("t_comp", params.t_comp),
+    ] {
+        if v.is_nan() {
+            return Err(SchedulingError::NaN(name));
+        }
+    }
+
+    validate_weights(params)?;
+
+    let available = total_layer_cap(gpu_caps.iter().map(|g| g.layer_cap));
+    if available < params.model_layer {
+        return Err(SchedulingError::InsufficientCapacity {
+            available,
+            required: params.model_layer,
+        });
+    }
+    Ok(())
+}
+
+/// Sum of the layer caps, saturating. Caps are gossiped by peers, a few
+/// absurd ones must not wrap the total around to something small.
+fn total_layer_cap(caps: impl IntoIterator<Item = usize>) -> usize {
+    caps.into_iter().fold(0, |sum, cap| sum.saturating_add(cap))
+}
+
+fn validate_weights(params: &SchedulingParams) -> Result<(), SchedulingError> {
+    let weights = &params.layer_compute_weights;
+    if weights.is_empty() {
+        return Ok(());
+    }
+    if weights.len() != params.model_layer {
+        return Err(SchedulingError::LayerWeightsMismatch {
+            expected: params.model_layer,
+            got: weights.len(),
+        });
+    }
+    if weights.iter().any(|w| w.is_nan()) {
+        return Err(SchedulingError::NaN("layer_compute_weights"));
+    }
+    Ok(())
+}
+
+fn phase1(
+    gpu_caps: &[Gpu],
+    params: &SchedulingParams,
+    score: &dyn ScoreFn,
+    deadline: Option<Instant>,
+) -> Result<PipelinePlan, SchedulingError> {
+    // a model no budget allows to load fails as too little capacity
+    let gpu_caps = &apply_load_budget(gpu_caps, params);
+    validate(gpu_caps, params)?;
+
+    // a gpu that holds no layer or computes nothing, usually a failed probe,
+    // would only add DP branches and inflate k_max
+    let mut order: Vec<usize> = (0..gpu_caps.len())
+        .filter(|&i| {
+            let gpu = &gpu_caps[i];
+            if gpu.layer_cap == 0 || gpu.compute_cap == 0 {
+                warn!(
+                    "skipping gpu {i} on node {:?}, it has no layer or compute capacity",
+                    gpu.node_id
+                );
+                return false;
+            }
+            capable(gpu, params)
+        })
+        .collect();
+    // non increasing order, node ids break ties so the input order doesn't
+    // matter
+    order.sort_by(|&a, &b| Gpu::cmp_for_scheduling(&gpu_caps[a], &gpu_caps[b]));
+
+    let sorted: Vec<Gpu> = order.iter().map(|&i| gpu_caps[i].clone()).collect();
+    let mut position = vec![usize::MAX; gpu_caps.len()];
+    for (pos, &i) in order.iter().enumerate() {
+        position[i] = pos;
+    }
+    let mut affinity = AffinityIndex::build(&params.affinities, &position, sorted.len())?;
+
+    let model_layer = params.model_layer;
     let n = sorted.len();
-    let total_cap: usize = sorted.iter().map(|g| g.layer_cap).sum();
-    let k_max = n.min(total_cap / model_layer);
+    let total_cap = total_layer_cap(sorted.iter().map(|g| g.layer_cap));
+    let mut k_max = n.min(total_cap / model_layer);
+    if params.one_replica_per_region {
+        k_max = k_max.min(affinity.assign_regions(&sorted)?);
+    }
 
     // k is number of pipeline replication , we need to maximize k
-    let mut best_k = 0;
-    let mut best_score = f64::MIN;
-    let mut best_trace = vec![];
+    let mut feasible = false;
+    // (Z, k, trace) of every k with a finite score
+    let mut scored = vec![];
+    let mut report = params.explain.then(SchedulingReport::default);
+    let mut note = |k, s_star, z, outcome| {
+        if let Some(report) = report.as_mut() {
+            report.candidates.push(KCandidate {
+                k,
+                s_star,
+                z,
+                outcome,
+            });
+        }
+    };
 
+    let explored = Cell::new(0);
     for k in 1..=k_max {
-        let (s_star, trace) = solve_for_k(&sorted, model_layer, k);
+        let ctx = DpCtx {
+            gpus: &sorted,
+            params,
+            affinity: &affinity,
+            k,
+            deadline,
+            timed_out: Cell::new(false),
+            explored: &explored,
+            state_limit_hit: Cell::new(false),
+        };
+        let (s_star, trace) = solve_for_k(&ctx);
+        if ctx.timed_out.get() {
+            return Err(SchedulingError::TimedOut);
+        }
+        if let Some(limit) = params.max_dp_states.filter(|_| ctx.state_limit_hit.get()) {
+            return Err(SchedulingError::StateLimit { limit });
+        }
+        if s_star >= INF {
+            // k replicas can't be built under the constraints
+            note(k, None, None, KOutcome::Infeasible);
+            continue;
+        }
+        feasible = true;
+
+        let z = score.score(k, s_star, params);
+        if !z.is_finite() {
+            // a zero denominator says nothing about which k is better
+            note(k, Some(s_star), None, KOutcome::DegenerateScore);
+            continue;
+        }
+        note(k, Some(s_star), Some(z), KOutcome::Outscored);
+        scored.push((z, k, trace));
+    }
 
-        let z = (k as f64).powf(alpha) / (t_comp + (s_star as f64 / k as f64) * r_rtt);
+    if scored.is_empty() {
+        return Err(if feasible {
+            SchedulingError::DegenerateParams
+        } else {
+            SchedulingError::NoFeasiblePlan
+        });
+    }
+    // best Z first, the smaller k on ties
+    scored.sort_by(|a, b| total_cmp_f64(b.0, a.0).then(a.1.cmp(&b.1)));
 
-        if z > best_score {
-            best_score = z;
-            best_k = k;
-            best_trace = trace;
+    // hop costs by sorted position, like everything else from here on
+    let hop_rtt: RttMatrix = params
+        .hop_rtt
+        .iter()
+        .filter(|((a, b), _)| {
+            position.get(*a).is_some_and(|&p| p < order.len())
+                && position.get(*b).is_some_and(|&p| p < order.len())
+        })
+        .map(|(&(a, b), &ms)| ((position[a], position[b]), ms))
+        .collect();
+    let order_by = StageOrder {
+        affinity: &affinity,
+        hop_rtt: &hop_rtt,
+        r_rtt: params.r_rtt,
+        client_regions: &params.client_regions,
+    };
+
+    // the first k by Z whose plan can be built and keeps every replica
+    // within the latency budget. Budget and pins are checked on the built
+    // plan, after weak and empty stages are gone, since dropping those shifts
+    // the stages behind them
+    let mut chosen = None;
+    for (_, k, trace) in scored {
+        let mut pipelines = reconstruct(&trace, &sorted, params, &order_by)?;
+        // back from sorted positions to the caller's indices
+        for a in pipelines.iter_mut().flatten() {
+            a.gpu_idx = order[a.gpu_idx];
+        }
+        let outcome = match build_plan(k, pipelines, params) {
+            Ok((plan, _)) if !pins_hold(&plan, params) => KOutcome::Infeasible,
+            Ok((plan, _)) if over_rtt_budget(&plan, params) => KOutcome::OverRttBudget,
+            Ok((plan, leftover)) => {
+                chosen = Some((k, trace, plan, leftover));
+                break;
+            }
+            Err(SchedulingError::NoFeasiblePlan) => KOutcome::Infeasible,
+            Err(e) => return Err(e),
+        };
+        if let Some(report) = report.as_mut() {
+            report.candidates[k - 1].outcome = outcome;
         }
     }
+    let Some((best_k, best_trace, mut plan, leftover)) = chosen else {
+        return Err(SchedulingError::NoFeasiblePlan);
+    };
     println!("Selected k̂ = {best_k}");
-    let pipelines = reconstruct(best_trace, &sorted);
 
-    for (i, p) in pipelines.iter().enumerate() {
-        println!("Pipeline {i}: {:?}", p);
+    // gpus the DP skipped, and the ones the plan had no stage for
+    let spare: Vec<StageAssignment> = best_trace
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| matches!(d, Decision::Skip))
+        .map(|(pos, _)| StageAssignment {
+            gpu_idx: order[pos],
+            gpu: sorted[pos].clone(),
+        })
+        .chain(leftover)
+        .collect();
+
+    plan.standby = pick_standby(&plan, &spare, params.standby_count);
+    if let Some(mut report) = report {
+        report.candidates[best_k - 1].outcome = KOutcome::Selected;
+        plan.report = Some(report);
     }
-    for pipeline in &pipelines {
-        let capacities: Vec<usize> = pipeline.iter().map(|p| p.layer_cap).collect();
+    Ok(plan)
+}
 
-        let compute: Vec<usize> = pipeline.iter().map(|p| p.compute_cap).collect();
+// summed cost of the forward hops of a pipeline
+fn pipeline_rtt(pipeline: &Pipeline, hop_rtt: &RttMatrix, default: f64) -> f64 {
+    pipeline
+        .stages
+        .windows(2)
+        .map(|pair| hop_cost(hop_rtt, pair[0].gpu_idx, pair[1].gpu_idx, default))
+        .sum()
+}
 
-        let layers = water_fill(model_layer, &capacities, &compute);
+// whether some replica's hops add up to more than `max_pipeline_rtt`
+fn over_rtt_budget(plan: &PipelinePlan, params: &SchedulingParams) -> bool {
+    params.max_pipeline_rtt.is_some_and(|budget| {
+        let budget_ms = budget.as_secs_f64() * 1000.0;
+        plan.pipelines
+            .iter()
+            .any(|p| pipeline_rtt(p, &params.hop_rtt, params.r_rtt) > budget_ms)
+    })
+}
 
-        println!("Layer allocation: {:?}", layers);
+/// Gives up to `count` spare gpus a stage each to stand in for, biggest
+/// stages first since they take longest to rebuild. A standby never covers
+/// a stage on its own node, it would go down with it.
+fn pick_standby(plan: &PipelinePlan, spare: &[StageAssignment], count: usize) -> Vec<Standby> {
+    let mut stages: Vec<(usize, usize, &Stage)> = plan
+        .pipelines
+        .iter()
+        .enumerate()
+        .flat_map(|(p, pipeline)| {
+            pipeline
+                .stages
+                .iter()
+                .enumerate()
+                .map(move |(s, stage)| (p, s, stage))
+        })
+        .filter(|(_, _, stage)| !stage.range.is_empty())
+        .collect();
+    stages.sort_by_key(|(_, _, stage)| std::cmp::Reverse(stage.loaded().len()));
+
+    let mut standby = vec![];
+    for StageAssignment { gpu_idx, gpu } in spare {
+        if standby.len() == count {
+            break;
+        }
+        let Some(i) = stages.iter().position(|(_, _, stage)| {
+            stage.loaded().len() <= gpu.layer_cap && stage.gpu.node_id != gpu.node_id
+        }) else {
+            continue;
+        };
+        let (pipeline, stage, s) = stages.remove(i);
+        standby.push(Standby {
+            gpu_idx: *gpu_idx,
+            gpu: gpu.clone(),
+            pipeline,
+            stage,
+            range: s.loaded(),
+        });
     }
+    standby
 }
 
-fn solve_for_k(gpus: &Vec<Gpu>, model_layer: usize, k: usize) -> (usize, Vec<Decision>) {
-    let mut trace = vec![];
-    let res = dfs(
-        0,
-        gpus,
-        model_layer,
-        k,
-        DpState::new(),
-        &mut vec![],
-        &mut trace,
-    );
-    (res, trace)
+/// Layers each node has committed to the models scheduled on it, so that
+/// scheduling one model only hands out what the others left over. A `Gpu`'s
+/// `layer_cap` is the node's total, across all models.
+#[derive(Debug, Clone, Default)]
+pub struct CapacityLedger {
+    // node id -> model -> layers held
+    committed: HashMap<String, HashMap<ModelId, usize>>,
 }
 
-const INF: usize = usize::MAX / 4;
-fn dfs(
-    i: usize,
-    gpus: &Vec<Gpu>,
-    model_layer: usize,
+impl CapacityLedger {
+    /// Layers per node a plan takes, standbys included since they preload.
+    pub fn usage(plan: &PipelinePlan) -> HashMap<String, usize> {
+        let active = plan
+            .pipelines
+            .iter()
+            .flat_map(|p| &p.stages)
+            .map(|s| (&s.gpu, s.loaded()));
+        let standby = plan.standby.iter().map(|s| (&s.gpu, s.range));
+
+        let mut usage = HashMap::new();
+        for (gpu, range) in active.chain(standby) {
+            *usage.entry(gpu.node_id.clone()).or_default() += range.len();
+        }
+        usage
+    }
+
+    /// Replaces what `model_id` had committed with `usage`.
+    pub fn commit(&mut self, model_id: &str, usage: HashMap<String, usize>) {
+        for models in self.committed.values_mut() {
+            models.remove(model_id);
+        }
+        for (node_id, layers) in usage {
+            self.committed
+                .entry(node_id)
+                .or_default()
+                .insert(model_id.to_string(), layers);
+        }
+        self.committed.retain(|_, models| !models.is_empty());
+    }
+
+    /// Layers the node has given to models other than `model_id`.
+    pub fn committed_elsewhere(&self, node_id: &str, model_id: &str) -> usize {
+        self.committed.get(node_id).map_or(0, |models| {
+            models
+                .iter()
+                .filter(|(m, _)| *m != model_id)
+                .map(|(_, layers)| layers)
+                .sum()
+        })
+    }
+
+    /// `gpus` with the capacity other models hold taken off, gpus left with
+    /// nothing dropped. Rescheduling a model frees what it held itself.
+    pub fn available(&self, gpus: &[Gpu], model_id: &str) -> Vec<Gpu> {
+        gpus.iter()
+            .filter_map(|gpu| {
+                let taken = self.committed_elsewhere(&gpu.node_id, model_id);
+                let layer_cap = gpu.layer_cap.checked_sub(taken).filter(|&c| c > 0)?;
+                Some(Gpu {
+                    layer_cap,
+                    ..gpu.clone()
+                })
+            })
+            .collect()
+    }
+}
+
+/// How phase 1 ranks replica counts, the highest score becomes k̂.
+/// `s_star` is s*(k), the fewest stages k replicas fit in. A non-finite
+/// score rules k out.
+pub trait ScoreFn: Send + Sync {
+    fn score(&self, k: usize, s_star: usize, params: &SchedulingParams) -> f64;
+}
+
+/// Z(k) = k^α / (T_comp + (s*(k)/k) r_RTT), trading replicas for
+/// per-token latency as `alpha` says.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultScore;
+
+impl ScoreFn for DefaultScore {
+    fn score(&self, k: usize, s_star: usize, params: &SchedulingParams) -> f64 {
+        let (compute, rtt) = token_latency(params, s_star as f64 / k as f64);
+        (k as f64).powf(params.alpha) / (compute + rtt)
+    }
+}
+
+/// Compute and hop time of one token through a replica with
+/// `stages_per_replica` stages, the Z(k) denominator split in two.
+fn token_latency(params: &SchedulingParams, stages_per_replica: f64) -> (f64, f64) {
+    // latencies can't be negative, a negative denominator would flip Z(k)
+    let t_comp = params.t_comp.max(0.0);
+    let r_rtt = params.r_rtt.max(0.0);
+    (t_comp, stages_per_replica * r_rtt)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LatencyEstimate {
+    // T_comp for every token
+    pub compute: f64,
+    // r_RTT for every hop of every token
+    pub rtt: f64,
+    pub total: f64,
+}
+
+/// Time an average replica of `plan` takes to generate `tokens` tokens, in
+/// the units of `t_comp` and `r_rtt`, using the same model as Z(k).
+pub fn estimate_latency(
+    plan: &PipelinePlan,
+    params: &SchedulingParams,
+    tokens: usize,
+) -> LatencyEstimate {
+    let stages: usize = plan.pipelines.iter().map(|p| p.stages.len()).sum();
+    let per_replica = match plan.pipelines.len() {
+        0 => 0.0,
+        k => stages as f64 / k as f64,
+    };
+
+    let (compute, rtt) = token_latency(params, per_replica);
+    let compute = compute * tokens as f64;
+    let rtt = rtt * tokens as f64;
+    LatencyEstimate {
+        compute,
+        rtt,
+        total: compute + rtt,
+    }
+}
+
+/// Longest-processing-time style fallback: gpus are taken in non-increasing
+/// capacity order and each goes to the replica with the most layers still
+/// missing. Starts from k_max replicas and drops one until every replica
+/// covers all `model_layer` layers.
+///
+/// Feasible but not optimal, it is meant for when the DP is too slow.
+pub fn schedule_greedy(
+    gpu_caps: &[Gpu],
+    params: &SchedulingParams,
+) -> Result<PipelinePlan, SchedulingError> {
+    if gpu_caps.is_empty() {
+        return Err(SchedulingError::NoGpus);
+    }
+    if params.model_layer == 0 {
+        return Err(SchedulingError::InvalidModel);
+    }
+    validate_weights(params)?;
+
+    let model_layer = params.model_layer;
+    let gpu_caps = apply_load_budget(gpu_caps, params);
+    let mut sorted: Vec<StageAssignment> = gpu_caps
+        .into_iter()
+        .enumerate()
+        .filter(|(_, gpu)| capable(gpu, params))
+        .map(|(gpu_idx, gpu)| StageAssignment { gpu_idx, gpu })
+        .collect();
+    sorted.sort_by(|a, b| Gpu::cmp_for_scheduling(&a.gpu, &b.gpu));
+
+    let total_cap = total_layer_cap(sorted.iter().map(|a| a.gpu.layer_cap));
+    if total_cap < model_layer {
+        return Err(SchedulingError::InsufficientCapacity {
+            available: total_cap,
+            required: model_layer,
+        });
+    }
+    let k_max = sorted.len().min(total_cap / model_layer);
+
+    for k in (1..=k_max).rev() {
+        let mut pipelines: Vec<Vec<StageAssignment>> = vec![vec![]; k];
+        let mut residual = vec![model_layer; k];
+
+        for a in &sorted {
+            // the replica furthest from complete
+            let Some((idx, &r)) = residual
+                .iter()
+                .enumerate()
+                .max_by_key(|&(idx, r)| (*r, std::cmp::Reverse(idx)))
+            else {
+                break;
+            };
+            if r == 0 {
+                break;
+            }
+            // like in the DP, stages after the first keep room for overlap
+            let cap = if pipelines[idx].is_empty() {
+                a.gpu.layer_cap
+            } else {
+                extend_cap(a.gpu.layer_cap, params.layer_overlap)
+            };
+            pipelines[idx].push(a.clone());
+            residual[idx] = r.saturating_sub(cap);
+        }
+
+        if residual.iter().all(|&r| r == 0) {
+            return build_plan(k, pipelines, params).map(|(plan, _)| plan);
+        }
+    }
+
+    Err(SchedulingError::NoFeasiblePlan)
+}
+
+/// Lays the layers out over the reconstructed pipelines. Also hands back
+/// the gpus that ended up without a stage, weak ones dropped and ones left
+/// without layers, so they can still serve as standbys.
+fn build_plan(
     k: usize,
-    state: DpState,
-    path: &mut Vec<Decision>,
-    best_path: &mut Vec<Decision>,
-) -> usize {
-    if i == gpus.len() {
-        if state.f == k {
-            *best_path = path.clone();
-            return 0;
+    pipelines: Vec<Vec<StageAssignment>>,
+    params: &SchedulingParams,
+) -> Result<(PipelinePlan, Vec<StageAssignment>), SchedulingError> {
+    let mut plan = PipelinePlan {
+        model_id: params.model_id.clone(),
+        k,
+        quality: PlanQuality::Optimal,
+        pipelines: Vec::with_capacity(pipelines.len()),
+        standby: vec![],
+        report: None,
+    };
+
+    let mut leftover = vec![];
+    for pipeline in pipelines {
+        let (pipeline, dropped) = drop_weak_stages(pipeline, params);
+        leftover.extend(dropped);
+        // every stage but the first keeps room for its overlap
+        let capacities: Vec<usize> = pipeline
+            .iter()
+            .enumerate()
+            .map(|(i, a)| match i {
+                0 => a.gpu.layer_cap,
+                _ => a.gpu.layer_cap.saturating_sub(params.layer_overlap),
+            })
+            .collect();
+
+        let compute: Vec<usize> = pipeline.iter().map(|a| a.gpu.compute_cap).collect();
+
+        let layers = if params.layer_compute_weights.is_empty() {
+            let mut layers = water_fill(params.model_layer, &capacities, &compute)?;
+            // with weak stages being dropped, the ones kept are all worth a
+            // layer
+            if params.min_compute_cap > 0 {
+                give_every_stage_a_layer(&mut layers, &capacities);
+            }
+            layers
+        } else {
+            weighted_fill(&params.layer_compute_weights, &capacities, &compute)?
+        };
+
+        println!("Layer allocation: {:?}", layers);
+
+        // write cursor, each stage starts where the previous one ended
+        let mut cursor = 0;
+        let mut stages = Vec::with_capacity(pipeline.len());
+        for (a, n) in pipeline.into_iter().zip(layers) {
+            // a stage left without layers would only add a hop
+            if n == 0 {
+                leftover.push(a);
+                continue;
+            }
+            let range = LayerRange {
+                start: cursor,
+                end: cursor + n,
+            };
+            // no more than the previous stage runs
+            let overlap = stages
+                .last()
+                .map_or(0, |prev: &Stage| params.layer_overlap.min(prev.range.len()));
+            cursor = range.end;
+            stages.push(Stage {
+                gpu_idx: a.gpu_idx,
+                gpu: a.gpu,
+                range,
+                overlap,
+            });
         }
-        return INF;
+        if cursor != params.model_layer {
+            // the pipeline's caps can't cover the model
+            return Err(SchedulingError::NoFeasiblePlan);
+        }
+
+        plan.pipelines.push(Pipeline { stages });
+    }
+
+    Ok((plan, leftover))
+}
+
+/// s*(k) and the decisions achieving it, with the dp1 table filled bottom
+/// up. A forward sweep collects the states reachable at each gpu, then a
+/// backward sweep computes dp1(i, r, f) from dp1(i + 1, ·) and keeps a
+/// back-pointer per state. Nothing recurses, so the stack doesn't grow
+/// with the cluster.
+fn solve_for_k(ctx: &DpCtx) -> (usize, Vec<Decision>) {
+    let n = ctx.gpus.len();
+
+    // layers[i] holds the distinct states reachable before gpu i, edges[i][j]
+    // the transitions out of layers[i][j] as (decision, index in layers[i +
+    // 1], stages added)
+    let mut layers: Vec<Vec<DpState>> = vec![vec![DpState::new()]];
+    let mut edges: Vec<Vec<Vec<(Decision, usize, usize)>>> = Vec::with_capacity(n);
+    for i in 0..n {
+        let mut next_layer = vec![];
+        let mut index: HashMap<DpState, usize> = HashMap::new();
+        let mut layer_edges = Vec::with_capacity(layers[i].len());
+
+        for state in &layers[i] {
+            if ctx.deadline.is_some_and(|d| Instant::now() >= d) {
+                ctx.timed_out.set(true);
+                return (INF, vec![]);
+            }
+            ctx.explored.set(ctx.explored.get() + 1);
+            if ctx
+                .params
+                .max_dp_states
+                .is_some_and(|limit| ctx.explored.get() > limit)
+            {
+                ctx.state_limit_hit.set(true);
+                return (INF, vec![]);
+            }
+            let out = transitions(i, ctx, state)
+                .into_iter()
+                .map(|(decision, next, cost)| {
+                    let j = *index.entry(next.clone()).or_insert_with(|| {
+                        next_layer.push(next);
+                        next_layer.len() - 1
+                    });
+                    (decision, j, cost)
+                })
+                .collect();
+            layer_edges.push(out);
+        }
+
+        edges.push(layer_edges);
+        layers.push(next_layer);
+    }
+
+    // back-pointers of every layer, filled from the last gpu to the first.
+    // dp1(n, ·) is done only once all k pipelines are complete
+    let mut table: Vec<Vec<ResultState>> = vec![vec![]; n + 1];
+    table[n] = layers[n]
+        .iter()
+        .map(|s| ResultState {
+            stages: if s.f == ctx.k { 0 } else { INF },
+            decision: None,
+        })
+        .collect();
+    for i in (0..n).rev() {
+        let below = &table[i + 1];
+        let row: Vec<ResultState> = edges[i]
+            .iter()
+            .map(|out| {
+                let mut best = ResultState {
+                    stages: INF,
+                    decision: None,
+                };
+                // strict so the first transition wins ties
+                for (decision, j, cost) in out {
+                    let v = cost + below[*j].stages;
+                    if v < best.stages {
+                        best = ResultState {
+                            stages: v,
+                            decision: Some((decision.clone(), *j)),
+                        };
+                    }
+                }
+                best
+            })
+            .collect();
+        table[i] = row;
+    }
+
+    let res = table[0][0].stages;
+    if res >= INF {
+        return (res, vec![]);
     }
 
-    let mut best = INF;
-    let ci = gpus[i].layer_cap;
+    let mut trace = Vec::with_capacity(n);
+    let mut j = 0;
+    for row in &table {
+        let Some((decision, next)) = row[j].decision.clone() else {
+            break;
+        };
+        trace.push(decision);
+        j = next;
+    }
+    (res, trace)
+}
+
+const INF: usize = usize::MAX / 4;
+
+/// The valid moves from `state` at gpu `i`, in the order the DP prefers
+/// them on ties: skip, extend each partial pipeline, start a new one. Each
+/// comes with the state it leads to and the stages it adds.
+fn transitions(i: usize, ctx: &DpCtx, state: &DpState) -> Vec<(Decision, DpState, usize)> {
+    let mut out = vec![];
+    let ci = ctx.gpus[i].layer_cap;
+    let model_layer = ctx.params.model_layer;
+    // a pipeline that still has residual layers once it hits the stage cap
+    // can never complete, so the branch creating it is dead
+    let max_stages = ctx.params.max_stages_per_replica.unwrap_or(usize::MAX);
+
+    let pinned = ctx.affinity.pinned[i];
+    let opens = ctx.affinity.opens[i];
+    let closes = ctx.affinity.closes[i];
+    let region = ctx.affinity.region[i];
+    // colocate partners that were skipped force a skip, placed ones force
+    // joining their pipeline, both at once can't be satisfied
+    let must_skip = closes & state.skipped;
+    let must_join = closes & !state.skipped;
+    if must_skip != 0 && must_join != 0 {
+        return out;
+    }
 
     // 1. skip
-    path.push(Decision::Skip);
-    let v = dfs(i + 1, gpus, model_layer, k, state.clone(), path, best_path);
-    if v < best {
-        best = v;
+    if pinned.is_none() && must_join == 0 {
+        let mut next = state.clone();
+        next.skipped |= opens;
+        out.push((Decision::Skip, next, 0));
+    }
+
+    if must_skip != 0 {
+        return out;
     }
-    path.pop();
 
     // 2. extend
+    let extend_cap = extend_cap(ci, ctx.params.layer_overlap);
     for idx in 0..state.r.len() {
+        if extend_cap == 0 {
+            break;
+        }
+        let target = state.r[idx];
+        if pinned.is_some_and(|stage| stage != target.stages)
+            || target.colocate & must_join != must_join
+            || target.region != region
+        {
+            continue;
+        }
+
         let mut next = state.clone();
-        next.r[idx] = next.r[idx].saturating_sub(ci);
+        let p = &mut next.r[idx];
+        p.r = p.r.saturating_sub(extend_cap);
+        p.stages += 1;
+        p.colocate |= opens;
 
-        if next.r[idx] == 0 {
+        if p.r == 0 {
             next.r.remove(idx);
             next.f += 1;
+        } else if p.stages >= max_stages {
+            continue;
         }
 
         next.normalize();
-
-        path.push(Decision::Extend(idx));
-        let v = 1 + dfs(i + 1, gpus, model_layer, k, next, path, best_path);
-        if v < best {
-            best = v;
-        }
-        path.pop();
+        out.push((Decision::Extend(idx), next, 1));
     }
 
     // 3. start new
-
-    if state.f + state.r.len() < k {
+    let residual = start_residual(model_layer, ci);
+    if state.f + state.r.len() < ctx.k
+        && (residual == 0 || max_stages > 1)
+        && pinned.is_none_or(|stage| stage == 0)
+        && must_join == 0
+        && state.regions & region == 0
+    {
         let mut next = state.clone();
-        let residual = model_layer.saturating_sub(ci);
+        next.regions |= region;
 
         if residual == 0 {
             next.f += 1;
         } else {
-            next.r.push(residual);
+            next.r.push(Partial {
+                r: residual,
+                stages: 1,
+                colocate: opens,
+                region,
+            });
             next.normalize();
         }
+        out.push((Decision::StartNew, next, 1));
+    }
+    out
+}
 
-        path.push(Decision::StartNew);
-        let v = 1 + dfs(i + 1, gpus, model_layer, k, next, path, best_path);
-        if v < best {
-            best = v;
+/// Removes the gpus under `min_compute_cap`, slowest first, as long as the
+/// rest still have the capacity for the whole model. Pinned gpus stay, and
+/// the removed ones are returned second.
+fn drop_weak_stages(
+    pipeline: Vec<StageAssignment>,
+    params: &SchedulingParams,
+) -> (Vec<StageAssignment>, Vec<StageAssignment>) {
+    let mut total = total_layer_cap(pipeline.iter().map(|a| a.gpu.layer_cap));
+    let mut weak: Vec<usize> = (0..pipeline.len())
+        .filter(|&i| pipeline[i].gpu.compute_cap < params.min_compute_cap)
+        .filter(|&i| !is_pinned(params, pipeline[i].gpu_idx))
+        .collect();
+    weak.sort_by_key(|&i| pipeline[i].gpu.compute_cap);
+
+    let mut dropped = vec![];
+    for i in weak {
+        let cap = pipeline[i].gpu.layer_cap;
+        if total.saturating_sub(cap) >= params.model_layer {
+            total -= cap;
+            dropped.push(i);
         }
-        path.pop();
     }
-    best
+    let (gone, kept): (Vec<_>, Vec<_>) = pipeline
+        .into_iter()
+        .enumerate()
+        .partition(|(i, _)| dropped.contains(i));
+    (
+        kept.into_iter().map(|(_, a)| a).collect(),
+        gone.into_iter().map(|(_, a)| a).collect(),
+    )
 }
 
-fn water_fill(model_layer: usize, layer_cap: &[usize], compute_cap: &[usize]) -> Vec<usize> {
-    let total_f: usize = compute_cap.iter().sum();
+fn is_pinned(params: &SchedulingParams, gpu_idx: usize) -> bool {
+    params
+        .affinities
+        .iter()
+        .any(|a| matches!(*a, Affinity::PinToStage { gpu_idx: g, .. } if g == gpu_idx))
+}
 
-    let lambda = model_layer as f64 / total_f as f64;
+// whether every pinned gpu still serves its stage once empty stages are gone
+fn pins_hold(plan: &PipelinePlan, params: &SchedulingParams) -> bool {
+    params.affinities.iter().all(|a| match *a {
+        Affinity::PinToStage { gpu_idx, stage } => plan
+            .pipelines
+            .iter()
+            .any(|p| p.stages.get(stage).is_some_and(|s| s.gpu_idx == gpu_idx)),
+        Affinity::Colocate { .. } => true,
+    })
+}
 
-    let mut frac: Vec<f64> = layer_cap
-        .iter()
-        .zip(compute_cap.iter())
-        .map(|(&c, &f)| {
-            let ideal = lambda * f as f64;
-            ideal.min(c as f64)
-        })
-        .collect();
+fn water_fill(
+    model_layer: usize,
+    layer_cap: &[usize],
+    compute_cap: &[usize],
+) -> Result<Vec<usize>, SchedulingError> {
+    let n = layer_cap.len();
+    let mut frac = vec![0.0f64; n];
+
+    // proportional share λ·f_i, but a stage whose share doesn't fit is pinned
+    // at its cap and the layers it couldn't take are spread over the rest
+    let mut free: Vec<usize> = (0..n).collect();
+    let mut left = model_layer as f64;
+    loop {
+        let total_f: f64 = free.iter().map(|&i| compute_cap[i] as f64).sum();
+        if free.is_empty() || total_f <= 0.0 || left <= 0.0 {
+            break;
+        }
+
+        let lambda = left / total_f;
+        let (capped, uncapped): (Vec<usize>, Vec<usize>) = free
+            .iter()
+            .partition(|&&i| lambda * compute_cap[i] as f64 >= layer_cap[i] as f64);
 
-    let mut alloc: Vec<usize> = frac.iter().map(|x| x.floor() as usize).collect();
+        if capped.is_empty() {
+            for i in uncapped {
+                frac[i] = lambda * compute_cap[i] as f64;
+            }
+            break;
+        }
+
+        for i in capped {
+            frac[i] = layer_cap[i] as f64;
+            left -= layer_cap[i] as f64;
+        }
+        free = uncapped;
+    }
+
+    if frac.iter().any(|x| x.is_nan()) {
+        return Err(SchedulingError::NaN("layer share"));
+    }
+
+    let alloc_floor = |(i, x): (usize, &f64)| (x.floor() as usize).min(layer_cap[i]);
+    let mut alloc: Vec<usize> = frac.iter().enumerate().map(alloc_floor).collect();
 
     let current_sum: usize = alloc.iter().sum();
     let mut remaining = model_layer.saturating_sub(current_sum);
@@ -296,7 +1631,7 @@ fn water_fill(model_layer: usize, layer_cap: &[usize], compute_cap: &[usize]) ->
         .map(|(i, &x)| (i, x - x.floor()))
         .collect();
 
-    remainders.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    remainders.sort_by(|a, b| total_cmp_f64(b.1, a.1));
 
     // Hamilton distribution
     for (idx, _) in remainders {
@@ -309,46 +1644,316 @@ fn water_fill(model_layer: usize, layer_cap: &[usize], compute_cap: &[usize]) ->
         }
     }
 
-    alloc
+    // float error or zero compute stages can leave layers over, hand them
+    // to whoever has room, fastest stage first
+    let mut by_compute: Vec<usize> = (0..n).collect();
+    by_compute.sort_by(|&a, &b| compute_cap[b].cmp(&compute_cap[a]));
+    for idx in by_compute {
+        if remaining == 0 {
+            break;
+        }
+        let room = (layer_cap[idx] - alloc[idx]).min(remaining);
+        alloc[idx] += room;
+        remaining -= room;
+    }
+
+    Ok(alloc)
+}
+
+// a stage whose share rounded down to nothing still gets a layer, taken from
+// the stage with the most, so no gpu in the pipeline idles
+fn give_every_stage_a_layer(alloc: &mut [usize], layer_cap: &[usize]) {
+    let n = alloc.len();
+    for i in 0..n {
+        if alloc[i] > 0 || layer_cap[i] == 0 {
+            continue;
+        }
+        let Some(donor) = (0..n).filter(|&j| alloc[j] > 1).max_by_key(|&j| alloc[j]) else {
+            break;
+        };
+        alloc[donor] -= 1;
+        alloc[i] = 1;
+    }
+}
+
+/// Splits the layers into contiguous blocks, one per stage in pipeline
+/// order, so that the slowest stage's weighted work over its compute is as
+/// small as possible. Stages take no more than their layer cap.
+///
+/// dp[s][l] is the best bottleneck with the first l layers on the first s
+/// stages, `None` where the caps can't fit them.
+fn weighted_fill(
+    weights: &[f64],
+    layer_cap: &[usize],
+    compute_cap: &[usize],
+) -> Result<Vec<usize>, SchedulingError> {
+    let n = layer_cap.len();
+    let model_layer = weights.len();
+
+    let mut prefix = vec![0.0f64; model_layer + 1];
+    for (l, w) in weights.iter().enumerate() {
+        prefix[l + 1] = prefix[l] + w.max(0.0);
+    }
+    // time stage s takes for layers j..l, a stage without compute only
+    // works for an empty block
+    let cost = |s: usize, j: usize, l: usize| {
+        if l == j {
+            0.0
+        } else if compute_cap[s] == 0 {
+            f64::INFINITY
+        } else {
+            (prefix[l] - prefix[j]) / compute_cap[s] as f64
+        }
+    };
+
+    let mut dp: Vec<Vec<Option<f64>>> = vec![vec![None; model_layer + 1]; n + 1];
+    let mut choice = vec![vec![0usize; model_layer + 1]; n + 1];
+    dp[0][0] = Some(0.0);
+
+    for s in 1..=n {
+        for l in 0..=model_layer {
+            for j in l.saturating_sub(layer_cap[s - 1])..=l {
+                let Some(prev) = dp[s - 1][j] else {
+                    continue;
+                };
+                let v = prev.max(cost(s - 1, j, l));
+                if dp[s][l].is_none_or(|best| total_cmp_f64(v, best).is_lt()) {
+                    dp[s][l] = Some(v);
+                    choice[s][l] = j;
+                }
+            }
+        }
+    }
+
+    match dp[n][model_layer] {
+        None => return Err(SchedulingError::NoFeasiblePlan),
+        Some(v) if v.is_nan() => return Err(SchedulingError::NaN("stage compute time")),
+        Some(_) => {}
+    }
+
+    let mut alloc = vec![0; n];
+    let mut l = model_layer;
+    for s in (1..=n).rev() {
+        let j = choice[s][l];
+        alloc[s - 1] = l - j;
+        l = j;
+    }
+
+    Ok(alloc)
+}
+
+/// What decides the order of equally capable stages in a pipeline.
+struct StageOrder<'a> {
+    affinity: &'a AffinityIndex,
+    // by sorted position
+    hop_rtt: &'a RttMatrix,
+    r_rtt: f64,
+    client_regions: &'a [String],
 }
 
-fn reconstruct(trace: Vec<Decision>, gpus: &Vec<Gpu>) -> Vec<Vec<Gpu>> {
+/// Replays the DP's decisions into pipelines of gpus, residuals taken off
+/// the same way `transitions` does so the Extend slots line up.
+fn reconstruct(
+    trace: &[Decision],
+    gpus: &[Gpu],
+    params: &SchedulingParams,
+    order_by: &StageOrder,
+) -> Result<Vec<Vec<StageAssignment>>, ReconstructError> {
+    let affinity = order_by.affinity;
     let mut pipelines: Vec<Vec<usize>> = vec![];
-    let mut active: Vec<usize> = vec![];
+    // partial pipelines tagged with their pipeline id, kept in the same
+    // order as DpState so the Extend indices in the trace line up
+    let mut partial: Vec<(Partial, usize)> = vec![];
 
     for (gpu_idx, decision) in trace.iter().enumerate() {
+        let ci = gpus[gpu_idx].layer_cap;
+        let opens = affinity.opens[gpu_idx];
         match decision {
             Decision::Skip => {}
             Decision::StartNew => {
                 pipelines.push(vec![gpu_idx]);
-                active.push(pipelines.len() - 1);
+                let residual = start_residual(params.model_layer, ci);
+                if residual > 0 {
+                    let p = Partial {
+                        r: residual,
+                        stages: 1,
+                        colocate: opens,
+                        region: affinity.region[gpu_idx],
+                    };
+                    partial.push((p, pipelines.len() - 1));
+                }
             }
-            Decision::Extend(p_idx) => {
-                if let Some(&pipe_id) = active.get(*p_idx) {
-                    pipelines[pipe_id].push(gpu_idx);
+            Decision::Extend(slot) => {
+                if let Some((p, pipe_id)) = partial.get_mut(*slot) {
+                    pipelines[*pipe_id].push(gpu_idx);
+                    p.r = p.r.saturating_sub(extend_cap(ci, params.layer_overlap));
+                    p.stages += 1;
+                    p.colocate |= opens;
+                    if p.r == 0 {
+                        partial.remove(*slot);
+                    }
                 }
             }
         }
+        partial.sort_unstable();
+    }
+
+    for pipe in &mut pipelines {
+        if order_by.hop_rtt.is_empty() {
+            prefer_region_adjacency(pipe, gpus, affinity);
+        } else {
+            prefer_low_forward_rtt(pipe, gpus, order_by);
+        }
+        choose_entry_stage(pipe, gpus, order_by);
     }
+    check_disjoint(&pipelines)?;
 
-    let mut result: Vec<Vec<Gpu>> = vec![];
+    let mut result: Vec<Vec<StageAssignment>> = vec![];
 
     for (pid, pipe) in pipelines.iter().enumerate() {
         println!("Pipeline {pid}:");
         let mut current = vec![];
         for (stage, gpu_idx) in pipe.iter().enumerate() {
-            let gpu = gpus[*gpu_idx];
+            let gpu = gpus[*gpu_idx].clone();
             println!(
                 "  Stage {stage} -> GPU {gpu_idx} (cap={}, compute={})",
                 gpu.layer_cap, gpu.compute_cap
             );
-            current.push(gpu);
+            current.push(StageAssignment {
+                gpu_idx: *gpu_idx,
+                gpu,
+            });
         }
         println!();
         result.push(current);
     }
 
-    result
+    Ok(result)
+}
+
+/// A gpu can only serve one stage of one pipeline.
+fn check_disjoint(pipelines: &[Vec<usize>]) -> Result<(), ReconstructError> {
+    let mut seen = HashSet::new();
+    for &gpu_idx in pipelines.iter().flatten() {
+        if !seen.insert(gpu_idx) {
+            return Err(ReconstructError::GpuReused { gpu_idx });
+        }
+    }
+    Ok(())
+}
+
+/// The runs of equally capable stages in `pipe` that can be reordered,
+/// those of more than one gpu without a pinned one.
+fn reorderable_runs(pipe: &[usize], gpus: &[Gpu], affinity: &AffinityIndex) -> Vec<Range<usize>> {
+    let same_caps = |a: usize, b: usize| {
+        gpus[a].layer_cap == gpus[b].layer_cap && gpus[a].compute_cap == gpus[b].compute_cap
+    };
+
+    let mut runs = vec![];
+    let mut start = 0;
+    while start < pipe.len() {
+        let mut end = start + 1;
+        while end < pipe.len() && same_caps(pipe[start], pipe[end]) {
+            end += 1;
+        }
+
+        let pinned = pipe[start..end]
+            .iter()
+            .any(|&g| affinity.pinned[g].is_some());
+        if end - start > 1 && !pinned {
+            runs.push(start..end);
+        }
+        start = end;
+    }
+    runs
+}
+
+/// Reorders runs of equally capable stages so that gpus from the same
+/// region sit next to each other, keeping hops inside a region where the
+/// pipeline allows it. Only the order within the run changes, membership
+/// and the capacity order the DP produced stay the same. Runs holding a
+/// pinned gpu are left alone.
+fn prefer_region_adjacency(pipe: &mut [usize], gpus: &[Gpu], affinity: &AffinityIndex) {
+    for Range { start, end } in reorderable_runs(pipe, gpus, affinity) {
+        let before = start.checked_sub(1).map(|s| gpus[pipe[s]].region.as_str());
+        let after = pipe.get(end).map(|&g| gpus[g].region.as_str());
+
+        // regions in first-seen order, the one matching the previous
+        // stage goes first and the one matching the next stage last
+        let mut regions: Vec<&str> = vec![];
+        for &g in &pipe[start..end] {
+            let region = gpus[g].region.as_str();
+            if !regions.contains(&region) {
+                regions.push(region);
+            }
+        }
+        regions.sort_by_key(|&r| {
+            if Some(r) == before {
+                0
+            } else if Some(r) == after {
+                2
+            } else {
+                1
+            }
+        });
+
+        let run: Vec<usize> = pipe[start..end].to_vec();
+        let grouped = regions
+            .iter()
+            .flat_map(|&r| run.iter().copied().filter(move |&g| gpus[g].region == r));
+        for (slot, g) in pipe[start..end].iter_mut().zip(grouped) {
+            *slot = g;
+        }
+    }
+}
+
+/// Moves the gpu best placed to receive prompts to stage 0: one in a client
+/// region first, then the one with the most bandwidth. The stages it skips
+/// over shift back by one, keeping their order. Nothing moves past a pinned
+/// gpu, and the current entry stays on ties.
+fn choose_entry_stage(pipe: &mut [usize], gpus: &[Gpu], order_by: &StageOrder) {
+    let key = |g: usize| {
+        let near = order_by.client_regions.contains(&gpus[g].region);
+        (near, gpus[g].network_bandwidth)
+    };
+
+    let movable = pipe
+        .iter()
+        .take_while(|&&g| order_by.affinity.pinned[g].is_none())
+        .count();
+    // max_by_key keeps the last of equal keys, walking backwards that is
+    // the earliest stage
+    let best = (0..movable)
+        .rev()
+        .max_by_key(|&i| key(pipe[i]))
+        .unwrap_or(0);
+    pipe[..=best].rotate_right(1);
+}
+
+/// Like `prefer_region_adjacency` but with measured hop costs: each run is
+/// walked greedily, every next stage being the one cheapest to send to
+/// from the stage before it. Only the forward direction counts, that is
+/// the way activations flow.
+fn prefer_low_forward_rtt(pipe: &mut [usize], gpus: &[Gpu], order_by: &StageOrder) {
+    let cost = |a, b| hop_cost(order_by.hop_rtt, a, b, order_by.r_rtt);
+
+    for Range { start, end } in reorderable_runs(pipe, gpus, order_by.affinity) {
+        let mut left: Vec<usize> = pipe[start..end].to_vec();
+        let mut prev = start.checked_sub(1).map(|s| pipe[s]);
+
+        for slot in start..end {
+            let pick = match prev {
+                Some(p) => (0..left.len())
+                    .min_by(|&x, &y| total_cmp_f64(cost(p, left[x]), cost(p, left[y])))
+                    .unwrap_or(0),
+                // nothing to send from, the run keeps its first gpu
+                None => 0,
+            };
+            let g = left.remove(pick);
+            pipe[slot] = g;
+            prev = Some(g);
+        }
+    }
 }
 
 pub fn main() {
@@ -356,47 +1961,90 @@ pub fn main() {
         Gpu {
             layer_cap: 6,
             compute_cap: 1,
+            ..Default::default()
         },
         Gpu {
             layer_cap: 6,
             compute_cap: 2,
+            ..Default::default()
         },
         Gpu {
             layer_cap: 6,
             compute_cap: 3,
+            ..Default::default()
         },
         Gpu {
             layer_cap: 6,
             compute_cap: 2,
+            ..Default::default()
         },
         Gpu {
             layer_cap: 6,
             compute_cap: 1,
+            ..Default::default()
         },
     ];
 
-    let model_layer = 10;
+    let params = SchedulingParams {
+        model_id: "demo".into(),
+        model_layer: 10,
+        alpha: 1.0,
+        r_rtt: 1.0,
+        t_comp: 10.0,
+        max_stages_per_replica: None,
+        affinities: vec![],
+        layer_compute_weights: vec![],
+        standby_count: 0,
+        explain: false,
+        min_compute_cap: 0,
+        hop_rtt: RttMatrix::new(),
+        client_regions: vec![],
+        load_budget: None,
+        one_replica_per_region: false,
+        max_pipeline_rtt: None,
+        max_dp_states: None,
+        layer_overlap: 0,
+        required_capabilities: HashSet::new(),
+    };
 
-    let alpha = 1.0;
-    let t_comp = 10.0;
-    let r_rtt = 1.0;
-
-    phase1_naive(&gpus, model_layer, alpha, r_rtt, t_comp);
+    match schedule_pipelines(&gpus, &params, Some(Duration::from_secs(1))) {
+        Ok(plan) => {
+            for (i, p) in plan.pipelines.iter().enumerate() {
+                println!("Pipeline {i}: {:?}", p);
+            }
+        }
+        Err(e) => println!("scheduling failed: {e}"),
+    }
 }
 
-fn phase2_naive(cluster: &HashMap<NodeId, NodePerf>, model_layers: usize) -> Phase2Result {
+fn phase2_naive(
+    cluster: &HashMap<NodeId, NodePerf>,
+    model_id: &str,
+    model_layers: usize,
+) -> Option<Phase2Result> {
+    if model_layers == 0 {
+        return None;
+    }
+    // walked in node id order, so ties go the same way on every run
+    let mut nodes: Vec<(&NodeId, &NodePerf)> = cluster.iter().collect();
+    nodes.sort_by_key(|&(node_id, _)| node_id);
+
     let mut dp: Vec<HashMap<NodeId, f32>> = vec![HashMap::new(); model_layers + 1];
-    for (node_id, perf) in cluster {
-        if let Some(&lat) = perf.layer_latency.get(&1) {
+    for &(node_id, perf) in &nodes {
+        let latency = perf.layer_latency.get(model_id);
+        if let Some(&lat) = latency.and_then(|l| l.get(&1)) {
             dp[1].insert(node_id.clone(), lat);
         }
     }
     let mut parent: Vec<HashMap<NodeId, NodeId>> = vec![HashMap::new(); model_layers + 1];
 
     for l in 1..model_layers {
-        for (g_i, &cost) in dp[l].clone().iter() {
-            for (g_j, perf_j) in cluster {
-                if let Some(tau) = perf_j.layer_latency.get(&((l + 1) as u32)) {
+        let mut reached: Vec<(NodeId, f32)> = dp[l].clone().into_iter().collect();
+        reached.sort_by(|a, b| a.0.cmp(&b.0));
+        for (g_i, cost) in &reached {
+            for &(g_j, perf_j) in &nodes {
+                let latency = perf_j.layer_latency.get(model_id);
+                if let Some(tau) = latency.and_then(|lat| lat.get(&((l + 1) as u32))) {
                     let rho = cluster[g_i].rtt.get(g_j).copied().unwrap_or(f32::INFINITY);
                     let new_cost = tau + rho + cost;
                     let entry = dp[l + 1].entry(g_j.clone()).or_insert(f32::INFINITY);
@@ -411,8 +2059,7 @@ fn phase2_naive(cluster: &HashMap<NodeId, NodePerf>, model_layers: usize) -> Pha
 
     let (best_gpu, &best_cost) = dp[model_layers]
         .iter()
-        .min_by(|a, b| a.1.partial_cmp(b.1).unwrap())
-        .unwrap();
+        .min_by(|a, b| total_cmp_f64(*a.1 as f64, *b.1 as f64).then_with(|| a.0.cmp(b.0)))?;
 
     let mut path = vec![best_gpu.clone()];
     let mut current = best_gpu.clone();
@@ -426,13 +2073,380 @@ fn phase2_naive(cluster: &HashMap<NodeId, NodePerf>, model_layers: usize) -> Pha
 
     path.reverse();
 
-    Phase2Result {
+    Some(Phase2Result {
         total_latency: best_cost,
         path,
-    }
+    })
 }
 
 struct Phase2Result {
     total_latency: f32,
     path: Vec<NodeId>,
 }
+
+/// Per layer cost in ms of each stage of `pipeline`, `[stage][layer]`, from
+/// the latencies its node measured for `model_id`. Layers it hasn't
+/// measured are estimated from its `compute_cap`.
+pub fn stage_layer_ms(
+    pipeline: &Pipeline,
+    perfs: &HashMap<String, NodePerf>,
+    model_id: &str,
+    model_layer: usize,
+) -> Vec<Vec<f64>> {
+    pipeline
+        .stages
+        .iter()
+        .map(|stage| {
+            let measured = perfs
+                .get(&stage.gpu.node_id)
+                .and_then(|p| p.layer_latency.get(model_id));
+            let estimate = calibration::REFERENCE_LAYER_LATENCY.as_secs_f64()
+                * 1000.0
+                * calibration::REFERENCE_COMPUTE_CAP as f64
+                / stage.gpu.compute_cap.max(1) as f64;
+            (0..model_layer)
+                .map(|l| {
+                    measured
+                        .and_then(|m| m.get(&(l as LayerId)))
+                        .map_or(estimate, |&ms| ms as f64)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// pipelines with more stages keep every stage in `refine_boundaries`, the
+// subsets it tries double with each one
+const MAX_REFINE_STAGES: usize = 12;
+
+/// Phase-2 refinement: moves the layer boundaries of `pipeline`, keeping its
+/// stages and their order, to minimize
+///
+///     balance_weight · bottleneck + (1 − balance_weight) · transfer
+///
+/// where bottleneck is the slowest stage's compute time (from `layer_ms`,
+/// see `stage_layer_ms`) and transfer the summed cost of the hops between
+/// stages (from `hop_rtt` by gpu index, `default_rtt` when unmeasured). At
+/// 1.0 stages are balanced as well as their caps allow, at 0.0 the fewest,
+/// cheapest hops win and stages may be left out, taking no layers.
+///
+/// Returns the pipeline without its unused stages and without any
+/// `Stage::overlap`, `None` if its caps can't cover the model.
+pub fn refine_boundaries(
+    pipeline: &Pipeline,
+    layer_ms: &[Vec<f64>],
+    hop_rtt: &RttMatrix,
+    default_rtt: f64,
+    balance_weight: f64,
+) -> Option<Pipeline> {
+    let stages = &pipeline.stages;
+    let model_layer = stages.last()?.range.end;
+    let w = balance_weight.clamp(0.0, 1.0);
+
+    // prefix[s][l] is the cost of layers 0..l on stage s
+    let prefix: Vec<Vec<f64>> = layer_ms
+        .iter()
+        .map(|costs| {
+            let mut p = vec![0.0];
+            for l in 0..model_layer {
+                p.push(p[l] + costs.get(l).copied().unwrap_or(0.0));
+            }
+            p
+        })
+        .collect();
+    if prefix.len() != stages.len() {
+        return None;
+    }
+
+    // the stage subsets to try, every stage first so ties keep the
+    // pipeline whole
+    let n = stages.len();
+    let subsets: Vec<Vec<usize>> = if n > MAX_REFINE_STAGES {
+        vec![(0..n).collect()]
+    } else {
+        (1..1u32 << n)
+            .rev()
+            .map(|mask| (0..n).filter(|&s| mask & (1 << s) != 0).collect())
+            .collect()
+    };
+
+    let mut best: Option<(f64, Vec<(usize, Range<usize>)>)> = None;
+    for used in subsets {
+        let transfer: f64 = used
+            .windows(2)
+            .map(|pair| {
+                hop_cost(
+                    hop_rtt,
+                    stages[pair[0]].gpu_idx,
+                    stages[pair[1]].gpu_idx,
+                    default_rtt,
+                )
+            })
+            .sum();
+        // no split of the layers can beat the best score on transfer alone
+        if best
+            .as_ref()
+            .is_some_and(|(score, _)| (1.0 - w) * transfer >= *score)
+        {
+            continue;
+        }
+        let Some((bottleneck, split)) = min_bottleneck(&used, stages, &prefix, model_layer) else {
+            continue;
+        };
+        let score = w * bottleneck + (1.0 - w) * transfer;
+        if best.as_ref().is_none_or(|(b, _)| score < *b) {
+            best = Some((score, split));
+        }
+    }
+
+    let (_, split) = best?;
+    Some(Pipeline {
+        stages: split
+            .into_iter()
+            .map(|(s, range)| Stage {
+                gpu_idx: stages[s].gpu_idx,
+                gpu: stages[s].gpu.clone(),
+                range: LayerRange {
+                    start: range.start,
+                    end: range.end,
+                },
+                overlap: 0,
+            })
+            .collect(),
+    })
+}
+
+// splits 0..model_layer into one nonempty block per `used` stage, in order
+// and within caps, with the slowest block as fast as possible. Linear
+// partition DP: best[j][l] is the lowest bottleneck of layers 0..l over the
+// first j used stages
+fn min_bottleneck(
+    used: &[usize],
+    stages: &[Stage],
+    prefix: &[Vec<f64>],
+    model_layer: usize,
+) -> Option<(f64, Vec<(usize, Range<usize>)>)> {
+    let m = used.len();
+    let mut best = vec![vec![f64::INFINITY; model_layer + 1]; m + 1];
+    let mut from = vec![vec![0; model_layer + 1]; m + 1];
+    best[0][0] = 0.0;
+
+    for j in 1..=m {
+        let s = used[j - 1];
+        let cap = stages[s].gpu.layer_cap;
+        for l in 1..=model_layer {
+            for k in l.saturating_sub(cap)..l {
+                if !best[j - 1][k].is_finite() {
+                    continue;
+                }
+                let cost = best[j - 1][k].max(prefix[s][l] - prefix[s][k]);
+                if cost < best[j][l] {
+                    best[j][l] = cost;
+                    from[j][l] = k;
+                }
+            }
+        }
+    }
+
+    let bottleneck = best[m][model_layer];
+    if !bottleneck.is_finite() {
+        return None;
+    }
+    let mut split = Vec::with_capacity(m);
+    let mut end = model_layer;
+    for j in (1..=m).rev() {
+        let start = from[j][end];
+        split.push((used[j - 1], start..end));
+        end = start;
+    }
+    split.reverse();
+    Some((bottleneck, split))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(model_layer: usize) -> SchedulingParams {
+        SchedulingParams {
+            model_id: "test".into(),
+            model_layer,
+            alpha: 1.0,
+            r_rtt: 1.0,
+            t_comp: 10.0,
+            max_stages_per_replica: None,
+            affinities: vec![],
+            layer_compute_weights: vec![],
+            standby_count: 0,
+            explain: false,
+            min_compute_cap: 0,
+            hop_rtt: Default::default(),
+            client_regions: vec![],
+            load_budget: None,
+            one_replica_per_region: false,
+            max_pipeline_rtt: None,
+            max_dp_states: None,
+            layer_overlap: 0,
+            required_capabilities: Default::default(),
+        }
+    }
+
+    fn gpus(layer_caps: &[usize]) -> Vec<Gpu> {
+        layer_caps
+            .iter()
+            .enumerate()
+            .map(|(i, &layer_cap)| Gpu {
+                node_id: format!("node-{i}"),
+                layer_cap,
+                compute_cap: 1,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    // runs `f` on the DP context for k replicas, walking `gpus` in the
+    // order given
+    fn with_ctx<R>(
+        gpus: &[Gpu],
+        params: &SchedulingParams,
+        k: usize,
+        f: impl FnOnce(&DpCtx) -> R,
+    ) -> R {
+        let position: Vec<usize> = (0..gpus.len()).collect();
+        let affinity = AffinityIndex::build(&params.affinities, &position, gpus.len()).unwrap();
+        let explored = Cell::new(0);
+        f(&DpCtx {
+            gpus,
+            params,
+            affinity: &affinity,
+            k,
+            deadline: None,
+            timed_out: Cell::new(false),
+            explored: &explored,
+            state_limit_hit: Cell::new(false),
+        })
+    }
+
+    // s*(k) and the decisions achieving it
+    fn solve(gpus: &[Gpu], params: &SchedulingParams, k: usize) -> (usize, Vec<Decision>) {
+        with_ctx(gpus, params, k, solve_for_k)
+    }
+
+    // the pipelines `trace` turns into, each stage as its gpu and layers
+    fn rebuild(
+        gpus: &[Gpu],
+        params: &SchedulingParams,
+        trace: &[Decision],
+    ) -> Vec<Vec<(usize, Range<usize>)>> {
+        let position: Vec<usize> = (0..gpus.len()).collect();
+        let affinity = AffinityIndex::build(&params.affinities, &position, gpus.len()).unwrap();
+        let hop_rtt = RttMatrix::new();
+        let order_by = StageOrder {
+            affinity: &affinity,
+            hop_rtt: &hop_rtt,
+            r_rtt: params.r_rtt,
+            client_regions: &params.client_regions,
+        };
+        let pipelines = reconstruct(trace, gpus, params, &order_by).unwrap();
+        let (plan, _) = build_plan(pipelines.len(), pipelines, params).unwrap();
+        plan.pipelines
+            .iter()
+            .map(|p| {
+                p.stages
+                    .iter()
+                    .map(|s| (s.gpu_idx, s.range.start..s.range.end))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn start_new_with_no_residual_completes_a_pipeline() {
+        let params = params(8);
+        let gpus = gpus(&[8]);
+
+        let start = with_ctx(&gpus, &params, 1, |ctx| {
+            transitions(0, ctx, &DpState::new())
+                .into_iter()
+                .find(|(d, _, _)| *d == Decision::StartNew)
+                .unwrap()
+        });
+        assert_eq!(start.1.f, 1);
+        assert!(start.1.r.is_empty());
+        assert_eq!(start.2, 1);
+
+        assert_eq!(solve(&gpus, &params, 1), (1, vec![Decision::StartNew]));
+        assert_eq!(
+            rebuild(&gpus, &params, &[Decision::StartNew]),
+            vec![vec![(0, 0..8)]]
+        );
+    }
+
+    #[test]
+    fn extend_completes_a_pipeline() {
+        let params = params(8);
+        let gpus = gpus(&[5, 3]);
+        let (stages, trace) = solve(&gpus, &params, 1);
+        assert_eq!(stages, 2);
+        assert_eq!(trace, vec![Decision::StartNew, Decision::Extend(0)]);
+        assert_eq!(
+            rebuild(&gpus, &params, &trace),
+            vec![vec![(0, 0..5), (1, 5..8)]]
+        );
+    }
+
+    #[test]
+    fn replicas_take_the_fewest_stages() {
+        // the full gpu serves alone, the other two share a replica
+        let params = params(8);
+        let gpus = gpus(&[8, 5, 3]);
+        let (stages, trace) = solve(&gpus, &params, 2);
+        assert_eq!(stages, 3);
+        assert_eq!(
+            trace,
+            vec![Decision::StartNew, Decision::StartNew, Decision::Extend(0)]
+        );
+        assert_eq!(
+            rebuild(&gpus, &params, &trace),
+            vec![vec![(0, 0..8)], vec![(1, 0..5), (2, 5..8)]]
+        );
+
+        // one replica leaves the pair out
+        let (stages, trace) = solve(&gpus, &params, 1);
+        assert_eq!(stages, 1);
+        assert_eq!(
+            trace,
+            vec![Decision::StartNew, Decision::Skip, Decision::Skip]
+        );
+        assert_eq!(rebuild(&gpus, &params, &trace), vec![vec![(0, 0..8)]]);
+    }
+
+    #[test]
+    fn k_past_the_capacity_is_infeasible() {
+        let params = params(8);
+        let gpus = gpus(&[5, 3]);
+        let (stages, trace) = solve(&gpus, &params, 2);
+        assert!(stages >= INF);
+        assert!(trace.is_empty());
+    }
+
+    #[test]
+    fn overlap_is_taken_off_the_same_way_when_rebuilding() {
+        // each stage after the first runs a layer less than it holds
+        let params = SchedulingParams {
+            layer_overlap: 1,
+            ..params(10)
+        };
+        let gpus = gpus(&[5, 5, 5]);
+        let (stages, trace) = solve(&gpus, &params, 1);
+        assert_eq!(stages, 3);
+        assert_eq!(
+            trace,
+            vec![Decision::StartNew, Decision::Extend(0), Decision::Extend(0)]
+        );
+        assert_eq!(
+            rebuild(&gpus, &params, &trace),
+            vec![vec![(0, 0..4), (1, 4..7), (2, 7..10)]]
+        );
+    }
+}
@@ -1,29 +1,116 @@
 //! This example demonstrates an HTTP server that serves files from a directory.
 //!
 //! Checkout the `README.md` for guidance.
-use anyhow::Result;
-use quinn::{ClientConfig, Endpoint, RecvStream, SendStream, ServerConfig};
+use anyhow::{Result, anyhow, bail};
+use quinn::{Endpoint, ReadToEndError, RecvStream, SendStream, ServerConfig, VarInt};
 use rustls::{
-    ClientConfig as TlsClientConfig, RootCertStore, ServerConfig as TlsServerConfig,
+    ServerConfig as TlsServerConfig,
     pki_types::{CertificateDer, PrivateKeyDer},
 };
 use std::{
-    collections::HashMap,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, SeekFrom},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::AsyncSeekExt,
+    sync::{
+        Notify, RwLock,
+        mpsc::{self, error::TrySendError},
+    },
+    task::JoinSet,
 };
-use tokio::sync::RwLock;
 use tracing::{error, info};
 
-use crate::dht::{GossipMsg, NodePerf};
+use crate::{
+    client::{QuicTimeouts, SpkiHash, spki_hash},
+    dht::{GossipMsg, Handshake, LayerId, NodePerf, unframe},
+    drain::Drain,
+    executor::{InferenceRequest, Inflight, Job},
+    gossip::GossipHandle,
+    gpu::Node,
+    latency::LayerLatencies,
+    metrics::{GossipMetrics, SchedulerMetrics},
+    model::{ModelId, ModelMetadata},
+    reputation::Reputation,
+    router::{RouteError, RouteGuard, Router, RouterConfig},
+    scheduling::PipelinePlan,
+    transfer::{Prefetch, Transfers},
+};
 
-struct CertChain {
+pub struct CertChain {
     cert_chain: Vec<CertificateDer<'static>>,
     private_key: PrivateKeyDer<'static>,
 }
 
-fn generate_self_signed_certificates() -> Result<CertChain> {
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+impl CertChain {
+    /// The pin clients need to trust this node, see `client::PinnedVerifier`.
+    pub fn pin(&self) -> Result<SpkiHash> {
+        Ok(spki_hash(&self.cert_chain[0])?)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    // subject alternative names for the self-signed cert, DNS names or IPs.
+    // empty means localhost plus the listen IP
+    pub cert_sans: Vec<String>,
+    // longest request accepted on a stream, larger ones are reset
+    pub max_request_bytes: usize,
+    // how long a client gets to send its whole request
+    pub read_timeout: Duration,
+    pub timeouts: QuicTimeouts,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            cert_sans: Vec::new(),
+            max_request_bytes: 1024 * 1024,
+            read_timeout: Duration::from_secs(10),
+            timeouts: QuicTimeouts::default(),
+        }
+    }
+}
+
+/// Application error codes streams are closed with.
+pub const REQUEST_TIMED_OUT: VarInt = VarInt::from_u32(1);
+pub const REQUEST_TOO_LARGE: VarInt = VarInt::from_u32(2);
+
+/// localhost plus the IP the node is reachable on. For a wildcard listen
+/// address that's the IP of the interface holding the default route.
+pub fn default_sans(listen: SocketAddr) -> Vec<String> {
+    let mut sans = vec!["localhost".to_string()];
+
+    let ip = if listen.ip().is_unspecified() {
+        detect_local_ip()
+    } else {
+        Some(listen.ip())
+    };
+    if let Some(ip) = ip {
+        sans.push(ip.to_string());
+    }
+
+    sans
+}
+
+fn detect_local_ip() -> Option<IpAddr> {
+    // connecting a udp socket sends nothing, it only picks the route
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:80").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// IP entries are encoded as IP SANs, everything else as DNS names.
+fn generate_self_signed_certificates(sans: Vec<String>) -> Result<CertChain> {
+    let cert = rcgen::generate_simple_self_signed(sans)?;
 
     let cert_der = cert.cert.der().clone();
     let key_der = PrivateKeyDer::Pkcs8(cert.signing_key.serialize_der().into());
@@ -34,38 +121,232 @@ fn generate_self_signed_certificates() -> Result<CertChain> {
     })
 }
 
-fn make_client_config(server_cert: CertificateDer<'static>) -> Result<ClientConfig> {
-    let mut roots = RootCertStore::empty();
-    roots.add(server_cert)?;
-
-    let tls = TlsClientConfig::builder()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
+/// The node's TLS identity, generated once at startup so its pin can be
+/// published before the server is up.
+pub fn generate_identity(addr: &str, opts: &ServerOptions) -> Result<CertChain> {
+    let sans = if opts.cert_sans.is_empty() {
+        let mut sans = vec![];
+        for listen in listen_addrs(addr)? {
+            for san in default_sans(listen) {
+                if !sans.contains(&san) {
+                    sans.push(san);
+                }
+            }
+        }
+        sans
+    } else {
+        opts.cert_sans.clone()
+    };
+    generate_self_signed_certificates(sans)
+}
 
-    Ok(ClientConfig::new(Arc::new(
-        quinn::crypto::rustls::QuicClientConfig::try_from(tls)?,
-    )))
+/// The addresses in a comma separated listen list, e.g.
+/// `0.0.0.0:7000,[::1]:7000` to serve both IPv4 and IPv6. On Linux `[::]`
+/// alone already takes both families, it can't be combined with
+/// `0.0.0.0` on the same port.
+pub fn listen_addrs(addr: &str) -> Result<Vec<SocketAddr>> {
+    let addrs = addr
+        .split(',')
+        .map(|a| {
+            a.trim()
+                .parse()
+                .map_err(|e| anyhow!("listen address {a}: {e}"))
+        })
+        .collect::<Result<Vec<SocketAddr>>>()?;
+    if addrs.is_empty() {
+        bail!("no listen address");
+    }
+    Ok(addrs)
 }
 
 pub type ClusterMap = Arc<RwLock<HashMap<String, NodePerf>>>;
 
-pub async fn start_server(addr: &str, cluster: ClusterMap) -> Result<()> {
-    let cert = generate_self_signed_certificates()?;
+/// The plans this node has adopted, one per model.
+pub type PlanState = Arc<RwLock<HashMap<ModelId, PipelinePlan>>>;
+
+/// Everything request handlers can read or update.
+#[derive(Clone, Default)]
+pub struct ServerState {
+    pub cluster: ClusterMap,
+    pub plans: PlanState,
+    // routes requests over the replicas of each model's plan
+    pub routers: Arc<RwLock<HashMap<ModelId, Arc<Router>>>>,
+    // stage work running on this node, waited on before leaving
+    pub drain: Arc<Drain>,
+    pub metrics: Arc<GossipMetrics>,
+    pub scheduler_metrics: Arc<SchedulerMetrics>,
+    // stage work records its layer timings here
+    pub latencies: Arc<LayerLatencies>,
+    // what this node can hold, plans giving it more are refused
+    pub host: Option<Arc<HostLimits>>,
+    // notified when the scheduler should come up with a new plan
+    pub reschedule: Arc<Notify>,
+    // directory served under `/files`, usually the model weights
+    pub files_root: Option<PathBuf>,
+    // joining nodes must match it
+    pub handshake: Handshake,
+    // generations running here, clients cancel them by request id
+    pub inflight: Arc<Inflight>,
+    // where accepted `Infer` requests go, see `executor::serve`. None on a
+    // node that serves no requests
+    pub jobs: Option<mpsc::Sender<Job>>,
+    // queue depth of the routers built for adopted plans
+    pub router_config: RouterConfig,
+    // how reliable peers have been, fed by gossip and stage failures
+    pub reputation: Arc<Reputation>,
+    // layer downloads in flight, listed by `GET /transfers`
+    pub transfers: Arc<Transfers>,
+    // where the layers of the stages this node stands by for are asked
+    // for, see `transfer::Prefetcher`. None on a node that doesn't prefetch
+    pub prefetch: Option<mpsc::UnboundedSender<Prefetch>>,
+    // while paused, incoming perf records are dropped
+    pub gossip: GossipHandle,
+}
+
+/// The local node and the models it serves, for checking stage assignments.
+pub struct HostLimits {
+    pub node_id: String,
+    pub node: Node,
+    pub models: HashMap<ModelId, ModelMetadata>,
+    pub max_seq_len: usize,
+}
+
+impl ServerState {
+    /// Swaps in a new plan for its model. The model's router starts over
+    /// with the new replicas, requests in flight on the old plan finish
+    /// against the old router.
+    ///
+    /// A plan giving this node a stage it can't fit in VRAM is refused and
+    /// the scheduler is asked for another one. Returns whether the plan
+    /// was adopted. The layers of the stages the node stands by for are
+    /// sent to `prefetch`.
+    pub async fn adopt_plan(&self, plan: PipelinePlan) -> bool {
+        let meta = self
+            .host
+            .as_ref()
+            .and_then(|host| Some((host, host.models.get(&plan.model_id)?)));
+        if let Some((host, meta)) = meta {
+            let too_big = plan
+                .pipelines
+                .iter()
+                .flat_map(|p| &p.stages)
+                .filter(|s| s.gpu.node_id == host.node_id)
+                .find(|s| !host.node.can_host(&s.loaded(), meta, host.max_seq_len));
+            if let Some(stage) = too_big {
+                let loaded = stage.loaded();
+                error!(
+                    "refusing plan for {}, layers {}..{} don't fit in free VRAM",
+                    plan.model_id, loaded.start, loaded.end
+                );
+                self.reschedule.notify_one();
+                return false;
+            }
+        }
+
+        if let (Some(host), Some(prefetch)) = (&self.host, &self.prefetch) {
+            for standby in plan
+                .standby
+                .iter()
+                .filter(|s| s.gpu.node_id == host.node_id)
+            {
+                // with the prefetcher gone the standby only starts cold
+                let _ = prefetch.send(Prefetch {
+                    model_id: plan.model_id.clone(),
+                    range: standby.range,
+                });
+            }
+        }
+
+        let model_id = plan.model_id.clone();
+        let router = Arc::new(Router::from_plan_with(&plan, self.router_config.clone()));
+        self.plans.write().await.insert(model_id.clone(), plan);
+        self.routers.write().await.insert(model_id, router);
+        true
+    }
+
+    /// Moves the stages of a dead node onto their warm standbys, in every
+    /// model's plan. Asks for a reschedule if some stage had none.
+    pub async fn peer_down(&self, node_id: &str) {
+        let mut plans = self.plans.write().await;
+        for plan in plans.values_mut() {
+            let affected = plan
+                .pipelines
+                .iter()
+                .flat_map(|p| &p.stages)
+                .any(|s| s.gpu.node_id == node_id);
+
+            if !plan.promote_standby(node_id) {
+                info!(
+                    "{node_id} down with no standby for some {} stages, rescheduling",
+                    plan.model_id
+                );
+                self.reschedule.notify_one();
+            }
+            if affected {
+                let router = Arc::new(Router::from_plan_with(plan, self.router_config.clone()));
+                self.routers
+                    .write()
+                    .await
+                    .insert(plan.model_id.clone(), router);
+            }
+        }
+    }
+
+    /// Routes a request over the model's adopted plan, skipping replicas
+    /// that have a stage on a node the cluster map reports as draining.
+    pub async fn route(&self, model_id: &str) -> Result<RouteGuard, RouteError> {
+        let router = self
+            .routers
+            .read()
+            .await
+            .get(model_id)
+            .cloned()
+            .ok_or(RouteError::NoReplicas)?;
+        let draining: HashSet<String> = {
+            let map = self.cluster.read().await;
+            map.values()
+                .filter(|p| p.draining)
+                .map(|p| p.node_id.clone())
+                .collect()
+        };
+        router.set_draining(&draining);
+        router.acquire()
+    }
+}
+
+pub async fn start_server(
+    addr: &str,
+    state: ServerState,
+    cert: CertChain,
+    opts: ServerOptions,
+) -> Result<()> {
+    let listen = listen_addrs(addr)?;
 
     let tls = TlsServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(cert.cert_chain.clone(), cert.private_key)?;
 
-    let server_config = ServerConfig::with_crypto(Arc::new(
+    let mut server_config = ServerConfig::with_crypto(Arc::new(
         quinn::crypto::rustls::QuicServerConfig::try_from(tls)?,
     ));
+    server_config.transport_config(opts.timeouts.transport_config()?);
+
+    let opts = Arc::new(opts);
+    let mut endpoints = JoinSet::new();
+    for listen in listen {
+        let endpoint = Endpoint::server(server_config.clone(), listen)?;
+        info!("server listening on {listen}");
+        endpoints.spawn(accept_loop(endpoint, state.clone(), opts.clone()));
+    }
+    endpoints.join_all().await;
 
-    let endpoint = Endpoint::server(server_config, addr.parse()?)?;
-
-    info!("server listening on {addr}");
+    Ok(())
+}
 
+async fn accept_loop(endpoint: Endpoint, state: ServerState, opts: Arc<ServerOptions>) {
     while let Some(connecting) = endpoint.accept().await {
-        let cluster = cluster.clone();
+        let state = state.clone();
+        let opts = opts.clone();
 
         tokio::spawn(async move {
             let conn = match connecting.await {
@@ -76,31 +357,96 @@ pub async fn start_server(addr: &str, cluster: ClusterMap) -> Result<()> {
                 }
             };
 
+            let peer = Arc::new(PeerConn {
+                id: conn.stable_id(),
+                welcomed: AtomicBool::new(false),
+            });
             while let Ok((send, recv)) = conn.accept_bi().await {
-                let cluster = cluster.clone();
+                let state = state.clone();
+                let opts = opts.clone();
+                let peer = peer.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_stream(send, recv, cluster).await {
+                    if let Err(e) = handle_stream(send, recv, state, &opts, &peer).await {
                         error!("stream error: {e}");
                     }
                 });
             }
         });
     }
+}
 
-    Ok(())
+// what the server knows of the connection a stream came in on
+struct PeerConn {
+    id: usize,
+    // set once the peer's `Hello` was welcomed on this connection
+    welcomed: AtomicBool,
 }
 
 async fn handle_stream(
     mut send: SendStream,
     mut recv: RecvStream,
-    cluster: ClusterMap,
+    state: ServerState,
+    opts: &ServerOptions,
+    peer: &PeerConn,
 ) -> Result<()> {
-    let data = recv.read_to_end(1024 * 1024).await?;
-    let msg: GossipMsg = serde_json::from_slice(&data)?;
+    // a client trickling bytes would otherwise hold the stream forever
+    let read = tokio::time::timeout(opts.read_timeout, recv.read_to_end(opts.max_request_bytes));
+    let data = match read.await {
+        Ok(Ok(data)) => data,
+        Ok(Err(ReadToEndError::TooLong)) => {
+            let _ = recv.stop(REQUEST_TOO_LARGE);
+            let _ = send.reset(REQUEST_TOO_LARGE);
+            bail!("request over {} bytes", opts.max_request_bytes);
+        }
+        Ok(Err(e)) => return Err(e.into()),
+        Err(_) => {
+            let _ = recv.stop(REQUEST_TIMED_OUT);
+            let _ = send.reset(REQUEST_TIMED_OUT);
+            bail!("request not received within {:?}", opts.read_timeout);
+        }
+    };
+
+    // plain `GET /path\r\n` requests are the query API, anything else is gossip
+    if data.starts_with(b"GET ") {
+        match process_get(&data, &state).await {
+            Ok(GetResponse::Body(body)) => send.write_all(&body).await?,
+            Ok(GetResponse::File { mut file, len }) => {
+                send.write_all(&len.to_be_bytes()).await?;
+                let copied = tokio::io::copy(&mut file, &mut send).await?;
+                if copied != len {
+                    bail!("file changed while sending, {copied} of {len} bytes sent");
+                }
+            }
+            Err(e) => {
+                let resp = serde_json::to_vec(&serde_json::json!({ "error": e.to_string() }))?;
+                send.write_all(&resp).await?;
+            }
+        }
+        send.finish()?;
+        return Ok(());
+    }
+
+    let cluster = state.cluster.clone();
+    let merged = &state.metrics.records_merged;
+    // compressed messages come framed, plain JSON as is
+    let msg: GossipMsg = serde_json::from_slice(&unframe(&data, opts.max_request_bytes)?)?;
 
     match msg {
+        // gossip from a peer that never passed the handshake, it may run
+        // another protocol or serve other weights
+        GossipMsg::Perf(_) | GossipMsg::SyncRequest | GossipMsg::SyncResponse(_)
+            if !peer.welcomed.load(Ordering::SeqCst) =>
+        {
+            info!("dropping gossip from a peer that didn't say hello");
+        }
+
+        // gossip is paused, the records would only go stale anyway
+        GossipMsg::Perf(_) | GossipMsg::SyncResponse(_) if state.gossip.is_paused() => {}
+
         GossipMsg::Perf(perf) => {
-            merge_perf(cluster, perf).await;
+            if merge_perf(cluster.clone(), perf).await {
+                GossipMetrics::inc(merged);
+            }
         }
 
         GossipMsg::SyncRequest => {
@@ -116,74 +462,275 @@ async fn handle_stream(
 
         GossipMsg::SyncResponse(perfs) => {
             for p in perfs {
-                merge_perf(cluster.clone(), p).await;
+                if merge_perf(cluster.clone(), p).await {
+                    GossipMetrics::inc(merged);
+                }
+            }
+        }
+
+        GossipMsg::Hello(hello) => {
+            let resp = match state.handshake.check(&hello) {
+                Ok(()) => {
+                    // before replying, the peer's next stream must see it
+                    peer.welcomed.store(true, Ordering::SeqCst);
+                    GossipMsg::Welcome
+                }
+                Err(e) => {
+                    info!("refusing joiner: {e}");
+                    GossipMsg::Refused(e.to_string())
+                }
+            };
+            send.write_all(&serde_json::to_vec(&resp)?).await?;
+        }
+
+        GossipMsg::Cancel(request_id) => {
+            if state.inflight.cancel(&request_id, peer.id) {
+                info!("request {request_id} cancelled by the client");
+            }
+        }
+
+        GossipMsg::Infer(request) => {
+            if let Err(e) = infer(&mut send, &state, peer, request).await {
+                let resp = serde_json::to_vec(&serde_json::json!({ "error": e.to_string() }))?;
+                send.write_all(&resp).await?;
             }
         }
+
+        // only ever sent in reply
+        GossipMsg::Welcome | GossipMsg::Refused(_) => {}
     }
 
     send.finish()?;
     Ok(())
 }
 
-async fn merge_perf(cluster: ClusterMap, incoming: NodePerf) {
-    let mut map = cluster.write().await;
-
-    match map.get(&incoming.node_id) {
-        Some(old) if old.timestamp_ms >= incoming.timestamp_ms => {}
-        _ => {
-            map.insert(incoming.node_id.clone(), incoming);
+/// Queues `request` for `executor::serve` and streams its chunks back as
+/// JSON lines. Errors before the first chunk was written, with `TooBusy`
+/// when the router or the job queue has no room for it.
+async fn infer(
+    send: &mut SendStream,
+    state: &ServerState,
+    peer: &PeerConn,
+    request: InferenceRequest,
+) -> Result<()> {
+    let jobs = state
+        .jobs
+        .as_ref()
+        .ok_or_else(|| anyhow!("this node serves no requests"))?;
+    // held until the last chunk is out, a draining node waits for it
+    let _work = state
+        .drain
+        .start_work()
+        .ok_or_else(|| anyhow!("this node is draining"))?;
+    // counts against the replica's queue depth until the last chunk is out
+    let route = state.route(&request.model_id).await?;
+    let guard = state.inflight.start(&request.request_id, peer.id)?;
+    let (chunks, mut rx) = mpsc::unbounded_channel();
+    let job = Job {
+        request,
+        cancel: guard.token.clone(),
+        chunks,
+        replica: route.replica,
+    };
+    jobs.try_send(job).map_err(|e| match e {
+        TrySendError::Full(_) => anyhow!(RouteError::TooBusy {
+            retry_after: state.router_config.retry_after,
+        }),
+        TrySendError::Closed(_) => anyhow!("the serving loop is gone"),
+    })?;
+
+    while let Some(chunk) = rx.recv().await {
+        let mut line = serde_json::to_vec(&chunk)?;
+        line.push(b'\n');
+        if send.write_all(&line).await.is_err() {
+            // the client went away, stop generating for it
+            guard.token.cancel();
         }
     }
+    Ok(())
 }
-pub async fn send_perf(
-    addr: &str,
-    perf: NodePerf,
-    server_cert: CertificateDer<'static>,
-) -> Result<()> {
-    let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
-    let client_cfg = make_client_config(server_cert)?;
-    endpoint.set_default_client_config(client_cfg);
-
-    let conn = endpoint.connect(addr.parse()?, "localhost")?.await?;
-
-    let (mut send, _) = conn.open_bi().await?;
 
-    let msg = GossipMsg::Perf(perf);
-    let bytes = serde_json::to_vec(&msg)?;
+// longest request path we look at, every real route is far shorter
+const MAX_PATH_LEN: usize = 1024;
+
+/// Splits the path of a `GET /path\r\n` request line into its segments.
+/// Paths that could step outside the API root (`.` or `..` segments, empty
+/// segments, NULs, backslashes) are refused.
+pub fn parse_get_path(req: &[u8]) -> Result<Vec<&str>> {
+    let Some(rest) = req.strip_prefix(b"GET ") else {
+        bail!("missing GET");
+    };
+    let Some(line) = rest.strip_suffix(b"\r\n") else {
+        bail!("missing \\r\\n");
+    };
+    let end = line.iter().position(|&c| c == b' ').unwrap_or(line.len());
+    if end > MAX_PATH_LEN {
+        bail!("path longer than {MAX_PATH_LEN} bytes");
+    }
+    let path = std::str::from_utf8(&line[..end])?;
+    if path.contains(['\0', '\\']) {
+        bail!("invalid character in path");
+    }
 
-    send.write_all(&bytes).await?;
-    send.finish()?;
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    if segments
+        .iter()
+        .any(|s| s.is_empty() || *s == "." || *s == "..")
+    {
+        bail!("invalid path {path}");
+    }
+    Ok(segments)
+}
 
-    Ok(())
+#[derive(Debug)]
+pub enum FileError {
+    // paths are the client's, relative to the root, so errors sent back
+    // don't tell where the node keeps its files
+    NotFound(String),
+    // the path resolves, through a symlink, to somewhere outside the root
+    OutsideRoot(String),
+    Io(io::Error),
 }
 
-pub async fn request_sync(
-    addr: &str,
-    server_cert: CertificateDer<'static>,
-    cluster: ClusterMap,
-) -> Result<()> {
-    let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
-    let client_cfg = make_client_config(server_cert)?;
-    endpoint.set_default_client_config(client_cfg);
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::NotFound(p) => write!(f, "/{p} not found"),
+            FileError::OutsideRoot(p) => write!(f, "/{p} resolves outside the served directory"),
+            FileError::Io(e) => write!(f, "io error: {e}"),
+        }
+    }
+}
 
-    let conn = endpoint.connect(addr.parse()?, "localhost")?.await?;
+impl std::error::Error for FileError {}
 
-    let (mut send, mut recv) = conn.open_bi().await?;
+/// Resolves `segments` under `root`, following symlinks. `parse_get_path`
+/// already refuses `..`, this catches a symlink inside the root pointing
+/// out of it.
+pub fn resolve_file(root: &Path, segments: &[&str]) -> Result<PathBuf, FileError> {
+    let root = root.canonicalize().map_err(FileError::Io)?;
+    let requested: PathBuf = segments.iter().fold(root.clone(), |p, s| p.join(s));
 
-    let msg = GossipMsg::SyncRequest;
-    let bytes = serde_json::to_vec(&msg)?;
+    let resolved = match requested.canonicalize() {
+        Ok(p) => p,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(FileError::NotFound(segments.join("/")));
+        }
+        Err(e) => return Err(FileError::Io(e)),
+    };
+    if !resolved.starts_with(&root) {
+        return Err(FileError::OutsideRoot(segments.join("/")));
+    }
+    Ok(resolved)
+}
 
-    send.write_all(&bytes).await?;
-    send.finish()?;
+pub enum GetResponse {
+    Body(Vec<u8>),
+    // streamed to the client in chunks after a big-endian u64 length, weight
+    // files are too large to buffer
+    File { file: tokio::fs::File, len: u64 },
+}
 
-    let resp = recv.read_to_end(1024 * 1024).await?;
-    let msg: GossipMsg = serde_json::from_slice(&resp)?;
+/// Handles the query API:
+///
+/// - `GET /plan` every adopted `PipelinePlan` by model id
+/// - `GET /plan/{model_id}` the model's plan
+/// - `GET /plan/{model_id}/stage/{layer_id}` the stage serving that layer in
+///   every replica of the model
+/// - `GET /metrics` counters in Prometheus text format
+/// - `GET /files/{path}` a file under `ServerState::files_root`
+/// - `GET /range/{offset}/{path}` the same file from byte `offset` on, for
+///   resuming an interrupted transfer
+pub async fn process_get(req: &[u8], state: &ServerState) -> Result<GetResponse> {
+    let segments = parse_get_path(req)?;
+
+    if segments == ["metrics"] {
+        let mut out = String::new();
+        state.metrics.render(&mut out);
+        state.scheduler_metrics.render(&mut out);
+        return Ok(GetResponse::Body(out.into_bytes()));
+    }
+    if segments == ["transfers"] {
+        let transfers: Vec<_> = state
+            .transfers
+            .active()
+            .into_iter()
+            .map(|t| {
+                let percent = t.percent();
+                serde_json::json!({ "transfer": t, "percent": percent })
+            })
+            .collect();
+        return Ok(GetResponse::Body(serde_json::to_vec(&transfers)?));
+    }
+    match segments.as_slice() {
+        ["files", rest @ ..] => return open_file(state, rest, 0).await,
+        ["range", offset, rest @ ..] => return open_file(state, rest, offset.parse()?).await,
+        _ => {}
+    }
 
-    if let GossipMsg::SyncResponse(perfs) = msg {
-        for p in perfs {
-            merge_perf(cluster.clone(), p).await;
+    let plans = state.plans.read().await;
+    let plan_for = |model: &str| {
+        plans
+            .get(model)
+            .ok_or_else(|| anyhow!("no plan adopted for {model}"))
+    };
+
+    let body = match segments.as_slice() {
+        ["plan"] => serde_json::to_vec(&*plans)?,
+        ["plan", model] => serde_json::to_vec(plan_for(model)?)?,
+        ["plan", model, "stage", layer] => {
+            let plan = plan_for(model)?;
+            let layer: LayerId = layer.parse()?;
+            let holders: Vec<_> = plan
+                .stages_for_layer(layer as usize)
+                .map(|(pipeline, stage, s)| {
+                    serde_json::json!({
+                        "pipeline": pipeline,
+                        "stage": stage,
+                        "node_id": s.gpu.node_id,
+                    })
+                })
+                .collect();
+            if holders.is_empty() {
+                bail!("layer {layer} is not served by the plan");
+            }
+            serde_json::to_vec(&holders)?
         }
+        _ => bail!("unknown path /{}", segments.join("/")),
+    };
+    Ok(GetResponse::Body(body))
+}
+
+async fn open_file(state: &ServerState, segments: &[&str], offset: u64) -> Result<GetResponse> {
+    let root = state
+        .files_root
+        .as_ref()
+        .ok_or_else(|| anyhow!("this node serves no files"))?;
+    let path = resolve_file(root, segments)?;
+    let mut file = tokio::fs::File::open(path).await?;
+    let meta = file.metadata().await?;
+    if !meta.is_file() {
+        bail!("/{} is not a file", segments.join("/"));
     }
+    if offset > meta.len() {
+        bail!("offset {offset} past the end of a {} byte file", meta.len());
+    }
+    file.seek(SeekFrom::Start(offset)).await?;
+    Ok(GetResponse::File {
+        file,
+        len: meta.len() - offset,
+    })
+}
 
-    Ok(())
+/// Last writer wins by timestamp. Returns whether `incoming` was kept.
+pub(crate) async fn merge_perf(cluster: ClusterMap, incoming: NodePerf) -> bool {
+    let mut map = cluster.write().await;
+
+    match map.get(&incoming.node_id) {
+        Some(old) if old.timestamp_ms >= incoming.timestamp_ms => false,
+        _ => {
+            map.insert(incoming.node_id.clone(), incoming);
+            true
+        }
+    }
 }
@@ -0,0 +1,199 @@
+//! Deterministic simulation harness for the gossip layer.
+//!
+//! `SimClock` is a virtual clock that only moves when the harness advances
+//! it, and `SimTransport` hands perf records straight to the target node's
+//! cluster map. `Sim` steps every node's `gossip_tick` in a fixed order, so
+//! convergence and eviction can be driven without sockets or real sleeps.
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use tokio::sync::RwLock;
+
+use crate::{
+    dht::NodePerf,
+    gossip::{Clock, GossipConfig, GossipHandle, GossipNode, PeerBackoff, Transport, gossip_tick},
+    gpu::SystemInfo,
+    server::{ClusterMap, merge_perf},
+    utils::SharedRng,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct SimClock {
+    now_ms: Arc<AtomicU64>,
+}
+
+impl SimClock {
+    pub fn advance(&self, d: Duration) {
+        self.now_ms.fetch_add(d.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for SimClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::SeqCst)
+    }
+
+    // waits for the harness to move time forward
+    fn sleep(&self, d: Duration) -> impl Future<Output = ()> + Send {
+        let target = self.now_ms() + d.as_millis() as u64;
+        let now_ms = self.now_ms.clone();
+        async move {
+            while now_ms.load(Ordering::SeqCst) < target {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+/// Every simulated node's cluster map by address, plus the nodes that are
+/// currently unreachable.
+#[derive(Default)]
+pub struct SimNetwork {
+    nodes: Mutex<HashMap<String, ClusterMap>>,
+    down: Mutex<HashSet<String>>,
+}
+
+impl SimNetwork {
+    pub fn register(&self, addr: &str, cluster: ClusterMap) {
+        self.nodes.lock().unwrap().insert(addr.to_string(), cluster);
+    }
+
+    pub fn set_down(&self, addr: &str, down: bool) {
+        let mut set = self.down.lock().unwrap();
+        if down {
+            set.insert(addr.to_string());
+        } else {
+            set.remove(addr);
+        }
+    }
+
+    pub fn is_down(&self, addr: &str) -> bool {
+        self.down.lock().unwrap().contains(addr)
+    }
+}
+
+pub struct SimTransport {
+    pub net: Arc<SimNetwork>,
+    pub from: String,
+}
+
+impl Transport for SimTransport {
+    fn send_perf(&self, peer: &str, perf: NodePerf) -> impl Future<Output = Result<()>> + Send {
+        let target = if self.net.is_down(&self.from) || self.net.is_down(peer) {
+            None
+        } else {
+            self.net.nodes.lock().unwrap().get(peer).cloned()
+        };
+        let peer = peer.to_string();
+
+        async move {
+            let cluster = target.ok_or_else(|| anyhow!("{peer} unreachable"))?;
+            merge_perf(cluster, perf).await;
+            Ok(())
+        }
+    }
+}
+
+pub struct Sim {
+    pub clock: SimClock,
+    pub net: Arc<SimNetwork>,
+    pub nodes: Vec<GossipNode<SimClock, SimTransport>>,
+}
+
+impl Sim {
+    /// `n` nodes addressed `sim-0..sim-n`, each seeded with `sim-0` only so
+    /// everything else has to be learned through gossip.
+    pub fn new(n: usize, config: GossipConfig) -> Sim {
+        let clock = SimClock::default();
+        let net = Arc::new(SimNetwork::default());
+        // the nodes share the host, one probe does for all of them
+        let system = SystemInfo::detect();
+
+        let nodes = (0..n)
+            .map(|i| {
+                let addr = format!("sim-{i}");
+                let cluster: ClusterMap = Arc::new(RwLock::new(HashMap::new()));
+                net.register(&addr, cluster.clone());
+
+                GossipNode {
+                    cluster,
+                    node_id: format!("node-{i}"),
+                    addr: addr.clone(),
+                    local_pin: None,
+                    seeds: if i == 0 { vec![] } else { vec!["sim-0".into()] },
+                    config: config.clone(),
+                    clock: clock.clone(),
+                    transport: SimTransport {
+                        net: net.clone(),
+                        from: addr,
+                    },
+                    backoff: PeerBackoff::default(),
+                    drain: Arc::default(),
+                    compute_factor: None,
+                    system,
+                    latencies: Arc::default(),
+                    metrics: Arc::default(),
+                    reputation: Arc::default(),
+                    rng: SharedRng::new(Some(i as u64)),
+                    handle: GossipHandle::default(),
+                    events: None,
+                }
+            })
+            .collect();
+
+        Sim { clock, net, nodes }
+    }
+
+    /// One gossip interval: every live node ticks once, then time moves on.
+    pub async fn step(&self) {
+        for node in &self.nodes {
+            if !self.net.is_down(&node.addr) {
+                gossip_tick(node).await;
+            }
+        }
+        if let Some(node) = self.nodes.first() {
+            self.clock.advance(node.config.interval);
+        }
+    }
+
+    pub async fn run_for(&self, d: Duration) {
+        let start = self.clock.now_ms();
+        let end = start + d.as_millis() as u64;
+        while self.clock.now_ms() < end {
+            self.step().await;
+        }
+    }
+
+    /// Node ids each live node currently knows about.
+    pub async fn views(&self) -> Vec<HashSet<String>> {
+        let mut views = vec![];
+        for node in &self.nodes {
+            if self.net.is_down(&node.addr) {
+                continue;
+            }
+            let map = node.cluster.read().await;
+            views.push(map.keys().cloned().collect());
+        }
+        views
+    }
+
+    /// True when every live node knows exactly the set of live nodes.
+    pub async fn converged(&self) -> bool {
+        let live: HashSet<String> = self
+            .nodes
+            .iter()
+            .filter(|n| !self.net.is_down(&n.addr))
+            .map(|n| n.node_id.clone())
+            .collect();
+
+        self.views().await.iter().all(|v| *v == live)
+    }
+}
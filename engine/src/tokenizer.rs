@@ -0,0 +1,390 @@
+//! Turns prompts into the token ids the entry stage takes, and the ids a
+//! pipeline produces back into text.
+//!
+//! The vocabulary comes from the GGUF metadata (`tokenizer.ggml.*`). Every
+//! token is mapped to the bytes it stands for once, at load. A character
+//! can be split over several tokens (byte fallback tokens, byte level BPE),
+//! so text streamed to a client is only ever cut at a character boundary:
+//! `decode_incremental` holds back the bytes of an unfinished character
+//! until the tokens completing it arrive.
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::gguf::{GgufFile, GgufValue};
+
+// `tokenizer.ggml.token_type` of tokens that never show up in text, like
+// <s> and </s>
+const TOKEN_TYPE_CONTROL: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizerModel {
+    // sentencepiece BPE, `tokenizer.ggml.model = "llama"`
+    Llama,
+    // byte level BPE, `tokenizer.ggml.model = "gpt2"`
+    Gpt2,
+}
+
+/// Everything a tokenizer is built from, as found under `tokenizer.ggml.*`.
+#[derive(Debug, Clone)]
+pub struct Vocab {
+    pub model: TokenizerModel,
+    pub tokens: Vec<String>,
+    // `token_type` of each token, missing entries are normal tokens
+    pub token_types: Vec<u64>,
+    // merge priority of each token, llama only
+    pub scores: Vec<f32>,
+    // "a b" pairs in merge order, gpt2 only
+    pub merges: Vec<String>,
+    pub bos: Option<u32>,
+    pub eos: Option<u32>,
+    pub add_bos: bool,
+    pub add_eos: bool,
+}
+
+pub struct Tokenizer {
+    model: TokenizerModel,
+    // bytes of each token, by id
+    pieces: Vec<Vec<u8>>,
+    ids: HashMap<String, u32>,
+    scores: Vec<f32>,
+    merge_rank: HashMap<(String, String), usize>,
+    bos: Option<u32>,
+    eos: Option<u32>,
+    add_bos: bool,
+    add_eos: bool,
+}
+
+/// Bytes `decode_incremental` received but couldn't emit yet, one per
+/// stream being decoded.
+#[derive(Debug, Default)]
+pub struct DecodeState {
+    pending: Vec<u8>,
+}
+
+impl DecodeState {
+    /// The bytes still held back once the stream has ended, a character
+    /// cut short comes out as U+FFFD.
+    pub fn finish(self) -> String {
+        String::from_utf8_lossy(&self.pending).into_owned()
+    }
+}
+
+impl Tokenizer {
+    pub fn from_gguf(gguf: &GgufFile) -> Result<Tokenizer> {
+        let key = |k: &str| gguf.get(&format!("tokenizer.ggml.{k}"));
+        let array = |k: &str| key(k).and_then(|v| v.as_array()).unwrap_or_default();
+        let strings = |k: &str| {
+            array(k)
+                .iter()
+                .map(|t| {
+                    t.as_str()
+                        .map(str::to_string)
+                        .ok_or_else(|| anyhow!("tokenizer.ggml.{k} holds a non string"))
+                })
+                .collect::<Result<Vec<String>>>()
+        };
+        let id = |k: &str| key(k).and_then(|v| v.as_u64()).map(|v| v as u32);
+        let flag = |k: &str| match key(k) {
+            Some(GgufValue::Bool(b)) => Some(*b),
+            _ => None,
+        };
+
+        let model = match key("model").and_then(|v| v.as_str()) {
+            Some("llama") => TokenizerModel::Llama,
+            Some("gpt2") => TokenizerModel::Gpt2,
+            Some(other) => bail!("unsupported tokenizer model {other}"),
+            None => bail!("tokenizer.ggml.model missing"),
+        };
+        let tokens = strings("tokens")?;
+        if tokens.is_empty() {
+            bail!("tokenizer.ggml.tokens missing");
+        }
+
+        Self::new(Vocab {
+            model,
+            tokens,
+            token_types: array("token_type")
+                .iter()
+                .filter_map(|t| t.as_u64())
+                .collect(),
+            scores: array("scores")
+                .iter()
+                .map(|s| match s {
+                    GgufValue::Float(f) => *f as f32,
+                    _ => 0.0,
+                })
+                .collect(),
+            merges: strings("merges")?,
+            bos: id("bos_token_id"),
+            eos: id("eos_token_id"),
+            // llama.cpp's defaults when the file doesn't say
+            add_bos: flag("add_bos_token").unwrap_or(model == TokenizerModel::Llama),
+            add_eos: flag("add_eos_token").unwrap_or(false),
+        })
+    }
+
+    /// Builds the byte table and the merge tables of `vocab`. Control
+    /// tokens decode to nothing.
+    pub fn new(vocab: Vocab) -> Result<Tokenizer> {
+        let piece: fn(&str) -> Vec<u8> = match vocab.model {
+            TokenizerModel::Llama => sentencepiece_bytes,
+            TokenizerModel::Gpt2 => byte_level_bytes,
+        };
+        let pieces = vocab
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                if vocab.token_types.get(i) == Some(&TOKEN_TYPE_CONTROL) {
+                    vec![]
+                } else {
+                    piece(t)
+                }
+            })
+            .collect();
+
+        let ids = vocab
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.clone(), i as u32))
+            .collect();
+        let merge_rank = vocab
+            .merges
+            .iter()
+            .enumerate()
+            .filter_map(|(rank, m)| {
+                let (a, b) = m.split_once(' ')?;
+                Some(((a.to_string(), b.to_string()), rank))
+            })
+            .collect();
+
+        for id in [vocab.bos, vocab.eos].into_iter().flatten() {
+            if id as usize >= vocab.tokens.len() {
+                bail!("special token {id} is outside the vocabulary");
+            }
+        }
+
+        Ok(Tokenizer {
+            model: vocab.model,
+            pieces,
+            ids,
+            scores: vocab.scores,
+            merge_rank,
+            bos: vocab.bos,
+            eos: vocab.eos,
+            add_bos: vocab.add_bos,
+            add_eos: vocab.add_eos,
+        })
+    }
+
+    pub fn bos(&self) -> Option<u32> {
+        self.bos
+    }
+
+    pub fn eos(&self) -> Option<u32> {
+        self.eos
+    }
+
+    /// Token ids of a prompt, with BOS in front and EOS behind when the
+    /// model asks for them.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut ids = vec![];
+        if self.add_bos
+            && let Some(bos) = self.bos
+        {
+            ids.push(bos);
+        }
+
+        match self.model {
+            TokenizerModel::Llama => {
+                // sentencepiece marks the start of the text as a word start
+                let text = format!("\u{2581}{}", text.replace(' ', "\u{2581}"));
+                let symbols = text.chars().map(String::from).collect();
+                for symbol in self.bpe(symbols) {
+                    match self.ids.get(&symbol) {
+                        Some(&id) => ids.push(id),
+                        // no token for it, spelled out with byte tokens
+                        None => ids.extend(
+                            symbol
+                                .bytes()
+                                .filter_map(|b| self.ids.get(&format!("<0x{b:02X}>")).copied()),
+                        ),
+                    }
+                }
+            }
+            TokenizerModel::Gpt2 => {
+                for word in split_words(text) {
+                    let symbols = word.bytes().map(|b| byte_level_char(b).into()).collect();
+                    ids.extend(
+                        self.bpe(symbols)
+                            .iter()
+                            .filter_map(|s| self.ids.get(s).copied()),
+                    );
+                }
+            }
+        }
+
+        if self.add_eos
+            && let Some(eos) = self.eos
+        {
+            ids.push(eos);
+        }
+        ids
+    }
+
+    // merges the best adjacent pair until none is left: the one whose merged
+    // token scores highest for llama, the earliest listed merge for gpt2
+    fn bpe(&self, mut symbols: Vec<String>) -> Vec<String> {
+        let priority = |a: &str, b: &str| -> Option<f64> {
+            match self.model {
+                TokenizerModel::Llama => {
+                    let id = *self.ids.get(&format!("{a}{b}"))?;
+                    Some(self.scores.get(id as usize).copied().unwrap_or(0.0) as f64)
+                }
+                TokenizerModel::Gpt2 => {
+                    let rank = self.merge_rank.get(&(a.to_string(), b.to_string()))?;
+                    Some(-(*rank as f64))
+                }
+            }
+        };
+
+        loop {
+            let mut best: Option<(usize, f64)> = None;
+            for i in 0..symbols.len().saturating_sub(1) {
+                if let Some(p) = priority(&symbols[i], &symbols[i + 1])
+                    && best.is_none_or(|(_, b)| p > b)
+                {
+                    best = Some((i, p));
+                }
+            }
+            let Some((i, _)) = best else {
+                return symbols;
+            };
+            let right = symbols.remove(i + 1);
+            symbols[i].push_str(&right);
+        }
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Text of a whole sequence. Ids outside the vocabulary are skipped and
+    /// bytes that aren't valid UTF-8 come out as U+FFFD.
+    pub fn decode(&self, ids: &[u32]) -> String {
+        String::from_utf8_lossy(&self.bytes(ids)).into_owned()
+    }
+
+    /// Text for the next `ids` of a stream, as much of it as ends on a
+    /// character boundary. The bytes of a character still missing tokens
+    /// stay in `state` and come out with a later call.
+    pub fn decode_incremental(&self, state: &mut DecodeState, ids: &[u32]) -> String {
+        state.pending.extend(self.bytes(ids));
+
+        let mut text = String::new();
+        let mut rest = state.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(s) => {
+                    text.push_str(s);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    // checked by from_utf8 just above
+                    text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        // can't ever become valid, replace and carry on
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        }
+                        // a character cut short, wait for the rest
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        state.pending = rest.to_vec();
+        text
+    }
+
+    fn bytes(&self, ids: &[u32]) -> Vec<u8> {
+        ids.iter()
+            .filter_map(|&id| self.pieces.get(id as usize))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+// sentencepiece writes spaces as U+2581 and bytes without a token of their
+// own as <0xAB>
+fn sentencepiece_bytes(token: &str) -> Vec<u8> {
+    if let Some(hex) = token.strip_prefix("<0x").and_then(|t| t.strip_suffix('>'))
+        && let Ok(byte) = u8::from_str_radix(hex, 16)
+    {
+        return vec![byte];
+    }
+    token.replace('\u{2581}', " ").into_bytes()
+}
+
+// byte level BPE spells every byte as a printable character, the table is
+// GPT-2's `bytes_to_unicode`: printable latin-1 bytes stand for themselves,
+// the others are moved to U+0100 onwards in byte order
+fn printable(b: u32) -> bool {
+    (0x21..=0x7e).contains(&b) || ((0xa1..=0xff).contains(&b) && b != 0xad)
+}
+
+fn byte_level_char(byte: u8) -> char {
+    let b = byte as u32;
+    if printable(b) {
+        return byte as char;
+    }
+    let n = (0..b).filter(|&x| !printable(x)).count() as u32;
+    char::from_u32(0x100 + n).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+fn byte_level_bytes(token: &str) -> Vec<u8> {
+    token
+        .chars()
+        .filter_map(|c| {
+            let c = c as u32;
+            if c < 0x100 && printable(c) {
+                return Some(c as u8);
+            }
+            let n = c.checked_sub(0x100)?;
+            (0u32..0x100)
+                .filter(|&b| !printable(b))
+                .nth(n as usize)
+                .map(|b| b as u8)
+        })
+        .collect()
+}
+
+// gpt2 merges within words only, a word taking the whitespace before it.
+// A simplification of its pre-tokenizer regex, which also splits off
+// punctuation and contractions
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = 0;
+    let mut prev_space = true;
+    for (i, c) in text.char_indices() {
+        let space = c.is_whitespace();
+        if space && !prev_space && i > start {
+            words.push(&text[start..i]);
+            start = i;
+        }
+        prev_space = space;
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    words
+}
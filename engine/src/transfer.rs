@@ -0,0 +1,444 @@
+//! Fetches layer files from the nodes holding them.
+//!
+//! A holder can go away halfway through a multi-gigabyte file. The transfer
+//! then looks the holders up in the DHT again and resumes on another one,
+//! through `GET /range`, and only gives up once every holder has failed.
+//!
+//! Whatever the holders send, only bytes that hash to what the model's
+//! signed `LayerManifest` says reach the output. They're checked a chunk at
+//! a time, a holder failing or sending a bad chunk costs the chunk, and the
+//! next one resumes after the last good chunk.
+//!
+//! Layer downloads report their progress to `Transfers`, which `GET
+//! /transfers` lists and UIs can subscribe to.
+//!
+//! A standby preloads the layers of the stage it stands in for through
+//! `Prefetcher`, so it can take the stage over without a download.
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use anyhow::{Result, anyhow, bail};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, mpsc, oneshot},
+};
+use tracing::{info, warn};
+
+use crate::{
+    client::{PinSet, fetch_range_with},
+    dht::{FILE_CHUNK_BYTES, FileDigest, HolderQuery, LayerId, LayerManifest},
+    model::ModelId,
+    scheduling::LayerRange,
+    server::ServerState,
+};
+
+/// Addresses of the nodes that can serve a file, asked again after each
+/// failure so holders that showed up meanwhile are tried too.
+pub trait HolderSource: Send + Sync {
+    fn holders(&self) -> impl Future<Output = Vec<String>> + Send;
+}
+
+/// Nodes providing `layer` under `dht::holder_key`, looked up anew through
+/// the swarm loop on every call, by the address they gossip. Kademlia knows
+/// them by peer id, which nodes on the DHT use as their node id.
+pub struct DhtHolders {
+    pub state: ServerState,
+    // where the swarm loop takes lookups, see `dht::fetch_holders`
+    pub queries: mpsc::Sender<HolderQuery>,
+    pub model_id: ModelId,
+    pub layer: LayerId,
+}
+
+impl HolderSource for DhtHolders {
+    fn holders(&self) -> impl Future<Output = Vec<String>> + Send {
+        async move {
+            let (reply, found) = oneshot::channel();
+            let query = HolderQuery {
+                model_id: self.model_id.clone(),
+                layer: self.layer,
+                reply,
+            };
+            // no swarm loop, no holders
+            if self.queries.send(query).await.is_err() {
+                return vec![];
+            }
+            let Ok(providers) = found.await else {
+                return vec![];
+            };
+
+            let map = self.state.cluster.read().await;
+            providers
+                .iter()
+                .filter_map(|peer| map.get(&peer.to_string()))
+                .filter(|p| !p.draining && !p.addr.is_empty())
+                .map(|p| p.addr.clone())
+                .collect()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TransferProgress {
+    pub model_id: ModelId,
+    pub layer_id: LayerId,
+    pub bytes_done: u64,
+    // 0 until a holder said how big the file is
+    pub bytes_total: u64,
+}
+
+impl TransferProgress {
+    pub fn percent(&self) -> f64 {
+        if self.bytes_total == 0 {
+            return 0.0;
+        }
+        self.bytes_done as f64 * 100.0 / self.bytes_total as f64
+    }
+}
+
+// progress events kept for subscribers lagging behind, older ones are
+// dropped
+const PROGRESS_BUFFER: usize = 1024;
+
+/// Layer downloads in flight on this node.
+#[derive(Debug)]
+pub struct Transfers {
+    active: Mutex<HashMap<(ModelId, LayerId), TransferProgress>>,
+    events: broadcast::Sender<TransferProgress>,
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Self {
+            active: Mutex::default(),
+            events: broadcast::channel(PROGRESS_BUFFER).0,
+        }
+    }
+}
+
+impl Transfers {
+    /// Every progress update from now on, a transfer's last one has
+    /// `bytes_done == bytes_total`.
+    pub fn subscribe(&self) -> broadcast::Receiver<TransferProgress> {
+        self.events.subscribe()
+    }
+
+    /// The transfers in flight, by model then layer.
+    pub fn active(&self) -> Vec<TransferProgress> {
+        let mut active: Vec<_> = self.active.lock().unwrap().values().cloned().collect();
+        active.sort_by(|a, b| (&a.model_id, a.layer_id).cmp(&(&b.model_id, b.layer_id)));
+        active
+    }
+
+    fn update(&self, progress: TransferProgress) {
+        let key = (progress.model_id.clone(), progress.layer_id);
+        self.active.lock().unwrap().insert(key, progress.clone());
+        // nobody listening is fine
+        let _ = self.events.send(progress);
+    }
+
+    fn finish(&self, model_id: &str, layer_id: LayerId) {
+        self.active
+            .lock()
+            .unwrap()
+            .remove(&(model_id.to_string(), layer_id));
+    }
+}
+
+/// Digest of a file, what the authority puts in `LayerManifest::files`.
+pub async fn file_digest(path: &Path) -> Result<FileDigest> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut digest = FileDigest {
+        len: 0,
+        chunks: vec![],
+    };
+    let mut chunk = Vec::with_capacity(FILE_CHUNK_BYTES as usize);
+    loop {
+        let n = (&mut file)
+            .take(FILE_CHUNK_BYTES)
+            .read_to_end(&mut chunk)
+            .await?;
+        if n == 0 {
+            return Ok(digest);
+        }
+        digest.len += n as u64;
+        digest.chunks.push(Sha256::digest(&chunk).into());
+        chunk.clear();
+    }
+}
+
+// passes on to `inner` only the chunks that hash to what `digest` says. The
+// bytes of the chunk coming in are held back until it is complete
+struct VerifyingWriter<'a, W> {
+    inner: &'a mut W,
+    digest: &'a FileDigest,
+    // the chunk being checked
+    chunk: usize,
+    // the first `ready` bytes are checked and wait for `inner`, the rest
+    // belong to `chunk`
+    buf: Vec<u8>,
+    ready: usize,
+}
+
+impl<W: AsyncWrite + Unpin> VerifyingWriter<'_, W> {
+    // bytes of the file checked so far, where the next holder resumes
+    fn verified(&self) -> u64 {
+        (self.chunk as u64 * FILE_CHUNK_BYTES).min(self.digest.len)
+    }
+
+    // drops what came in of the current chunk
+    fn discard_partial(&mut self) {
+        self.buf.truncate(self.ready);
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.ready > 0 {
+            let n = std::task::ready!(
+                Pin::new(&mut *self.inner).poll_write(cx, &self.buf[..self.ready])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.buf.drain(..n);
+            self.ready -= n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for VerifyingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        std::task::ready!(this.poll_drain(cx))?;
+
+        let Some(chunk_len) = this.digest.chunk_len(this.chunk) else {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("more than the {} bytes in the manifest", this.digest.len),
+            )));
+        };
+        let chunk_len = chunk_len as usize;
+        let n = (chunk_len - this.buf.len()).min(buf.len());
+        this.buf.extend_from_slice(&buf[..n]);
+        if this.buf.len() == chunk_len {
+            let digest: [u8; 32] = Sha256::digest(&this.buf).into();
+            if digest != this.digest.chunks[this.chunk] {
+                this.buf.clear();
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk {} fails its checksum", this.chunk),
+                )));
+            }
+            this.ready = chunk_len;
+            this.chunk += 1;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        std::task::ready!(self.poll_drain(cx))?;
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        std::task::ready!(self.poll_drain(cx))?;
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// `fetch_from_holders` for a layer, checked against the layer's digest in
+/// `manifest`, which the caller got through `dht::manifest_from_record`.
+/// Its progress is published in `holders.state.transfers`.
+pub async fn fetch_layer<W: AsyncWrite + Unpin>(
+    holders: &DhtHolders,
+    pins: &PinSet,
+    manifest: &LayerManifest,
+    path: &str,
+    out: &mut W,
+) -> Result<u64> {
+    let transfers = &holders.state.transfers;
+    let model_id = &holders.model_id;
+    let layer_id = holders.layer;
+    if manifest.model_id != *model_id {
+        bail!("manifest is for {}, not {model_id}", manifest.model_id);
+    }
+    let Some(expected) = manifest.files.get(&layer_id) else {
+        bail!("manifest of {model_id} has no layer {layer_id}");
+    };
+
+    let mut progress = |bytes_done, bytes_total| {
+        transfers.update(TransferProgress {
+            model_id: model_id.clone(),
+            layer_id,
+            bytes_done,
+            bytes_total,
+        })
+    };
+    let result = fetch_from_holders_with(holders, pins, path, expected, out, &mut progress).await;
+    transfers.finish(model_id, layer_id);
+    result
+}
+
+/// Downloads `path` into `out` from whichever holders answer, resuming
+/// after the last chunk that matched `expected`. Each holder is tried at
+/// most once. Returns the bytes written, all of them checked.
+pub async fn fetch_from_holders<H: HolderSource, W: AsyncWrite + Unpin>(
+    holders: &H,
+    pins: &PinSet,
+    path: &str,
+    expected: &FileDigest,
+    out: &mut W,
+) -> Result<u64> {
+    fetch_from_holders_with(holders, pins, path, expected, out, &mut |_, _| {}).await
+}
+
+/// Like `fetch_from_holders`, see `fetch_range_with` for `progress`.
+pub async fn fetch_from_holders_with<H: HolderSource, W: AsyncWrite + Unpin>(
+    holders: &H,
+    pins: &PinSet,
+    path: &str,
+    expected: &FileDigest,
+    out: &mut W,
+    progress: &mut (dyn FnMut(u64, u64) + Send),
+) -> Result<u64> {
+    let mut tried = HashSet::new();
+    let mut last_err = None;
+    let mut out = VerifyingWriter {
+        inner: out,
+        digest: expected,
+        chunk: 0,
+        buf: vec![],
+        ready: 0,
+    };
+
+    loop {
+        let next = holders
+            .holders()
+            .await
+            .into_iter()
+            .find(|addr| !tried.contains(addr));
+        let Some(addr) = next else {
+            return Err(match last_err {
+                Some(e) => anyhow!("every holder of {path} failed, last error: {e}"),
+                None => anyhow!("no holder for {path}"),
+            });
+        };
+        tried.insert(addr.clone());
+
+        let offset = out.verified();
+        let mut written = offset;
+        let result = fetch_range_with(&addr, pins, path, offset, &mut out, &mut written, progress);
+        match result.await {
+            Ok(()) if out.verified() == expected.len => {
+                out.flush().await?;
+                return Ok(expected.len);
+            }
+            Ok(()) => {
+                last_err = Some(anyhow!(
+                    "{addr} ended {path} at byte {written}, short of {}",
+                    expected.len
+                ));
+            }
+            Err(e) => {
+                info!(
+                    "fetching {path} from {addr} failed, {} bytes checked: {e}",
+                    out.verified()
+                );
+                last_err = Some(e);
+            }
+        }
+        out.discard_partial();
+    }
+}
+
+/// Where holders keep and serve the file of `layer`, relative to their
+/// files root.
+pub fn layer_path(model_id: &str, layer: LayerId) -> String {
+    format!("{model_id}/layer-{layer}")
+}
+
+/// Layers of a model this node should hold ahead of time, sent by
+/// `ServerState::adopt_plan` for each stage the node stands by for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prefetch {
+    pub model_id: ModelId,
+    pub range: LayerRange,
+}
+
+/// Downloads the layers a `Prefetch` asks for into `root`, the directory
+/// the node serves under `/files`, so a promoted standby has them at hand
+/// and serves them to others.
+pub struct Prefetcher {
+    pub state: ServerState,
+    // where the swarm loop takes holder lookups, see `dht::fetch_holders`
+    pub queries: mpsc::Sender<HolderQuery>,
+    pub pins: PinSet,
+    // the verified manifest of each model, see `dht::manifest_from_record`
+    pub manifests: HashMap<ModelId, LayerManifest>,
+    pub root: PathBuf,
+}
+
+impl Prefetcher {
+    /// Handles requests until every sender is gone. A layer that can't be
+    /// fetched skips the rest of its range, the standby is then promoted
+    /// with a download still to do.
+    pub async fn run(&self, mut requests: mpsc::UnboundedReceiver<Prefetch>) {
+        while let Some(Prefetch { model_id, range }) = requests.recv().await {
+            for layer in range.start..range.end {
+                if let Err(e) = self.fetch(&model_id, layer as LayerId).await {
+                    warn!("prefetching layer {layer} of {model_id} failed: {e}");
+                    break;
+                }
+            }
+        }
+    }
+
+    // downloads next to the final file and renames it in place, so holders
+    // never serve a partial layer. A file already matching the manifest is
+    // kept
+    async fn fetch(&self, model_id: &str, layer: LayerId) -> Result<()> {
+        let Some(manifest) = self.manifests.get(model_id) else {
+            bail!("no manifest of {model_id}");
+        };
+        let Some(expected) = manifest.files.get(&layer) else {
+            bail!("manifest of {model_id} has no layer {layer}");
+        };
+        let path = layer_path(model_id, layer);
+        let dest = self.root.join(&path);
+        if tokio::fs::try_exists(&dest).await? && file_digest(&dest).await? == *expected {
+            return Ok(());
+        }
+
+        if let Some(dir) = dest.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let part = dest.with_extension("part");
+        let mut out = tokio::fs::File::create(&part).await?;
+        let holders = DhtHolders {
+            state: self.state.clone(),
+            queries: self.queries.clone(),
+            model_id: model_id.to_string(),
+            layer,
+        };
+        if let Err(e) = fetch_layer(&holders, &self.pins, manifest, &path, &mut out).await {
+            let _ = tokio::fs::remove_file(&part).await;
+            return Err(e);
+        }
+        out.sync_all().await?;
+        tokio::fs::rename(&part, &dest).await?;
+        info!("prefetched layer {layer} of {model_id}");
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    cmp::Ordering,
+    sync::{Arc, Mutex},
+};
+
+use rand::{SeedableRng, rngs::StdRng};
+
+/// Total order on `f64` for sorting and min/max, NaN sorts after every
+/// number (of either sign) and equal to other NaNs. Unlike
+/// `f64::total_cmp` a negative NaN doesn't end up first, so a NaN that
+/// sneaks into latencies or scores never wins an ascending pick.
+pub fn total_cmp_f64(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.total_cmp(&b),
+    }
+}
+
+/// The one RNG behind everything random on a node (gossip timing, replica
+/// tie-breaks), so that a run given a seed can be reproduced. Clones share
+/// the same generator.
+#[derive(Debug, Clone)]
+pub struct SharedRng(Arc<Mutex<StdRng>>);
+
+impl SharedRng {
+    /// Seeded from `seed`, or from the OS when None.
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        SharedRng(Arc::new(Mutex::new(rng)))
+    }
+
+    pub fn with<T>(&self, f: impl FnOnce(&mut StdRng) -> T) -> T {
+        f(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Default for SharedRng {
+    fn default() -> Self {
+        SharedRng::new(None)
+    }
+}
@@ -0,0 +1,141 @@
+//! Three nodes find each other over the simulated gossip transport, then each
+//! schedules the model on what it learned. They have to end up with the same
+//! plan.
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use engine::{
+    RamConfig,
+    controller::{SchedulerController, cluster_gpus},
+    gossip::GossipConfig,
+    model::{Dtype, ModelMetadata},
+    reputation::Reputation,
+    scheduling::{CapacityLedger, PipelinePlan, SchedulingParams},
+    server::ServerState,
+    sim::Sim,
+};
+
+const NODES: usize = 3;
+
+fn meta() -> ModelMetadata {
+    ModelMetadata {
+        name: "swarm".into(),
+        model_layers: 12,
+        hidden_size: 64,
+        n_kv_heads: 2,
+        head_dim: 16,
+        ffn_size: 256,
+        dtype: Dtype::F16,
+        act_dtype: Dtype::F16,
+    }
+}
+
+fn params(meta: &ModelMetadata) -> SchedulingParams {
+    SchedulingParams {
+        model_id: meta.name.clone(),
+        model_layer: meta.model_layers,
+        alpha: 1.0,
+        r_rtt: 1.0,
+        t_comp: 10.0,
+        max_stages_per_replica: None,
+        affinities: vec![],
+        layer_compute_weights: vec![],
+        standby_count: 0,
+        explain: false,
+        min_compute_cap: 0,
+        hop_rtt: Default::default(),
+        client_regions: vec![],
+        load_budget: None,
+        one_replica_per_region: false,
+        max_pipeline_rtt: None,
+        max_dp_states: None,
+        layer_overlap: 0,
+        required_capabilities: Default::default(),
+    }
+}
+
+#[tokio::test]
+async fn swarm_agrees_on_a_plan() {
+    // every node advertises whatever memory the host has, a token per byte
+    // is enough for the whole model
+    let config = GossipConfig {
+        ram: RamConfig {
+            reserved_ram_bytes: 0,
+            bytes_per_token: 1,
+        },
+        jitter: 0.0,
+        ..Default::default()
+    };
+    let sim = Sim::new(NODES, config);
+    for _ in 0..20 {
+        if sim.converged().await {
+            break;
+        }
+        sim.step().await;
+    }
+    assert!(sim.converged().await, "gossip didn't converge");
+
+    let meta = meta();
+    let reputation = Reputation::default();
+    let mut plans: Vec<PipelinePlan> = vec![];
+    for node in &sim.nodes {
+        // records carry the sim clock, nothing is too old for this test
+        let gpus = cluster_gpus(
+            &node.cluster,
+            Duration::from_millis(u64::MAX),
+            &meta,
+            &reputation,
+        )
+        .await;
+        assert_eq!(gpus.len(), NODES, "{} sees {gpus:?}", node.node_id);
+
+        let mut controller = SchedulerController {
+            params: params(&meta),
+            budget: None,
+            state: ServerState {
+                cluster: node.cluster.clone(),
+                ..Default::default()
+            },
+            tuner: None,
+            ledger: Arc::new(Mutex::new(CapacityLedger::default())),
+        };
+        assert_eq!(controller.reschedule(&gpus).await, Ok(true));
+
+        let adopted = controller.state.plans.read().await;
+        plans.push(adopted.get(&meta.name).cloned().expect("no plan adopted"));
+    }
+
+    let first = serde_json::to_string(&plans[0]).unwrap();
+    for (node, plan) in sim.nodes.iter().zip(&plans) {
+        assert_eq!(
+            serde_json::to_string(plan).unwrap(),
+            first,
+            "{} disagrees",
+            node.node_id
+        );
+    }
+
+    let plan = &plans[0];
+    assert!(!plan.pipelines.is_empty());
+    assert_eq!(plan.pipelines.len(), plan.k);
+    let known: HashSet<&str> = sim.nodes.iter().map(|n| n.node_id.as_str()).collect();
+    let mut used = HashSet::new();
+    for pipeline in &plan.pipelines {
+        let mut next = 0;
+        for stage in &pipeline.stages {
+            assert_eq!(stage.range.start, next, "stages leave a gap");
+            assert!(stage.range.end > stage.range.start);
+            assert!(known.contains(stage.gpu.node_id.as_str()));
+            assert!(
+                used.insert(stage.gpu_idx),
+                "gpu {} used twice",
+                stage.gpu_idx
+            );
+            next = stage.range.end;
+        }
+        assert_eq!(next, meta.model_layers);
+    }
+}